@@ -0,0 +1,149 @@
+//! Reads and writes the `bookmarks` table of a buku SQLite database.
+//!
+//! Buku keeps the title in `metadata`, the description in `desc` and the
+//! tags as one string with leading and trailing commas, like `,rust,cli,`.
+//! It has no folders or creation dates, so bookmarks are dated when they're
+//! first imported and exported ones lose their folder. Bookmarks already in the
+//! database keep their id and `flags` when they're written back.
+
+use crate::structures::Bookmark;
+use chrono::Utc;
+use rusqlite::{params, Connection, OpenFlags};
+use std::collections::HashMap;
+use std::path::Path;
+use url::Url;
+
+/// The table buku creates, used when exporting to a new file
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS bookmarks (
+    id integer PRIMARY KEY,
+    URL text NOT NULL UNIQUE,
+    metadata text default '',
+    tags text default ',',
+    desc text default '',
+    flags integer default 0
+)";
+
+/// Split buku's `,a,b,` tags
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    let mut parsed: Vec<String> = Vec::new();
+    for tag in tags.split(',').map(str::trim) {
+        if !tag.is_empty() && !parsed.iter().any(|t| t == tag) {
+            parsed.push(tag.to_string());
+        }
+    }
+    parsed
+}
+
+/// Join tags the way buku stores them, which can't contain commas
+pub fn format_tags(tags: &[String]) -> String {
+    let mut formatted = String::from(",");
+    for tag in tags {
+        let tag = tag.replace(',', " ");
+        let tag = tag.trim();
+        if !tag.is_empty() {
+            formatted.push_str(tag);
+            formatted.push(',');
+        }
+    }
+    formatted
+}
+
+/// Buku accepts anything as a URL, like bare domains, which are read as http
+fn parse_link(link: &str) -> Option<Url> {
+    Url::parse(link)
+        .or_else(|_| Url::parse(&format!("http://{}", link)))
+        .ok()
+}
+
+/// Every bookmark in the database at `path`, skipping ones whose URL isn't valid
+pub fn read(path: &Path) -> Result<Vec<Bookmark>, String> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| e.to_string())?;
+    let mut statement = connection
+        .prepare("SELECT URL, metadata, tags, desc FROM bookmarks ORDER BY id")
+        .map_err(|e| e.to_string())?;
+    let rows = statement
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            ))
+        })
+        .map_err(|e| e.to_string())?;
+
+    let mut bookmarks = Vec::new();
+    for row in rows {
+        let (link, title, tags, description) = row.map_err(|e| e.to_string())?;
+        let link = match parse_link(&link) {
+            Some(link) => link,
+            None => continue,
+        };
+        bookmarks.push(Bookmark {
+            container: None,
+            created_at: Utc::now(),
+            description: (!description.trim().is_empty()).then_some(description),
+            label: if title.trim().is_empty() {
+                link.to_string()
+            } else {
+                title
+            },
+            link,
+            tags: parse_tags(&tags),
+        });
+    }
+    Ok(bookmarks)
+}
+
+/// Insert or update `bookmarks` in the database at `path`, creating it if needed.
+/// Stored URLs are matched after parsing them, so rows aren't duplicated when
+/// tinymark normalized a URL, e.g. by adding a trailing slash.
+pub fn write(path: &Path, bookmarks: &[Bookmark]) -> Result<(), String> {
+    let mut connection = Connection::open(path).map_err(|e| e.to_string())?;
+    let transaction = connection.transaction().map_err(|e| e.to_string())?;
+    transaction.execute(SCHEMA, []).map_err(|e| e.to_string())?;
+    {
+        let mut stored: HashMap<Url, String> = HashMap::new();
+        let mut statement = transaction
+            .prepare("SELECT URL FROM bookmarks")
+            .map_err(|e| e.to_string())?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            let link = row.map_err(|e| e.to_string())?;
+            if let Some(parsed) = parse_link(&link) {
+                stored.insert(parsed, link);
+            }
+        }
+
+        let mut statement = transaction
+            .prepare(
+                "INSERT INTO bookmarks (URL, metadata, tags, desc) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(URL) DO UPDATE SET
+                 metadata = excluded.metadata, tags = excluded.tags, desc = excluded.desc",
+            )
+            .map_err(|e| e.to_string())?;
+        for bookmark in bookmarks {
+            let link = stored
+                .get(&bookmark.link)
+                .map_or(bookmark.link.as_str(), String::as_str);
+            // untitled bookmarks are labelled with their link when read
+            let title = if bookmark.label == bookmark.link.as_str() {
+                ""
+            } else {
+                bookmark.label.as_str()
+            };
+            statement
+                .execute(params![
+                    link,
+                    title,
+                    format_tags(&bookmark.tags),
+                    bookmark.description.as_deref().unwrap_or(""),
+                ])
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    transaction.commit().map_err(|e| e.to_string())
+}
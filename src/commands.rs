@@ -1,253 +1,2205 @@
-use crate::database;
-use crate::structures::{Bookmark, Container, ContainerTypes, Keyspace};
-use chrono::Utc;
+use crate::errors::{fail, report, ErrorCode, Failure};
+use crate::output;
+use crate::replica::{self, Delta, Replica, DELTA_VERSION};
+use crate::structures::{
+    Backend, Bookmark, CompletionKind, CompletionShell, Config, ConflictStrategy, Container,
+    ContainerTypes, ExportDocument, FeedFormat, FileFormat, ImportFile, Keyspace, Normalize,
+    Operation, Profile, SortOrder, SyncState, TagInfo, TrashEntry, EXPORT_VERSION,
+};
+use crate::{
+    buku, completions, config, database, feed, manual, native, outline, server, services, site,
+    sync,
+};
+use chrono::{DateTime, Duration, Local, Utc};
 use paris::*;
 use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::VarError;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use url::Url;
 use uuid::Uuid;
-use uuid_simd::UuidExt;
 
-use dialoguer::{console::Term, theme::ColorfulTheme, Select};
+use dialoguer::{console::Term, theme::ColorfulTheme, Confirm, Select};
 
 pub fn edit_bookmark(json: bool, url: &Option<Url>, path: Option<PathBuf>) {
     if json {
-        println!(
-            "{}",
-            json!({
-                "status": "fail",
-                "reason": "unsupported command",
-            })
-        );
-        std::process::exit(exitcode::DATAERR);
-    } else {
-        match url {
-            Some(link) => {
-                println!("User selected item :\n{}", link);
+        fail(json, ErrorCode::Usage, "edit can't be used with --json");
+    }
+    match url {
+        Some(link) => {
+            println!("User selected item :\n{}", link);
+        }
+        None => {
+            let bookmarks = database::get_all(json, path, Keyspace::Bookmarks);
+            if bookmarks.is_empty() {
+                return info!("there are no bookmarks to edit");
             }
-            None => match database::get_all(json, path, Keyspace::Bookmarks) {
-                Some(bookmarks) => {
-                    let mut items: Vec<&Url> = Vec::new();
-                    for i in &bookmarks {
-                        items.push(&i.link);
-                    }
-                    let selection = Select::with_theme(&ColorfulTheme::default())
-                        .items(&items)
-                        .default(0)
-                        .interact_on_opt(&Term::stderr())
-                        .unwrap();
-
-                    match selection {
-                        Some(index) => {
-                            println!(
-                                "User selected item :\n{}",
-                                bookmarks[index]
-                            );
-                        }
-                        None => println!("User did not select anything"),
-                    }
-                }
-                None => {
-                    if json {
-                        println!(
-                            "{}",
-                            json!({
-                                "status": "fail",
-                                "reason": "an error ocurred in running your command",
-                            })
-                        );
-                    } else {
-                        warn!("an error ocurred in running your command");
-                    }
+            let mut items: Vec<&Url> = Vec::new();
+            for i in &bookmarks {
+                items.push(&i.link);
+            }
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .items(&items)
+                .default(0)
+                .interact_on_opt(&Term::stderr())
+                .unwrap();
+
+            match selection {
+                Some(index) => {
+                    println!("User selected item :\n{}", bookmarks[index]);
                 }
-            },
+                None => println!("User did not select anything"),
+            }
         }
     }
 }
 
 pub fn add_bookmark(
     url: &Url,
-    name: &String,
+    name: &str,
     description: &Option<String>,
-    tags: &Vec<String>,
+    tags: &[String],
+    folder: &Option<Uuid>,
     json: bool,
     path: Option<PathBuf>,
 ) {
+    if let Some(id) = folder {
+        check_folder(id, json, &path);
+    }
+
     let bookmark = Bookmark {
         link: url.to_owned(),
         label: name.to_string(),
         description: description.to_owned(),
-        tags: tags.to_vec(),
-        container: None,
+        tags: tags
+            .iter()
+            .flat_map(|t| t.split(','))
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect(),
+        container: *folder,
         created_at: Utc::now(),
     };
 
     database::insert_entry(json, path, Keyspace::Bookmarks, &bookmark);
     if json {
-        println!("{}", serde_json::to_string(&bookmark).unwrap());
+        output::print(&bookmark, json!({ "reason": "inserted entry" }));
     } else {
         println!("Added new bookmark!");
         println!("{}", bookmark);
     };
 }
 
-pub fn list_bookmarks(json: bool, path: Option<PathBuf>) {
-    match database::get_all(json, path, Keyspace::Bookmarks) {
-        Some(bookmarks) => {
-            for i in bookmarks {
-                if json {
-                    println!(
-                        "{}",
-                        serde_json::to_string(&i).unwrap()
-                    );
-                } else {
-                    println!("{}", &i);
+pub fn list_bookmarks(sort: SortOrder, json: bool, path: Option<PathBuf>) {
+    list_to(&mut io::stdout().lock(), sort, json, path);
+}
+
+/// List the bookmarks into `writer`
+pub fn list_to(writer: &mut dyn Write, sort: SortOrder, json: bool, path: Option<PathBuf>) {
+    let mut bookmarks = database::get_all(json, path, Keyspace::Bookmarks);
+    sort.sort(&mut bookmarks);
+    let result = if json {
+        writeln!(
+            writer,
+            "{}",
+            output::success(&bookmarks, serde_json::Value::Null)
+        )
+    } else {
+        if bookmarks.is_empty() {
+            info!("there are no bookmarks yet, add one with `tinymark add <url> <name>`");
+        }
+        bookmarks.iter().try_for_each(|i| writeln!(writer, "{}", i))
+    };
+    if let Err(e) = result {
+        fail(
+            json,
+            ErrorCode::Io,
+            &format!("error writing bookmarks: {}", e),
+        );
+    }
+}
+
+pub fn delete_bookmarks(
+    urls: &[Url],
+    query: &Option<String>,
+    dry_run: bool,
+    yes: bool,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let targets: Vec<Bookmark> = match query {
+        Some(query) => database::get_all(json, path.clone(), Keyspace::Bookmarks)
+            .into_iter()
+            .filter(|b| b.matches(query))
+            .collect(),
+        None => {
+            let mut found = Vec::new();
+            for url in urls {
+                match database::get_entry(url, json, path.clone(), Keyspace::Bookmarks) {
+                    Some(bookmark) => found.push(bookmark),
+                    None => fail(
+                        json,
+                        ErrorCode::NotFound,
+                        &format!("entry {} not found", url),
+                    ),
                 }
             }
+            found
+        }
+    };
+
+    if dry_run {
+        if json {
+            output::print(
+                &targets,
+                json!({ "reason": format!("would remove {} entries", targets.len()), "dry_run": true }),
+            );
+        } else {
+            info!("would remove {} entries:", targets.len());
+            for i in &targets {
+                println!("{}", i.link);
+            }
+        }
+        return;
+    }
+
+    if targets.is_empty() {
+        if json {
+            output::print(&targets, json!({ "reason": "nothing to remove" }));
+        } else {
+            info!("nothing to remove");
+        }
+        return;
+    }
+
+    if !yes {
+        if json {
+            fail(
+                json,
+                ErrorCode::Usage,
+                "refusing to delete without confirmation, pass --yes",
+            );
+        }
+
+        for i in &targets {
+            println!("{}", i.link);
+        }
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Delete {} bookmarks?", targets.len()))
+            .default(false)
+            .interact_on_opt(&Term::stderr())
+            .unwrap();
+
+        if confirmed != Some(true) {
+            info!("nothing was deleted");
+            return;
+        }
+    }
+
+    let links: Vec<Url> = targets.iter().map(|b| b.link.clone()).collect();
+    let removed = database::remove_entries(&links, json, path);
+
+    if json {
+        output::print(
+            &removed,
+            json!({ "reason": format!("moved {} entries to the trash", removed.len()) }),
+        );
+    } else {
+        for i in &removed {
+            info!("moved entry <i>{}</i> to the trash", i.link);
+        }
+    }
+}
+
+pub fn undo(json: bool, path: Option<PathBuf>) {
+    match database::undo(json, path) {
+        Some(operation) => {
+            if json {
+                output::print(
+                    operation_json(&operation),
+                    json!({ "reason": format!("undid #{}: {}", operation.id, operation.description) }),
+                );
+            } else {
+                info!("undid #{}: {}", operation.id, operation.description);
+            }
         }
         None => {
+            fail(json, ErrorCode::NotFound, "nothing to undo");
+        }
+    }
+}
+
+pub fn redo(json: bool, path: Option<PathBuf>) {
+    match database::redo(json, path) {
+        Some(operation) => {
             if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": "an error ocurred in running your command",
-                    })
+                output::print(
+                    operation_json(&operation),
+                    json!({ "reason": format!("redid #{}: {}", operation.id, operation.description) }),
                 );
             } else {
-                warn!("an error ocurred in running your command");
+                info!("redid #{}: {}", operation.id, operation.description);
             }
         }
+        None => {
+            fail(json, ErrorCode::NotFound, "nothing to redo");
+        }
     }
 }
 
-pub fn env_err(json: bool, e: VarError) {
+pub fn show_log(limit: &Option<usize>, json: bool, path: Option<PathBuf>) {
+    let operations = database::get_journal(json, path);
+    let (_, undone) = database::history(&operations);
+
+    let limit = limit.unwrap_or(operations.len());
+    let shown = operations.iter().rev().take(limit);
     if json {
+        let values: Vec<serde_json::Value> = shown
+            .map(|operation| {
+                let mut value = operation_json(operation);
+                value["undone"] = json!(undone.contains(&operation.id));
+                value
+            })
+            .collect();
+        return output::print(values, serde_json::Value::Null);
+    }
+    for operation in shown {
         println!(
-            "{}",
+            "#{:<6} {}  {}{}",
+            operation.id,
+            operation.at.with_timezone(&Local).to_rfc2822(),
+            operation.description,
+            if undone.contains(&operation.id) {
+                " (undone)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+/// The journal stores raw values, decode them so the JSON output is readable
+fn operation_json(operation: &Operation) -> serde_json::Value {
+    let decode = |keyspace: Keyspace, bytes: &Option<Vec<u8>>| -> serde_json::Value {
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return serde_json::Value::Null,
+        };
+        let value = match keyspace {
+            Keyspace::Bookmarks => bincode::deserialize::<Bookmark>(bytes).map(|v| json!(v)),
+            Keyspace::Containers => bincode::deserialize::<Container>(bytes).map(|v| json!(v)),
+            Keyspace::Trash => bincode::deserialize::<TrashEntry>(bytes).map(|v| json!(v)),
+            Keyspace::Journal | Keyspace::Sync | Keyspace::Replica => {
+                return serde_json::Value::Null
+            }
+        };
+        value.unwrap_or(serde_json::Value::Null)
+    };
+
+    let changes: Vec<serde_json::Value> = operation
+        .changes
+        .iter()
+        .map(|c| {
             json!({
-                "status": "fail",
-                "reason": e.to_string(),
+                "keyspace": c.keyspace,
+                "key": c.key,
+                "before": decode(c.keyspace, &c.before),
+                "after": decode(c.keyspace, &c.after),
             })
-        );
-    } else {
-        warn!("couldn't read $HOME environment variable: {}", e);
+        })
+        .collect();
+
+    json!({
+        "id": operation.id,
+        "at": operation.at,
+        "description": operation.description,
+        "kind": operation.kind,
+        "changes": changes,
+    })
+}
+
+/// Create the directory of the configuration file, which may not exist yet
+fn create_config_dir(path: &Path, json: bool) {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = fs::create_dir_all(dir) {
+            fail(
+                json,
+                ErrorCode::CantCreate,
+                &format!("error creating {}: {}", dir.display(), e),
+            );
+        }
+    }
+}
+
+/// The file given with `--config`, or the default one
+fn config_file(path: &Option<PathBuf>, json: bool) -> PathBuf {
+    match path.clone().or_else(config::default_path) {
+        Some(path) => path,
+        None => {
+            fail(
+                json,
+                ErrorCode::Config,
+                "couldn't find the configuration directory, pass --config",
+            );
+        }
     }
 }
 
-pub fn export(file_path: PathBuf, json: bool, path: Option<PathBuf>) {
-    let file = match File::create(&file_path) {
-        Ok(f) => f,
+/// Read the configuration from `path`, or the default location. A missing
+/// file is the default configuration, and isn't written until it changes.
+pub fn load_config(path: &Option<PathBuf>, json: bool) -> Config {
+    let file = config_file(path, json);
+    let text = match fs::read_to_string(&file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Config::default(),
         Err(e) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": e.to_string(),
-                    })
-                );
-            } else {
-                warn!("error opening file! {}", e);
-            }
-            std::process::exit(exitcode::DATAERR);
+            fail(
+                json,
+                ErrorCode::Config,
+                &format!("error reading {}: {}", file.display(), e),
+            );
         }
     };
-    let writer = BufWriter::new(file);
+    config::parse(&text).unwrap_or_else(|e| {
+        fail(
+            json,
+            ErrorCode::Config,
+            &format!(
+                "invalid configuration in {}: {}, fix it with `tinymark config edit`",
+                file.display(),
+                e.trim_end()
+            ),
+        );
+    })
+}
 
-    match database::get_all(json, path, Keyspace::Bookmarks) {
-        Some(bookmarks) => serde_json::to_writer(writer, &bookmarks).unwrap(),
-        None => std::process::exit(exitcode::IOERR),
+fn write_config(file: &Path, text: &str, json: bool) {
+    create_config_dir(file, json);
+    // Written next to it and renamed, so a failed write leaves the old one
+    let draft = file.with_extension("toml.new");
+    if let Err(e) = fs::write(&draft, text).and_then(|_| fs::rename(&draft, file)) {
+        fail(
+            json,
+            ErrorCode::CantCreate,
+            &format!("error writing {}: {}", file.display(), e),
+        );
     }
+}
 
+pub fn store_config(path: &Option<PathBuf>, cfg: &Config, json: bool) {
+    let text = config::to_string(cfg).unwrap_or_else(|e| {
+        fail(
+            json,
+            ErrorCode::Internal,
+            &format!("error writing the configuration: {}", e),
+        );
+    });
+    write_config(&config_file(path, json), &text, json);
+}
+
+pub fn config_path(path: &Option<PathBuf>, json: bool) {
+    let file = config_file(path, json);
     if json {
-        println!(
-            "{}",
-            json!({
-                "status": "success",
-                "reason": format!("exported bookmarks to {}", file_path.to_str().unwrap()),
-            })
+        output::print(
+            json!({ "path": file, "exists": file.exists() }),
+            serde_json::Value::Null,
         );
     } else {
-        info!(
-            "Succesfully exported bookmarks to {}!",
-            file_path.to_str().unwrap()
-        );
+        println!("{}", file.display());
     }
 }
 
-pub fn import(file_path: PathBuf, json: bool, store_path: Option<PathBuf>) {
-    let file = match File::open(&file_path) {
-        Ok(f) => f,
+/// Print the setting `key`, or the whole configuration
+pub fn get_config(key: &Option<String>, cfg: &Config, json: bool) {
+    let key = match key {
+        Some(key) => key,
+        None if json => return output::print(cfg, serde_json::Value::Null),
+        None => return print!("{}", config::to_string(cfg).unwrap_or_default()),
+    };
+    let value = match config::get(cfg, key) {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            fail(json, ErrorCode::InvalidData, &format!("{} isn't set", key));
+        }
         Err(e) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": e.to_string(),
-                    })
-                );
-            } else {
-                warn!("error opening file! {}", e);
-            }
-            std::process::exit(exitcode::DATAERR);
+            fail(json, ErrorCode::Usage, &e);
+        }
+    };
+    if json {
+        output::print(
+            json!({ "key": key, "value": value }),
+            serde_json::Value::Null,
+        );
+    } else {
+        match value {
+            toml::Value::String(text) => println!("{}", text),
+            toml::Value::Table(table) => print!("{}", toml::to_string_pretty(&table).unwrap()),
+            value => println!("{}", value),
         }
+    }
+}
+
+/// Save `changed`, unless it's the same as `cfg`
+fn save_setting(
+    changed: Result<Config, String>,
+    cfg: &Config,
+    reason: &str,
+    path: &Option<PathBuf>,
+    json: bool,
+) {
+    let changed = changed.unwrap_or_else(|e| {
+        fail(json, ErrorCode::Usage, &e);
+    });
+    let reason = if changed == *cfg {
+        "the configuration is unchanged"
+    } else {
+        store_config(path, &changed, json);
+        reason
     };
-    let reader = BufReader::new(file);
+    if json {
+        output::print_reason(reason);
+    } else {
+        info!("{}", reason);
+    }
+}
 
-    let bookmarks: Vec<Bookmark> = match serde_json::from_reader(reader) {
-        Ok(contents) => contents,
+pub fn set_config(key: &str, value: &str, cfg: &Config, path: &Option<PathBuf>, json: bool) {
+    save_setting(
+        config::set(cfg, key, value),
+        cfg,
+        &format!("set {} to {}", key, value),
+        path,
+        json,
+    );
+}
+
+pub fn unset_config(key: &str, cfg: &Config, path: &Option<PathBuf>, json: bool) {
+    save_setting(
+        config::unset(cfg, key),
+        cfg,
+        &format!("reset {} to its default", key),
+        path,
+        json,
+    );
+}
+
+/// Edit a copy of the configuration, which only replaces the file once it's valid
+pub fn edit_config(path: &Option<PathBuf>, json: bool) {
+    if json {
+        fail(
+            json,
+            ErrorCode::Usage,
+            "config edit is interactive, use config set with --json",
+        );
+    }
+    let file = config_file(path, json);
+    let original = match fs::read_to_string(&file) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            config::to_string(&Config::default()).unwrap()
+        }
         Err(e) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": e.to_string(),
-                    })
+            fail(
+                json,
+                ErrorCode::Config,
+                &format!("error reading {}: {}", file.display(), e),
+            );
+        }
+    };
+
+    let mut text = original.clone();
+    loop {
+        text = match dialoguer::Editor::new().extension(".toml").edit(&text) {
+            Ok(Some(edited)) => edited,
+            Ok(None) => {
+                info!("the configuration is unchanged");
+                return;
+            }
+            Err(e) => {
+                fail(
+                    json,
+                    ErrorCode::Unavailable,
+                    &format!("error running the editor: {}", e),
                 );
-            } else {
-                warn!("error serializing file! {}", e);
             }
-            std::process::exit(exitcode::DATAERR);
+        };
+        let error = match config::parse(&text) {
+            Ok(_) => break,
+            Err(e) => e,
+        };
+        report(
+            json,
+            ErrorCode::Config,
+            &format!("invalid configuration: {}", error.trim_end()),
+        );
+        let again = Term::stderr().is_term()
+            && Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Edit it again?")
+                .default(true)
+                .interact_on_opt(&Term::stderr())
+                .unwrap()
+                == Some(true);
+        if !again {
+            fail(
+                json,
+                ErrorCode::Config,
+                &format!("kept {} as it was", file.display()),
+            );
         }
+    }
+
+    if text == original {
+        info!("the configuration is unchanged");
+    } else {
+        write_config(&file, &text, json);
+        info!("saved {}", file.display());
+    }
+}
+
+/// The profile chosen with `--profile` or `default_profile`, which has to exist
+pub fn selected_profile(name: &Option<String>, cfg: &Config, json: bool) -> Option<String> {
+    let name = name.as_ref().or(cfg.default_profile.as_ref())?;
+    if !cfg.profiles.contains_key(name) {
+        fail(
+            json,
+            ErrorCode::Config,
+            &format!(
+                "there's no profile {}, create it with `tinymark profile create {}`",
+                name, name
+            ),
+        );
+    }
+    Some(name.clone())
+}
+
+/// The actor of the store in use, which it keeps from the first time it's
+/// needed. The `actor` of the configuration or profile is where the store they
+/// point at starts, other stores get a new one.
+pub fn store_actor(
+    cfg: &Config,
+    profile: &Option<String>,
+    configured: bool,
+    json: bool,
+    path: Option<PathBuf>,
+) -> Uuid {
+    let configured_actor = match profile {
+        Some(name) => cfg.profiles[name].actor,
+        None => cfg.actor,
     };
+    let default = configured_actor
+        .filter(|_| configured)
+        .unwrap_or_else(Uuid::new_v4);
+    database::store_actor(default, json, path)
+}
 
-    database::insert_multiple(&bookmarks, json, store_path, Keyspace::Bookmarks);
+/// Exit because there's no home directory to keep the store in
+pub fn env_err(json: bool, e: VarError) -> ! {
+    fail(
+        json,
+        ErrorCode::Config,
+        &format!(
+            "couldn't read $HOME environment variable: {}, pass --db or set XDG_DATA_HOME",
+            e
+        ),
+    )
+}
 
-    if json {
-        println!(
-            "{}",
-            json!({
-                "status": "success",
-                "reason": format!("imported bookmarks from {}", file_path.to_str().unwrap()),
-            })
+/// Open `file_path` for writing, or stdout when it's `-`
+fn open_output(file_path: &Path, json: bool) -> Box<dyn Write> {
+    if file_path == Path::new("-") {
+        return Box::new(BufWriter::new(io::stdout()));
+    }
+    match File::create(file_path) {
+        Ok(f) => Box::new(BufWriter::new(f)),
+        Err(e) => fail(
+            json,
+            ErrorCode::CantCreate,
+            &format!("error creating {}: {}", file_path.display(), e),
+        ),
+    }
+}
+
+/// Open `file_path` for reading, or stdin when it's `-`
+fn open_input(file_path: &Path, json: bool) -> Box<dyn BufRead> {
+    if file_path == Path::new("-") {
+        return Box::new(BufReader::new(io::stdin()));
+    }
+    match File::open(file_path) {
+        Ok(f) => Box::new(BufReader::new(f)),
+        Err(e) => fail(
+            json,
+            ErrorCode::NoInput,
+            &format!("error opening {}: {}", file_path.display(), e),
+        ),
+    }
+}
+
+/// The format given with `--format`, guessed from the extension, or else the
+/// configured `default_format`
+fn detect_format(
+    file_path: &Path,
+    format: Option<FileFormat>,
+    default: Option<FileFormat>,
+) -> FileFormat {
+    format.unwrap_or_else(|| match file_path.extension().and_then(|e| e.to_str()) {
+        Some("jsonl") | Some("ndjson") => FileFormat::Jsonl,
+        Some("md") | Some("markdown") => FileFormat::Markdown,
+        Some("org") => FileFormat::Org,
+        Some("csv") => FileFormat::Raindrop,
+        Some("db") | Some("sqlite") => FileFormat::Buku,
+        _ => default.unwrap_or(FileFormat::Json),
+    })
+}
+
+fn write_record(
+    writer: &mut dyn Write,
+    format: FileFormat,
+    first: bool,
+    bookmark: &Bookmark,
+) -> io::Result<()> {
+    if format == FileFormat::Json && !first {
+        writer.write_all(b",")?;
+    }
+    serde_json::to_writer(&mut *writer, bookmark)?;
+    if format == FileFormat::Jsonl {
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Every tag in use and how many bookmarks have it, sorted by name
+pub fn tag_counts(json: bool, path: Option<PathBuf>) -> Vec<TagInfo> {
+    try_tag_counts(json, path).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_tag_counts(json: bool, path: Option<PathBuf>) -> Result<Vec<TagInfo>, Failure> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    database::try_for_each(json, path, Keyspace::Bookmarks, |bookmark| {
+        for tag in bookmark.tags {
+            *counts.entry(tag).or_default() += 1;
+        }
+    })?;
+    Ok(counts
+        .into_iter()
+        .map(|(name, count)| TagInfo { name, count })
+        .collect())
+}
+
+/// Start an [`ExportDocument`], leaving its `bookmarks` array open to be streamed into
+fn write_header(writer: &mut dyn Write, json: bool, path: &Option<PathBuf>) -> io::Result<()> {
+    let containers = database::get_containers(json, path.clone());
+    let tags = tag_counts(json, path.clone());
+
+    write!(
+        writer,
+        "{{\"version\":{},\"exported_at\":{},\"containers\":{},\"tags\":{},\"bookmarks\":[",
+        EXPORT_VERSION,
+        serde_json::to_string(&Utc::now())?,
+        serde_json::to_string(&containers)?,
+        serde_json::to_string(&tags)?,
+    )
+}
+
+pub fn export(
+    file_path: PathBuf,
+    format: Option<FileFormat>,
+    default_format: Option<FileFormat>,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let format = detect_format(&file_path, format, default_format);
+    if format.is_import_only() {
+        fail(
+            json,
+            ErrorCode::Usage,
+            &format!("{:?} files can only be imported", format),
         );
-    } else {
-        info!(
-            "succesfully imported bookmarks from {}!",
-            file_path.to_str().unwrap()
+    }
+    if format == FileFormat::Buku {
+        return export_buku(&file_path, json, path);
+    }
+    let mut writer = open_output(&file_path, json);
+    export_to(&mut writer, &file_path, format, json, path);
+}
+
+/// Export into `writer`, which is stdout when `file_path` is `-`
+pub fn export_to(
+    writer: &mut dyn Write,
+    file_path: &Path,
+    format: FileFormat,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let mut result = match format {
+        FileFormat::Json => write_header(writer, json, &path),
+        FileFormat::Markdown | FileFormat::Org => {
+            write_outline(writer, format, json, path);
+            return finish_export(file_path, writer, json);
+        }
+        _ => Ok(()),
+    };
+
+    // Write each bookmark as it's read, so the whole store is never held in memory
+    let mut first = true;
+    database::for_each(json, path, Keyspace::Bookmarks, |bookmark| {
+        if result.is_err() {
+            return;
+        }
+        result = write_record(writer, format, first, &bookmark);
+        first = false;
+    });
+    if format == FileFormat::Json {
+        result = result.and_then(|_| writer.write_all(b"]}"));
+    }
+    if let Err(e) = result {
+        fail(json, ErrorCode::Io, &format!("error writing export: {}", e));
+    }
+    finish_export(file_path, writer, json);
+}
+
+/// Write every bookmark into a buku database, which has to be a file
+fn export_buku(file_path: &Path, json: bool, path: Option<PathBuf>) {
+    check_seekable(file_path, json);
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path, Keyspace::Bookmarks, |b| bookmarks.push(b));
+    if let Err(e) = buku::write(file_path, &bookmarks) {
+        fail(json, ErrorCode::Io, &format!("error writing export: {}", e));
+    }
+    finish_export(file_path, &mut io::sink(), json);
+}
+
+/// Exit if `file_path` is stdin or stdout, for formats that need a real file
+fn check_seekable(file_path: &Path, json: bool) {
+    if file_path == Path::new("-") {
+        fail(
+            json,
+            ErrorCode::Usage,
+            "buku databases can't be read from stdin or written to stdout",
         );
     }
 }
 
-pub fn new_folder(label: &String, json: bool) {
-    let folder = Container {
-        id: Uuid::new_v4(),
-        label: label.to_string(),
-        container: None,
-        container_type: ContainerTypes::Folder,
+/// Render the whole hierarchy at once, outlines can't be streamed
+fn write_outline(writer: &mut dyn Write, format: FileFormat, json: bool, path: Option<PathBuf>) {
+    let containers = database::get_containers(json, path.clone());
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path, Keyspace::Bookmarks, |b| bookmarks.push(b));
+
+    let text = match format {
+        FileFormat::Org => outline::to_org(&containers, &bookmarks),
+        _ => outline::to_markdown(&containers, &bookmarks),
     };
-    println!("{:?}", folder);
+    if let Err(e) = writer.write_all(text.as_bytes()) {
+        fail(json, ErrorCode::Io, &format!("error writing export: {}", e));
+    }
+}
+
+fn finish_export(file_path: &Path, writer: &mut dyn Write, json: bool) {
+    if let Err(e) = writer.flush() {
+        fail(json, ErrorCode::Io, &format!("error writing export: {}", e));
+    }
+
+    // Don't mix a status message into the exported data
+    if file_path == Path::new("-") {
+        return;
+    }
+
     if json {
-        println!("{}", serde_json::to_string(&folder).unwrap());
+        output::print_reason(&format!(
+            "exported bookmarks to {}",
+            file_path.to_str().unwrap()
+        ));
     } else {
-        println!("{:?}", folder);
+        info!(
+            "Succesfully exported bookmarks to {}!",
+            file_path.to_str().unwrap()
+        );
+    }
+}
+
+/// How many bookmarks of a JSON Lines import are looked up in the store at once
+const IMPORT_CHUNK: usize = 1000;
+
+pub fn import(
+    file_path: PathBuf,
+    format: Option<FileFormat>,
+    default_format: Option<FileFormat>,
+    on_conflict: ConflictStrategy,
+    normalize: &Normalize,
+    json: bool,
+    store_path: Option<PathBuf>,
+) {
+    if (json || file_path == Path::new("-")) && on_conflict == ConflictStrategy::Ask {
+        fail(
+            json,
+            ErrorCode::Usage,
+            "--on-conflict ask can't be used with --json or when reading from stdin",
+        );
     }
+
+    let format = detect_format(&file_path, format, default_format);
+    if format == FileFormat::Buku {
+        check_seekable(&file_path, json);
+        let mut bookmarks = match buku::read(&file_path) {
+            Ok(bookmarks) => bookmarks,
+            Err(e) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("error reading buku database: {}", e),
+                );
+            }
+        };
+        // Buku has no dates, so ones already stored aren't replaced by today
+        let links: Vec<Url> = bookmarks.iter().map(|b| normalize.apply(&b.link)).collect();
+        let stored = database::get_multiple(&links, json, store_path.clone(), Keyspace::Bookmarks);
+        for (bookmark, stored) in bookmarks.iter_mut().zip(stored) {
+            if let Some(stored) = stored {
+                bookmark.created_at = stored.created_at;
+            }
+        }
+        let mut summary = ImportSummary::default();
+        let mut pending = PendingImport::default();
+        import_bookmarks(
+            bookmarks,
+            on_conflict,
+            normalize,
+            &mut summary,
+            &mut pending,
+            json,
+            &store_path,
+        );
+        pending.write(json, &store_path);
+        return finish_import(&file_path, &summary, json);
+    }
+    let reader = open_input(&file_path, json);
+    import_from(
+        reader,
+        &file_path,
+        format,
+        on_conflict,
+        normalize,
+        json,
+        store_path,
+    );
 }
 
-pub fn create_heirarchy(bookmarks: Vec<Bookmark>, containers: Vec<Container>) {}
+/// Import what `reader` has, which is stdin when `file_path` is `-`
+pub fn import_from(
+    reader: impl BufRead,
+    file_path: &Path,
+    format: FileFormat,
+    on_conflict: ConflictStrategy,
+    normalize: &Normalize,
+    json: bool,
+    store_path: Option<PathBuf>,
+) -> ImportSummary {
+    let mut summary = ImportSummary::default();
+    let mut pending = PendingImport::default();
+    match format {
+        FileFormat::Json => {
+            let contents: ImportFile = match serde_json::from_reader(reader) {
+                Ok(contents) => contents,
+                Err(e) => fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("error reading file! {}", e),
+                ),
+            };
+            let bookmarks = match contents {
+                ImportFile::Bookmarks(bookmarks) => bookmarks,
+                ImportFile::Document(document) => {
+                    import_document(document, &mut summary, &mut pending, json, &store_path)
+                }
+            };
+            import_bookmarks(
+                bookmarks,
+                on_conflict,
+                normalize,
+                &mut summary,
+                &mut pending,
+                json,
+                &store_path,
+            );
+        }
+        FileFormat::Jsonl => {
+            let mut chunk = Vec::new();
+            for (number, line) in reader.lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        fail(json, ErrorCode::Io, &format!("error reading file! {}", e));
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(&line) {
+                    Ok(bookmark) => chunk.push(bookmark),
+                    Err(e) => {
+                        fail(
+                            json,
+                            ErrorCode::InvalidData,
+                            &format!("error on line {}: {}", number + 1, e),
+                        );
+                    }
+                }
+                if chunk.len() == IMPORT_CHUNK {
+                    let full = std::mem::take(&mut chunk);
+                    import_bookmarks(
+                        full,
+                        on_conflict,
+                        normalize,
+                        &mut summary,
+                        &mut pending,
+                        json,
+                        &store_path,
+                    );
+                }
+            }
+            import_bookmarks(
+                chunk,
+                on_conflict,
+                normalize,
+                &mut summary,
+                &mut pending,
+                json,
+                &store_path,
+            );
+        }
+        _ => {
+            let mut text = String::new();
+            let mut reader = reader;
+            if let Err(e) = reader.read_to_string(&mut text) {
+                fail(json, ErrorCode::Io, &format!("error reading file! {}", e));
+            }
+            let parsed = match format {
+                FileFormat::Org => outline::from_org(&text),
+                FileFormat::Pinboard => services::from_pinboard(&text),
+                FileFormat::Raindrop => services::from_raindrop(&text),
+                FileFormat::Pocket => services::from_pocket(&text),
+                FileFormat::Linkding => services::from_linkding(&text),
+                _ => outline::from_markdown(&text),
+            };
+            let (containers, bookmarks) = match parsed {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    fail(json, ErrorCode::InvalidData, &e);
+                }
+            };
+
+            let existing = database::get_containers(json, store_path.clone());
+            let (containers, mapping) = match_folders(containers, &existing);
+            summary.folders += containers.len();
+            pending.containers.extend(containers);
+            let bookmarks = bookmarks
+                .into_iter()
+                .map(|mut b| {
+                    b.container = b.container.map(|id| mapping[&id]);
+                    b
+                })
+                .collect();
+            import_bookmarks(
+                bookmarks,
+                on_conflict,
+                normalize,
+                &mut summary,
+                &mut pending,
+                json,
+                &store_path,
+            );
+        }
+    }
+
+    pending.write(json, &store_path);
+    finish_import(file_path, &summary, json);
+    summary
+}
+
+fn finish_import(file_path: &Path, summary: &ImportSummary, json: bool) {
+    let reason = format!("imported bookmarks from {}", file_path.to_str().unwrap());
+    print_summary(&reason, summary, json);
+}
+
+fn print_summary(reason: &str, summary: &ImportSummary, json: bool) {
+    if json {
+        output::print(
+            json!({
+                "links": summary.outcomes.len(),
+                "added": summary.count(Outcome::Added),
+                "updated": summary.count(Outcome::Updated),
+                "skipped": summary.count(Outcome::Skipped),
+                "conflicted": summary.conflicted.len(),
+                "folders": summary.folders,
+            }),
+            json!({ "reason": reason }),
+        );
+    } else {
+        info!("succesfully {}!", reason);
+        println!(
+            "{} added, {} updated and {} skipped, of which {} conflicted; {} folders",
+            summary.count(Outcome::Added),
+            summary.count(Outcome::Updated),
+            summary.count(Outcome::Skipped),
+            summary.conflicted.len(),
+            summary.folders
+        );
+    }
+}
+
+/// Import the folders of `document` and return its bookmarks pointing at them
+fn import_document(
+    document: ExportDocument,
+    summary: &mut ImportSummary,
+    pending: &mut PendingImport,
+    json: bool,
+    store_path: &Option<PathBuf>,
+) -> Vec<Bookmark> {
+    if document.version > EXPORT_VERSION {
+        fail(
+            json,
+            ErrorCode::InvalidData,
+            &format!(
+                "export version {} is newer than the supported version {}, please upgrade tinymark",
+                document.version, EXPORT_VERSION
+            ),
+        );
+    }
+
+    let existing = database::get_containers(json, store_path.clone());
+    let (containers, mapping) = remap_containers(document.containers, &existing);
+    summary.folders += containers.len();
+    pending.containers.extend(containers);
+
+    let known = |id: &Uuid| mapping.contains_key(id) || existing.iter().any(|c| c.id == *id);
+    document
+        .bookmarks
+        .into_iter()
+        .map(|mut bookmark| {
+            bookmark.container = bookmark
+                .container
+                .filter(known)
+                .map(|id| mapping.get(&id).copied().unwrap_or(id));
+            bookmark
+        })
+        .collect()
+}
+
+/// Give imported containers ids that don't clash with `existing` ones.
+///
+/// A container whose id is already stored with the same label and type is
+/// the same folder and is reused, any other clash gets a fresh id. Parents
+/// that are neither imported nor stored are dropped. Returns the containers
+/// that need to be written and the mapping from imported to stored ids.
+pub fn remap_containers(
+    imported: Vec<Container>,
+    existing: &[Container],
+) -> (Vec<Container>, HashMap<Uuid, Uuid>) {
+    let mut mapping = HashMap::new();
+    let mut reused = HashSet::new();
+    for container in &imported {
+        let stored = existing.iter().find(|c| c.id == container.id);
+        let id = match stored {
+            None => container.id,
+            Some(stored)
+                if stored.label == container.label
+                    && stored.container_type == container.container_type =>
+            {
+                reused.insert(container.id);
+                container.id
+            }
+            Some(_) => Uuid::new_v4(),
+        };
+        mapping.insert(container.id, id);
+    }
+
+    let containers = imported
+        .into_iter()
+        .filter(|c| !reused.contains(&c.id))
+        .map(|mut container| {
+            container.id = mapping[&container.id];
+            container.container =
+                container
+                    .container
+                    .and_then(|parent| match mapping.get(&parent) {
+                        Some(id) => Some(*id),
+                        None => existing.iter().any(|c| c.id == parent).then_some(parent),
+                    });
+            container
+        })
+        .collect();
+    (containers, mapping)
+}
+
+/// Match folders parsed from an outline with the stored ones by their label
+/// and parent, since outlines don't carry ids. `parsed` must list parents
+/// before their children. Returns the folders that need to be created and
+/// the mapping from parsed to stored ids.
+pub fn match_folders(
+    parsed: Vec<Container>,
+    existing: &[Container],
+) -> (Vec<Container>, HashMap<Uuid, Uuid>) {
+    let mut mapping = HashMap::new();
+    let mut created: Vec<Container> = Vec::new();
+    for mut container in parsed {
+        let parent = container.container.and_then(|id| mapping.get(&id).copied());
+        let found = existing
+            .iter()
+            .chain(created.iter())
+            .find(|c| c.label == container.label && c.container == parent)
+            .map(|c| c.id);
+        match found {
+            Some(id) => {
+                mapping.insert(container.id, id);
+            }
+            None => {
+                mapping.insert(container.id, container.id);
+                container.container = parent;
+                created.push(container);
+            }
+        }
+    }
+    (created, mapping)
+}
+
+/// Resolve conflicts between `bookmarks` and the store, then write them
+fn import_bookmarks(
+    bookmarks: Vec<Bookmark>,
+    on_conflict: ConflictStrategy,
+    normalize: &Normalize,
+    summary: &mut ImportSummary,
+    pending: &mut PendingImport,
+    json: bool,
+    store_path: &Option<PathBuf>,
+) {
+    if bookmarks.is_empty() {
+        return;
+    }
+    let bookmarks: Vec<Bookmark> = bookmarks
+        .into_iter()
+        .map(|mut b| {
+            b.link = normalize.apply(&b.link);
+            b
+        })
+        .collect();
+
+    let links: Vec<Url> = bookmarks.iter().map(|b| b.link.clone()).collect();
+    let existing = database::get_multiple(&links, json, store_path.clone(), Keyspace::Bookmarks);
+
+    for (imported, stored) in bookmarks.into_iter().zip(existing) {
+        // Duplicates within the file are resolved against each other
+        let link = imported.link.clone();
+        let was_stored = stored.is_some();
+        let position = pending.positions.get(&link).copied();
+        let current = match position {
+            Some(i) => Some(pending.bookmarks[i].clone()),
+            None => stored,
+        };
+
+        let resolved = match current {
+            None => Some(imported),
+            Some(current) if current == imported => None,
+            Some(current) => {
+                summary.conflicted.insert(link.clone());
+                let strategy = match on_conflict {
+                    ConflictStrategy::Ask => ask_conflict(&current, &imported),
+                    strategy => strategy,
+                };
+                let resolved = match strategy {
+                    ConflictStrategy::Overwrite => imported,
+                    ConflictStrategy::Merge => current.merge(&imported),
+                    _ => current.clone(),
+                };
+                (resolved != current).then_some(resolved)
+            }
+        };
+
+        // A link that's already been written keeps its outcome
+        let outcome = if !was_stored {
+            Outcome::Added
+        } else if resolved.is_some() || position.is_some() {
+            Outcome::Updated
+        } else {
+            Outcome::Skipped
+        };
+        summary.outcomes.insert(link, outcome);
+
+        if let Some(bookmark) = resolved {
+            match position {
+                Some(i) => pending.bookmarks[i] = bookmark,
+                None => {
+                    pending
+                        .positions
+                        .insert(bookmark.link.clone(), pending.bookmarks.len());
+                    pending.bookmarks.push(bookmark);
+                }
+            }
+        }
+    }
+}
+
+/// What an import writes once it's read everything, so it's a single change to undo
+#[derive(Default)]
+struct PendingImport {
+    containers: Vec<Container>,
+    bookmarks: Vec<Bookmark>,
+    /// Where each link is in `bookmarks`
+    positions: HashMap<Url, usize>,
+}
+
+impl PendingImport {
+    fn write(self, json: bool, store_path: &Option<PathBuf>) {
+        if !self.containers.is_empty() || !self.bookmarks.is_empty() {
+            database::insert_imported(json, store_path.clone(), &self.containers, &self.bookmarks);
+        }
+    }
+}
+
+/// What an import did with a link
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Added,
+    Updated,
+    Skipped,
+}
+
+#[derive(Default)]
+pub struct ImportSummary {
+    pub folders: usize,
+    /// The outcome of each link, which counts once however often it's in the file
+    pub outcomes: HashMap<Url, Outcome>,
+    /// The links that differed from the stored bookmark, whatever their outcome
+    pub conflicted: HashSet<Url>,
+}
+
+impl ImportSummary {
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.outcomes.values().filter(|o| **o == outcome).count()
+    }
+}
+
+fn ask_conflict(current: &Bookmark, imported: &Bookmark) -> ConflictStrategy {
+    println!("Stored:\n{}\nImported:\n{}", current, imported);
+    let choices = [
+        ConflictStrategy::Skip,
+        ConflictStrategy::Overwrite,
+        ConflictStrategy::Merge,
+    ];
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!("{} already exists", current.link))
+        .items(&["keep stored", "overwrite", "merge"])
+        .default(0)
+        .interact_on_opt(&Term::stderr())
+        .unwrap();
+
+    match selection {
+        Some(index) => choices[index],
+        None => ConflictStrategy::Skip,
+    }
+}
+
+/// Exit unless the folder `id` exists
+fn check_folder(id: &Uuid, json: bool, path: &Option<PathBuf>) {
+    if !database::get_containers(json, path.clone())
+        .iter()
+        .any(|c| c.id == *id)
+    {
+        fail(
+            json,
+            ErrorCode::NotFound,
+            &format!("folder {} not found", id),
+        );
+    }
+}
+
+pub fn new_folder(label: &str, parent: &Option<Uuid>, json: bool, path: Option<PathBuf>) {
+    if let Some(id) = parent {
+        check_folder(id, json, &path);
+    }
+
+    let folder = Container {
+        id: Uuid::new_v4(),
+        label: label.to_string(),
+        container: *parent,
+        container_type: ContainerTypes::Folder,
+    };
+    database::insert_container(json, path, &folder);
+    if json {
+        output::print(&folder, json!({ "reason": "created folder" }));
+    } else {
+        info!(
+            "created folder <i>{}</i> with id {}",
+            folder.label, folder.id
+        );
+    }
+}
+
+/// Where `tinymark publish` looks for templates by default, next to the configuration
+pub fn templates_dir(config_path: &Option<PathBuf>, json: bool) -> PathBuf {
+    let file = config_file(config_path, json);
+    file.parent().unwrap_or(Path::new(".")).join("templates")
+}
+
+pub fn publish(
+    dir: &Option<PathBuf>,
+    title: &str,
+    base_url: &Option<Url>,
+    templates_dir: PathBuf,
+    init_templates: bool,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    if init_templates {
+        match site::Templates::write_defaults(&templates_dir) {
+            Ok(written) => {
+                if json {
+                    output::print(
+                        &written,
+                        json!({ "reason": format!("wrote {} templates", written.len()) }),
+                    );
+                } else {
+                    for i in &written {
+                        info!("wrote <i>{}", i.display());
+                    }
+                    info!("templates are in {}", templates_dir.display());
+                }
+            }
+            Err(e) => {
+                fail(
+                    json,
+                    ErrorCode::CantCreate,
+                    &format!("error writing templates: {}", e),
+                );
+            }
+        }
+        return;
+    }
+
+    let dir = match dir {
+        Some(dir) => dir,
+        None => {
+            fail(json, ErrorCode::Usage, "no directory to publish to");
+        }
+    };
+
+    let templates = match site::Templates::load(&templates_dir) {
+        Ok(templates) => templates,
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::Config,
+                &format!("error reading templates: {}", e),
+            );
+        }
+    };
+
+    let containers = database::get_containers(json, path.clone());
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path, Keyspace::Bookmarks, |b| bookmarks.push(b));
+
+    let site = site::Site {
+        title,
+        base_url: base_url.as_ref(),
+        templates,
+    };
+    match site::publish(dir, &site, &containers, &bookmarks) {
+        Ok(pages) => {
+            if json {
+                output::print(
+                    json!({ "pages": pages }),
+                    json!({ "reason": format!("published {} pages to {}", pages, dir.display()) }),
+                );
+            } else {
+                info!("published {} pages to {}", pages, dir.display());
+            }
+        }
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::Io,
+                &format!("error publishing site: {}", e),
+            );
+        }
+    }
+}
+
+pub fn feed(
+    tag: &Option<String>,
+    limit: usize,
+    format: FeedFormat,
+    title: &Option<String>,
+    link: &Option<Url>,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path, Keyspace::Bookmarks, |b| bookmarks.push(b));
+    let bookmarks = feed::recent(bookmarks, tag.as_deref(), limit);
+
+    let (default_title, description, id) = match tag {
+        Some(tag) => (
+            format!("Bookmarks tagged #{}", tag),
+            format!("Recently added bookmarks tagged #{}", tag),
+            format!("urn:tinymark:tag:{}", site::slug(tag)),
+        ),
+        None => (
+            "Bookmarks".to_string(),
+            "Recently added bookmarks".to_string(),
+            "urn:tinymark:recent".to_string(),
+        ),
+    };
+    let info = feed::FeedInfo {
+        title: title.as_deref().unwrap_or(&default_title),
+        id: link.as_ref().map_or(id.as_str(), |l| l.as_str()),
+        link: link.as_ref().map(|l| l.as_str()),
+        description: &description,
+    };
+
+    let document = match format {
+        FeedFormat::Atom => feed::atom(&info, &bookmarks),
+        FeedFormat::Rss => feed::rss(&info, &bookmarks),
+    };
+    if json {
+        output::print(
+            document,
+            json!({ "reason": format!("feed of {} bookmarks", bookmarks.len()) }),
+        );
+    } else {
+        print!("{}", document);
+    }
+}
+
+fn sync_err(json: bool, e: String) -> ! {
+    fail(json, ErrorCode::Io, &format!("error syncing: {}", e));
+}
+
+pub fn sync(dir: &Path, json: bool, path: Option<PathBuf>) {
+    if let Err(e) = fs::create_dir_all(dir) {
+        sync_err(json, e.to_string());
+    }
+    // States are kept per directory, however it's spelled
+    let dir_key = match dir.canonicalize() {
+        Ok(dir) => dir.display().to_string(),
+        Err(e) => sync_err(json, e.to_string()),
+    };
+    let remote = match sync::read_dir(dir) {
+        Ok(remote) => remote,
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::InvalidData,
+                &format!("error reading {}: {}", dir.display(), e),
+            );
+        }
+    };
+
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path.clone(), Keyspace::Bookmarks, |b| {
+        bookmarks.push(b)
+    });
+    let local = sync::local_versions(bookmarks, &database::get_journal(json, path.clone()));
+    let folders: HashSet<Uuid> = database::get_containers(json, path.clone())
+        .iter()
+        .map(|c| c.id)
+        .collect();
+    let states = database::get_sync_states(&dir_key, json, path.clone());
+
+    let mut keys: Vec<&String> = local.keys().chain(remote.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut pulled = Vec::new();
+    let mut deleted = Vec::new();
+    let mut new_states = Vec::new();
+    let mut pushed = 0;
+    let mut conflicts = Vec::new();
+    for key in keys {
+        let local = local.get(key);
+        // Folders aren't synced, so keep the local one when the shared one doesn't exist here
+        let remote = remote.get(key).cloned().map(|version| match version {
+            sync::Version::Present(mut bookmark, at) => {
+                if !bookmark.container.is_some_and(|id| folders.contains(&id)) {
+                    bookmark.container = match local {
+                        Some(sync::Version::Present(b, _)) => b.container,
+                        _ => None,
+                    };
+                }
+                sync::Version::Present(bookmark, at)
+            }
+            deleted => deleted,
+        });
+        let link = match Url::parse(key) {
+            Ok(link) => link,
+            Err(_) => continue,
+        };
+
+        let (winner, loser, push) = match sync::resolve(local, remote.as_ref(), states.get(key)) {
+            sync::Resolution::Unchanged => {
+                if let (Some(local), Some(remote)) = (local, &remote) {
+                    let state = SyncState {
+                        local: local.updated_at(),
+                        remote: remote.updated_at(),
+                    };
+                    if states.get(key) != Some(&state) {
+                        new_states.push((key.clone(), state));
+                    }
+                }
+                continue;
+            }
+            sync::Resolution::Push { conflict } => {
+                (local.cloned(), remote.filter(|_| conflict), true)
+            }
+            sync::Resolution::Pull { conflict } => {
+                (remote, local.cloned().filter(|_| conflict), false)
+            }
+        };
+        let winner = match winner {
+            Some(winner) => winner,
+            None => continue,
+        };
+
+        if let Some(sync::Version::Present(bookmark, at)) = loser {
+            match sync::write_conflict(dir, &bookmark, at) {
+                Ok(file) => conflicts.push(file),
+                Err(e) => sync_err(json, e),
+            }
+        }
+
+        if push {
+            if let Err(e) = sync::write(dir, &link, &winner) {
+                sync_err(json, e);
+            }
+            pushed += 1;
+            let state = SyncState {
+                local: winner.updated_at(),
+                remote: winner.updated_at(),
+            };
+            new_states.push((key.clone(), state));
+        } else {
+            match &winner {
+                sync::Version::Present(bookmark, _) => pulled.push(bookmark.clone()),
+                sync::Version::Deleted(_) => {
+                    if let Some(sync::Version::Present(bookmark, _)) = local {
+                        deleted.push(bookmark.clone());
+                    }
+                }
+            }
+            // The local time of anything written is filled in once it's applied
+            let state = SyncState {
+                local: local.map_or(winner.updated_at(), |l| l.updated_at()),
+                remote: winner.updated_at(),
+            };
+            new_states.push((key.clone(), state));
+        }
+    }
+
+    let description = format!("sync with {}", dir_key);
+    let applied_at = database::apply_sync(&pulled, &deleted, &description, json, path.clone());
+    let written: HashSet<String> = pulled
+        .iter()
+        .chain(deleted.iter())
+        .map(|b| b.link.to_string())
+        .collect();
+    for (key, state) in &mut new_states {
+        if written.contains(key) {
+            state.local = applied_at;
+        }
+    }
+    database::set_sync_states(&dir_key, &new_states, json, path);
+
+    if json {
+        output::print(
+            json!({
+                "pushed": pushed,
+                "pulled": pulled.len(),
+                "deleted": deleted.len(),
+                "conflicts": conflicts,
+            }),
+            json!({ "reason": format!("synced with {}", dir.display()) }),
+        );
+    } else {
+        info!("synced with {}", dir.display());
+        println!(
+            "{} pushed, {} pulled, {} deleted, {} conflicts",
+            pushed,
+            pulled.len(),
+            deleted.len(),
+            conflicts.len()
+        );
+        for file in &conflicts {
+            warn!("kept a conflicting copy in {}", file);
+        }
+    }
+}
+
+/// The replicas of every bookmark, brought up to date with the changes made
+/// to the store since they were last looked at
+fn observe_store(
+    actor: Uuid,
+    json: bool,
+    path: &Option<PathBuf>,
+) -> (
+    HashMap<String, Replica>,
+    HashMap<String, sync::Version>,
+    HashSet<Uuid>,
+) {
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path.clone(), Keyspace::Bookmarks, |b| {
+        bookmarks.push(b)
+    });
+    let local = sync::local_versions(bookmarks, &database::get_journal(json, path.clone()));
+    let folders: HashSet<Uuid> = database::get_containers(json, path.clone())
+        .iter()
+        .map(|c| c.id)
+        .collect();
+
+    let mut replicas = database::get_replicas(json, path.clone());
+    let changed = replica::observe(&mut replicas, &local, &folders, actor);
+    let changed: Vec<&Replica> = changed.iter().map(|key| &replicas[key]).collect();
+    if !changed.is_empty() {
+        database::set_replicas(&changed, json, path.clone());
+    }
+    (replicas, local, folders)
+}
+
+/// The replicas that changed in this store since `since`, or all of them
+pub fn delta_since(
+    since: Option<DateTime<Utc>>,
+    actor: Uuid,
+    json: bool,
+    path: Option<PathBuf>,
+) -> Delta {
+    let (replicas, _, _) = observe_store(actor, json, &path);
+    let mut bookmarks: Vec<Replica> = replicas
+        .into_values()
+        .filter(|r| since.is_none_or(|since| r.changed_at > since))
+        .collect();
+    bookmarks.sort_by(|a, b| a.link.cmp(&b.link));
+    Delta {
+        version: DELTA_VERSION,
+        actor,
+        exported_at: Utc::now(),
+        bookmarks,
+    }
+}
+
+/// Merge `delta` into the store, returning how many bookmarks were updated and deleted
+pub fn merge_delta(delta: Delta, actor: Uuid, json: bool, path: Option<PathBuf>) -> (usize, usize) {
+    if delta.version > DELTA_VERSION {
+        fail(
+            json,
+            ErrorCode::InvalidData,
+            &format!(
+                "delta version {} is newer than the supported version {}, please upgrade tinymark",
+                delta.version, DELTA_VERSION
+            ),
+        );
+    }
+
+    let (mut replicas, local, folders) = observe_store(actor, json, &path);
+    let mut changed = Vec::new();
+    for incoming in delta.bookmarks {
+        let key = incoming.link.to_string();
+        let merged = match replicas.get_mut(&key) {
+            Some(replica) => replica.merge(&incoming),
+            None => {
+                let mut replica = incoming;
+                replica.changed_at = Utc::now();
+                replicas.insert(key.clone(), replica);
+                true
+            }
+        };
+        if merged {
+            changed.push(key);
+        }
+    }
+
+    let mut updated = Vec::new();
+    let mut deleted = Vec::new();
+    for key in &changed {
+        let current = match local.get(key) {
+            Some(sync::Version::Present(bookmark, _)) => Some(bookmark),
+            _ => None,
+        };
+        match (replicas[key].bookmark(), current) {
+            (Some(mut bookmark), current) => {
+                // Folders aren't replicated, keep the local one if it's unknown here
+                if !bookmark.container.is_none_or(|id| folders.contains(&id)) {
+                    bookmark.container = current.and_then(|b| b.container);
+                }
+                if current != Some(&bookmark) {
+                    updated.push(bookmark);
+                }
+            }
+            (None, Some(current)) => deleted.push(current.clone()),
+            (None, None) => {}
+        }
+    }
+
+    let description = format!("apply delta from {}", delta.actor);
+    database::apply_sync(&updated, &deleted, &description, json, path.clone());
+    let changed: Vec<&Replica> = changed.iter().map(|key| &replicas[key]).collect();
+    if !changed.is_empty() {
+        database::set_replicas(&changed, json, path);
+    }
+    (updated.len(), deleted.len())
+}
+
+pub fn export_delta(
+    file_path: &Path,
+    since: &Option<DateTime<Utc>>,
+    actor: Uuid,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let delta = delta_since(*since, actor, json, path);
+    let mut writer = open_output(file_path, json);
+    if let Err(e) = serde_json::to_writer(&mut writer, &delta) {
+        fail(json, ErrorCode::Io, &format!("error writing delta: {}", e));
+    }
+    finish_export(file_path, &mut writer, json);
+}
+
+pub fn apply_delta(file_path: &Path, actor: Uuid, json: bool, path: Option<PathBuf>) {
+    let delta: Delta = match serde_json::from_reader(open_input(file_path, json)) {
+        Ok(delta) => delta,
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::InvalidData,
+                &format!("error reading delta: {}", e),
+            );
+        }
+    };
+    let from = delta.actor;
+    let (updated, deleted) = merge_delta(delta, actor, json, path);
+
+    if json {
+        output::print(
+            json!({ "updated": updated, "deleted": deleted }),
+            json!({ "reason": format!("applied delta from {}", from) }),
+        );
+    } else {
+        info!("applied delta from {}", from);
+        println!("{} updated, {} deleted", updated, deleted);
+    }
+}
+
+pub fn list_profiles(cfg: &Config, active: &Option<String>, json: bool) {
+    if cfg.profiles.is_empty() && !json {
+        info!("there are no profiles, create one with `tinymark profile create <name>`");
+    }
+    if json {
+        let profiles: Vec<serde_json::Value> = cfg
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                json!({
+                    "name": name,
+                    "storage_location": profile.storage_location,
+                    "default": cfg.default_profile.as_ref() == Some(name),
+                    "active": active.as_ref() == Some(name),
+                })
+            })
+            .collect();
+        return output::print(profiles, serde_json::Value::Null);
+    }
+    for (name, profile) in &cfg.profiles {
+        let is_active = active.as_ref() == Some(name);
+        let is_default = cfg.default_profile.as_ref() == Some(name);
+        println!(
+            "{} {}{}\n    {}",
+            if is_active { "*" } else { " " },
+            name,
+            if is_default { " (default)" } else { "" },
+            profile.storage_location.display()
+        );
+    }
+}
+
+pub fn create_profile(
+    name: &str,
+    path: &Option<PathBuf>,
+    default: bool,
+    cfg: &mut Config,
+    config_path: &Option<PathBuf>,
+    json: bool,
+) {
+    if !config::valid_profile_name(name) {
+        fail(
+            json,
+            ErrorCode::Usage,
+            &format!(
+                "invalid profile name {}, use letters, digits, - and _",
+                name
+            ),
+        );
+    }
+    if cfg.profiles.contains_key(name) {
+        fail(
+            json,
+            ErrorCode::Usage,
+            &format!("profile {} already exists", name),
+        );
+    }
+
+    let storage_location = match path {
+        Some(path) if path.is_relative() => match std::env::current_dir() {
+            Ok(dir) => dir.join(path),
+            Err(e) => {
+                fail(
+                    json,
+                    ErrorCode::Io,
+                    &format!("couldn't resolve {}: {}", path.display(), e),
+                );
+            }
+        },
+        Some(path) => path.clone(),
+        None => database::data_dir(json).join("profiles").join(name),
+    };
+    let profile = Profile {
+        storage_location,
+        actor: Some(Uuid::new_v4()),
+    };
+    cfg.profiles.insert(name.to_string(), profile.clone());
+    if default {
+        cfg.default_profile = Some(name.to_string());
+    }
+    store_config(config_path, cfg, json);
+
+    if json {
+        output::print(
+            json!({ "storage_location": profile.storage_location }),
+            json!({ "reason": format!("created profile {}", name) }),
+        );
+    } else {
+        info!(
+            "created profile <i>{}</i> in {}",
+            name,
+            profile.storage_location.display()
+        );
+    }
+}
+
+/// Copy the bookmarks of the store at `path`, or the ones matching `query`,
+/// into the store of profile `other`, along with the folders they're in
+pub fn copy_to_profile(
+    other: &str,
+    query: &Option<String>,
+    on_conflict: ConflictStrategy,
+    cfg: &Config,
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let target = match cfg.profiles.get(other) {
+        Some(profile) => profile.storage_location.clone(),
+        None => {
+            fail(
+                json,
+                ErrorCode::Config,
+                &format!("there's no profile {}", other),
+            );
+        }
+    };
+    if path.clone().unwrap_or_else(|| database::default_path(json)) == target {
+        fail(
+            json,
+            ErrorCode::Usage,
+            &format!("profile {} is the one in use", other),
+        );
+    }
+    if json && on_conflict == ConflictStrategy::Ask {
+        fail(
+            json,
+            ErrorCode::Usage,
+            "--on-conflict ask can't be used with --json",
+        );
+    }
+
+    let mut bookmarks = Vec::new();
+    database::for_each(json, path.clone(), Keyspace::Bookmarks, |bookmark| {
+        if query.as_ref().is_none_or(|q| bookmark.matches(q)) {
+            bookmarks.push(bookmark);
+        }
+    });
+    let mut containers = database::get_containers(json, path);
+    if query.is_some() {
+        // Only the folders holding the copied bookmarks, and their parents
+        let mut needed: HashSet<Uuid> = bookmarks.iter().filter_map(|b| b.container).collect();
+        let mut added = true;
+        while added {
+            let parents: Vec<Uuid> = containers
+                .iter()
+                .filter(|c| needed.contains(&c.id))
+                .filter_map(|c| c.container)
+                .collect();
+            added = false;
+            for parent in parents {
+                added |= needed.insert(parent);
+            }
+        }
+        containers.retain(|c| needed.contains(&c.id));
+    }
+
+    let document = ExportDocument {
+        version: EXPORT_VERSION,
+        exported_at: Utc::now(),
+        containers,
+        tags: Vec::new(),
+        bookmarks,
+    };
+    let target = Some(target);
+    let mut summary = ImportSummary::default();
+    let mut pending = PendingImport::default();
+    let bookmarks = import_document(document, &mut summary, &mut pending, json, &target);
+    import_bookmarks(
+        bookmarks,
+        on_conflict,
+        &Normalize::default(),
+        &mut summary,
+        &mut pending,
+        json,
+        &target,
+    );
+    pending.write(json, &target);
+    print_summary(
+        &format!("copied bookmarks to profile {}", other),
+        &summary,
+        json,
+    );
+}
+
+pub fn serve(bind: &str, allowed_origins: &[String], json: bool, path: Option<PathBuf>) {
+    let listener = match tiny_http::Server::http(bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::Unavailable,
+                &format!("can't listen on {}: {}", bind, e),
+            );
+        }
+    };
+    if json {
+        output::print_reason(&format!("listening on http://{}", bind));
+    } else {
+        info!("listening on <i>http://{}</i>", bind);
+    }
+    server::run(listener, allowed_origins, json, path);
+}
+
+pub fn completions(shell: CompletionShell) {
+    print!("{}", completions::script(shell));
+}
+
+/// Print the man page of `command`, or write every page to `out_dir`
+pub fn man(command: &[String], out_dir: &Option<PathBuf>, json: bool) {
+    let dir = match out_dir {
+        Some(dir) => dir,
+        None => match manual::page(command) {
+            Ok(page) => {
+                io::stdout().write_all(&page).unwrap();
+                return;
+            }
+            Err(e) => {
+                fail(json, ErrorCode::Usage, &e);
+            }
+        },
+    };
+
+    let written = fs::create_dir_all(dir).and_then(|_| {
+        let pages = manual::pages()?;
+        for (name, page) in &pages {
+            fs::write(dir.join(name), page)?;
+        }
+        Ok(pages.len())
+    });
+    match written {
+        Ok(count) => {
+            if json {
+                output::print_reason(&format!("wrote {} man pages to {}", count, dir.display()));
+            } else {
+                info!("wrote {} man pages to <i>{}</i>", count, dir.display());
+            }
+        }
+        Err(e) => {
+            fail(
+                json,
+                ErrorCode::Io,
+                &format!("error writing man pages: {}", e),
+            );
+        }
+    }
+}
+
+/// Print the stored tags or links for a completion script
+pub fn complete(kind: CompletionKind, path: Option<PathBuf>) {
+    match kind {
+        CompletionKind::Tags => {
+            for tag in tag_counts(false, path) {
+                println!("{}", tag.name);
+            }
+        }
+        CompletionKind::Urls => database::for_each(false, path, Keyspace::Bookmarks, |b| {
+            println!("{}", b.link);
+        }),
+    }
+}
+
+pub fn schema() {
+    print!("{}", output::SCHEMA);
+}
+
+/// `path` with `extension` added, so `database` becomes `database.bak`
+fn with_added_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Copy the store to `to`, into `out` or in its place, keeping the old one as a backup
+pub fn convert_store(to: Backend, out: &Option<PathBuf>, json: bool, path: Option<PathBuf>) {
+    let source = path.unwrap_or_else(|| database::default_path(json));
+    if !source.exists() {
+        fail(
+            json,
+            ErrorCode::NoInput,
+            &format!("there's no store at {}", source.display()),
+        );
+    }
+    let from = database::backend_of(&source);
+    if from == to && out.is_none() {
+        let reason = format!("the store at {} is already {}", source.display(), to.name());
+        if json {
+            return output::print_reason(&reason);
+        }
+        return info!("{}", reason);
+    }
+
+    let target = out
+        .clone()
+        .unwrap_or_else(|| with_added_extension(&source, "converting"));
+    let backup = out.is_none().then(|| with_added_extension(&source, "bak"));
+    for path in Some(&target).into_iter().chain(&backup) {
+        if path.exists() {
+            fail(
+                json,
+                ErrorCode::CantCreate,
+                &format!(
+                    "{} already exists, move it out of the way first",
+                    path.display()
+                ),
+            );
+        }
+    }
+
+    let copied = database::convert(json, &source, &target, to);
+    if let Some(backup) = &backup {
+        database::close(&source);
+        database::close(&target);
+        if let Err(e) = fs::rename(&source, backup).and_then(|_| fs::rename(&target, &source)) {
+            fail(
+                json,
+                ErrorCode::Io,
+                &format!("error moving the new store to {}: {}", source.display(), e),
+            );
+        }
+    }
+
+    let converted = out.clone().unwrap_or_else(|| source.clone());
+    let reason = format!(
+        "converted {} entries from {} to {}",
+        copied,
+        from.name(),
+        to.name()
+    );
+    if json {
+        return output::print(
+            json!({
+                "from": from,
+                "to": to,
+                "entries": copied,
+                "path": converted,
+                "backup": backup,
+            }),
+            json!({ "reason": reason }),
+        );
+    }
+    info!("{}, the store is at <i>{}</i>", reason, converted.display());
+    if let Some(backup) = backup {
+        info!("the old store is kept at <i>{}</i>", backup.display());
+    }
+}
+
+/// Answer a browser extension, which owns stdout, so errors go to stderr
+pub fn native_host(path: Option<PathBuf>) {
+    let (stdin, stdout) = (io::stdin(), io::stdout());
+    if let Err(e) = native::run(&mut stdin.lock(), &mut stdout.lock(), &path) {
+        fail(
+            false,
+            ErrorCode::Io,
+            &format!("tinymark native-host: {}", e),
+        );
+    }
+}
+
+pub fn delete_folder(id: &Uuid, json: bool, path: Option<PathBuf>) {
+    let removed = database::remove_container(id, json, path);
+    let contents = match removed.len() - 1 {
+        0 => String::new(),
+        1 => " and the entry in it".to_string(),
+        count => format!(" and the {} entries in it", count),
+    };
+    if json {
+        output::print(
+            &removed,
+            json!({ "reason": format!("moved folder {}{} to the trash", id, contents) }),
+        );
+    } else {
+        info!(
+            "moved folder <i>{}</i>{} to the trash",
+            removed[0].label(),
+            contents
+        );
+    }
+}
+
+pub fn list_trash(json: bool, path: Option<PathBuf>) {
+    let trash = database::get_trash(json, path);
+    if json {
+        return output::print(trash, serde_json::Value::Null);
+    }
+    for i in trash {
+        println!("{}", i);
+    }
+}
+
+pub fn restore_trash(id: &str, json: bool, path: Option<PathBuf>) {
+    let entry = database::restore_trash(id, json, path);
+    if json {
+        output::print(
+            &entry.item,
+            json!({ "reason": format!("restored {}", entry.item.key()) }),
+        );
+    } else {
+        info!("restored <i>{}", entry.item.key());
+    }
+}
+
+pub fn empty_trash(older_than: &Option<Duration>, json: bool, path: Option<PathBuf>) {
+    let removed = database::empty_trash(*older_than, json, path);
+    if json {
+        output::print(
+            &removed,
+            json!({ "reason": format!("permanently deleted {} entries", removed.len()) }),
+        );
+    } else {
+        info!(
+            "permanently deleted {} entries from the trash",
+            removed.len()
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Completion scripts for `tinymark completions`.
+//!
+//! The scripts clap generates only know about the command line itself, so
+//! each one gets a hook that asks `tinymark __complete` for the stored links
+//! when completing `delete` and `edit`, and the stored tags for `feed --tag`.
+
+use crate::structures::{Cli, CompletionShell};
+use clap::{Command, CommandFactory};
+use clap_complete::{generate, Shell};
+
+const BASH_HOOK: &str = r#"
+_tinymark_stored() {
+    local command="" word
+    for word in "${COMP_WORDS[@]:1:COMP_CWORD-1}"; do
+        if [[ $word != -* ]]; then
+            command="$word"
+            break
+        fi
+    done
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    # Links contain colons, which bash splits words on
+    local cur="${COMP_LINE:0:COMP_POINT}"
+    cur="${cur##*[[:space:]]}"
+
+    if [[ $command == feed && $prev == --tag ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($(compgen -W "$(tinymark __complete tags 2>/dev/null)" -- "$cur"))
+    elif [[ ($command == delete || $command == edit) && $cur != -* && $prev != -q && $prev != --query ]]; then
+        local IFS=$'\n'
+        COMPREPLY=($(compgen -W "$(tinymark __complete urls 2>/dev/null)" -- "$cur"))
+        if [[ $COMP_WORDBREAKS == *:* && $cur == *:* ]]; then
+            local prefix="${cur%"${cur##*:}"}"
+            COMPREPLY=("${COMPREPLY[@]#"$prefix"}")
+        fi
+    else
+        _tinymark "$@"
+    fi
+}
+
+complete -F _tinymark_stored -o bashdefault -o default tinymark
+"#;
+
+const ZSH_HOOK: &str = r#"
+_tinymark() {
+    local command=${${words[2,CURRENT-1]:#-*}[1]}
+    if [[ $command == feed && $words[CURRENT-1] == --tag ]]; then
+        local -a tags
+        tags=(${(f)"$(tinymark __complete tags 2>/dev/null)"})
+        compadd -a tags
+    elif [[ ($command == delete || $command == edit) && $PREFIX != -* && $words[CURRENT-1] != (-q|--query) ]]; then
+        local -a urls
+        urls=(${(f)"$(tinymark __complete urls 2>/dev/null)"})
+        compadd -a urls
+    else
+        _tinymark_static "$@"
+    fi
+}
+"#;
+
+const FISH_HOOK: &str = r#"
+complete -c tinymark -n "__fish_seen_subcommand_from delete edit" -f -a "(tinymark __complete urls 2>/dev/null)"
+complete -c tinymark -n "__fish_seen_subcommand_from feed" -l tag -x -a "(tinymark __complete tags 2>/dev/null)"
+"#;
+
+/// The command line without hidden subcommands. Besides there being no point
+/// in completing them, the bash generator splits command paths on `__`.
+fn visible_command() -> Command<'static> {
+    let cli = Cli::command();
+    Command::new("tinymark")
+        .args(
+            cli.get_arguments()
+                .filter(|a| a.get_id() != "help")
+                .cloned(),
+        )
+        .subcommands(cli.get_subcommands().filter(|c| !c.is_hide_set()).cloned())
+}
+
+pub fn script(shell: CompletionShell) -> String {
+    let generator = match shell {
+        CompletionShell::Bash => Shell::Bash,
+        CompletionShell::Zsh => Shell::Zsh,
+        CompletionShell::Fish => Shell::Fish,
+    };
+    let mut generated = Vec::new();
+    generate(
+        generator,
+        &mut visible_command(),
+        "tinymark",
+        &mut generated,
+    );
+    let generated = String::from_utf8_lossy(&generated);
+
+    match shell {
+        CompletionShell::Bash => {
+            // Keep clap's `complete` line out, ours replaces it
+            let generated = generated
+                .lines()
+                .filter(|l| !l.starts_with("complete "))
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("{}\n{}", generated, BASH_HOOK)
+        }
+        CompletionShell::Zsh => {
+            // The script is also the body of the autoloaded `_tinymark`, which
+            // ends by calling it, so the hook takes over its name
+            let generated = generated.replacen("\n_tinymark() {", "\n_tinymark_static() {", 1);
+            match generated.rsplit_once("\n_tinymark \"$@\"") {
+                Some((functions, rest)) => {
+                    format!("{}\n{}\n_tinymark \"$@\"{}", functions, ZSH_HOOK, rest)
+                }
+                None => format!("{}\n{}", generated, ZSH_HOOK),
+            }
+        }
+        CompletionShell::Fish => format!("{}{}", generated, FISH_HOOK),
+    }
+}
@@ -0,0 +1,171 @@
+//! Reading, checking and changing `tinymark.toml` for `tinymark config`.
+//!
+//! Settings are addressed by their TOML path, like `sort`,
+//! `normalize.remove_tracking` or `profiles.work.storage_location`. Every
+//! change goes through [`parse`], so a configuration that's written has
+//! always been checked first.
+
+use crate::structures::Config;
+use directories::ProjectDirs;
+use std::path::PathBuf;
+use toml::value::{Table, Value};
+
+/// The top level settings, which the tables below extend
+const KEYS: &[&str] = &[
+    "tui",
+    "json",
+    "storage_location",
+    "actor",
+    "default_profile",
+    "default_format",
+    "sort",
+    "backend",
+    "normalize",
+    "profiles",
+];
+
+const NORMALIZE_KEYS: &[&str] = &[
+    "remove_tracking",
+    "remove_fragment",
+    "remove_trailing_slash",
+];
+
+const PROFILE_KEYS: &[&str] = &["storage_location", "actor"];
+
+/// Where the configuration is read from when there's no `--config`
+pub fn default_path() -> Option<PathBuf> {
+    ProjectDirs::from("rs", "", "tinymark").map(|dirs| dirs.config_dir().join("tinymark.toml"))
+}
+
+/// Whether `name` can be used for a profile
+pub fn valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Check what TOML can't express, like `default_profile` naming a profile
+pub fn validate(cfg: &Config) -> Result<(), String> {
+    if let Some(name) = &cfg.default_profile {
+        if !cfg.profiles.contains_key(name) {
+            return Err(format!(
+                "default_profile is {}, but there's no such profile",
+                name
+            ));
+        }
+    }
+    if let Some(name) = cfg.profiles.keys().find(|n| !valid_profile_name(n)) {
+        return Err(format!(
+            "invalid profile name {}, use letters, digits, - and _",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Read a configuration file's contents, checking every setting
+pub fn parse(text: &str) -> Result<Config, String> {
+    let cfg: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+    validate(&cfg)?;
+    Ok(cfg)
+}
+
+pub fn to_string(cfg: &Config) -> Result<String, String> {
+    toml::to_string_pretty(cfg).map_err(|e| e.to_string())
+}
+
+/// Split a key into its parts, refusing settings that don't exist
+fn check_key(key: &str) -> Result<Vec<&str>, String> {
+    let parts: Vec<&str> = key.split('.').collect();
+    let known = match parts.as_slice() {
+        [key] => KEYS.contains(key),
+        ["normalize", key] => NORMALIZE_KEYS.contains(key),
+        ["profiles", name] => valid_profile_name(name),
+        ["profiles", name, key] => valid_profile_name(name) && PROFILE_KEYS.contains(key),
+        _ => false,
+    };
+    if known {
+        Ok(parts)
+    } else {
+        Err(format!(
+            "unknown setting {}, the settings are {}, normalize.{} and profiles.<name>.{}",
+            key,
+            KEYS.join(", "),
+            NORMALIZE_KEYS.join("|"),
+            PROFILE_KEYS.join("|"),
+        ))
+    }
+}
+
+fn to_table(cfg: &Config) -> Result<Table, String> {
+    match Value::try_from(cfg).map_err(|e| e.to_string())? {
+        Value::Table(table) => Ok(table),
+        _ => unreachable!("the configuration is a struct"),
+    }
+}
+
+fn from_table(table: Table) -> Result<Config, String> {
+    let cfg: Config = Value::Table(table).try_into().map_err(|e| e.to_string())?;
+    validate(&cfg)?;
+    Ok(cfg)
+}
+
+/// The value of `key`, or `None` if it isn't set
+pub fn get(cfg: &Config, key: &str) -> Result<Option<Value>, String> {
+    let parts = check_key(key)?;
+    let mut value = Value::Table(to_table(cfg)?);
+    for part in parts {
+        match value.get(part) {
+            Some(inner) => value = inner.clone(),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value))
+}
+
+/// Walk to the table holding the last part of the key, creating the tables
+/// of a new profile on the way
+fn parent_table<'a>(mut table: &'a mut Table, parts: &[&str]) -> &'a mut Table {
+    for part in &parts[..parts.len() - 1] {
+        table = match table
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(inner) => inner,
+            _ => unreachable!("only tables have nested settings"),
+        };
+    }
+    table
+}
+
+/// The configuration with `key` set to `value`. The value is read as TOML, like
+/// `true` or `[1, 2]`, and as text if that doesn't fit the setting.
+pub fn set(cfg: &Config, key: &str, value: &str) -> Result<Config, String> {
+    let parts = check_key(key)?;
+    let last = parts[parts.len() - 1];
+    let text = Value::String(value.to_string());
+    let parsed = toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut t| t.remove("value"));
+
+    let mut error = String::new();
+    for candidate in parsed.into_iter().chain(Some(text)) {
+        let mut table = to_table(cfg)?;
+        parent_table(&mut table, &parts).insert(last.to_string(), candidate);
+        match from_table(table) {
+            Ok(cfg) => return Ok(cfg),
+            Err(e) if error.is_empty() => error = e,
+            Err(_) => {}
+        }
+    }
+    Err(format!("invalid value {} for {}: {}", value, key, error))
+}
+
+/// The configuration with `key` back to its default
+pub fn unset(cfg: &Config, key: &str) -> Result<Config, String> {
+    let parts = check_key(key)?;
+    let mut table = to_table(cfg)?;
+    parent_table(&mut table, &parts).remove(parts[parts.len() - 1]);
+    from_table(table).map_err(|e| format!("can't unset {}: {}", key, e))
+}
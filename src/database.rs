@@ -1,292 +1,1127 @@
 use crate::commands::env_err;
-use crate::structures::{Bookmark, Keyspace};
+use crate::errors::{self, fail, ErrorCode, Failure};
+use crate::replica::Replica;
+use crate::storage::{self, Storage, StorageError, Write};
+use crate::structures::{
+    Backend, Bookmark, Change, Container, Keyspace, Operation, OperationKind, SyncState,
+    TrashEntry, Trashed,
+};
 
+use chrono::{DateTime, Duration, Utc};
 use paris::*;
-use serde_json::json;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use url::Url;
+use uuid::Uuid;
 
-fn open_database(json: bool, path: Option<PathBuf>, keyspace: Keyspace) -> Option<sled::Tree> {
-    let db: sled::Db;
-    let database_path = match path {
-        Some(path) => path,
-        None => {
-            let key = "HOME";
-            match env::var(key) {
-                Ok(val) => {
-                    let mut tmp_path = PathBuf::new();
-                    tmp_path.push(val);
-                    tmp_path.push(".local/share/tinymark");
-                    tmp_path.push("database");
-                    tmp_path
-                }
-                Err(e) => {
-                    env_err(json, e);
-                    std::process::exit(exitcode::DATAERR);
-                }
-            }
-        }
+/// The trees whose writes are recorded in the journal
+const JOURNALED: [Keyspace; 3] = [Keyspace::Bookmarks, Keyspace::Containers, Keyspace::Trash];
+
+/// Stores opened by this process, by path
+static STORES: Mutex<BTreeMap<PathBuf, Arc<dyn Storage>>> = Mutex::new(BTreeMap::new());
+
+/// How long to wait for another process to close a store, in milliseconds
+static WAIT: AtomicU64 = AtomicU64::new(0);
+
+/// What stores that don't exist yet are created as
+static BACKEND: Mutex<Backend> = Mutex::new(Backend::Sled);
+
+/// Wait up to `wait` for other processes to close a store before giving up
+pub fn set_wait(wait: std::time::Duration) {
+    WAIT.store(wait.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Create new stores in `backend`
+pub fn set_backend(backend: Backend) {
+    *BACKEND.lock().unwrap() = backend;
+}
+
+/// The directory stores are kept in by default: `$XDG_DATA_HOME/tinymark`,
+/// or `~/.local/share/tinymark` if it isn't set
+pub fn data_dir(json: bool) -> PathBuf {
+    data_dir_in(env::var_os("XDG_DATA_HOME"), env::var("HOME"), json)
+}
+
+/// [`data_dir`] for the given `$XDG_DATA_HOME` and `$HOME`
+pub fn data_dir_in(
+    data_home: Option<std::ffi::OsString>,
+    home: Result<String, env::VarError>,
+    json: bool,
+) -> PathBuf {
+    // The spec says relative paths are invalid and should be ignored
+    if let Some(data) = data_home.map(PathBuf::from).filter(|p| p.is_absolute()) {
+        return data.join("tinymark");
+    }
+    match home {
+        Ok(home) => PathBuf::from(home).join(".local/share/tinymark"),
+        Err(e) => env_err(json, e),
+    }
+}
+
+/// Where the store is kept when no path is given
+pub fn default_path(json: bool) -> PathBuf {
+    data_dir(json).join("database")
+}
+
+/// The backend of the store at `path`: sled keeps a directory and SQLite a
+/// file, and a new store uses the configured backend
+pub fn backend_of(path: &std::path::Path) -> Backend {
+    if path.is_dir() {
+        Backend::Sled
+    } else if path.is_file() {
+        Backend::Sqlite
+    } else {
+        *BACKEND.lock().unwrap()
+    }
+}
+
+/// The failure of a read or write in the store
+fn storage_failure(what: &str, error: StorageError) -> Failure {
+    let code = match error {
+        StorageError::Io(_) => ErrorCode::Io,
+        StorageError::Abort(_) => ErrorCode::InvalidData,
+        StorageError::Locked | StorageError::Conflict => ErrorCode::Busy,
     };
+    Failure::new(code, format!("{}: {}", what, error))
+}
 
-    match sled::open(database_path) {
-        Ok(database) => db = database,
-        Err(error) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": error.to_string(),
-                    })
-                );
-            } else {
-                error!("error in opening database: {}", error);
-            }
-            return None;
+/// Exit with the failure of a read or write in the store
+fn storage_fail(json: bool, what: &str, error: StorageError) -> ! {
+    storage_failure(what, error).exit(json)
+}
+
+fn open_store(json: bool, path: Option<PathBuf>) -> Arc<dyn Storage> {
+    try_open_store(json, path).unwrap_or_else(|failure| failure.exit(json))
+}
+
+fn try_open_store(json: bool, path: Option<PathBuf>) -> Result<Arc<dyn Storage>, Failure> {
+    let database_path = path.unwrap_or_else(|| default_path(json));
+    let backend = backend_of(&database_path);
+    open_backend(&database_path, backend)
+}
+
+/// Open the store at `path` in `backend`, creating it if it doesn't exist
+fn open_backend(path: &std::path::Path, backend: Backend) -> Result<Arc<dyn Storage>, Failure> {
+    // sled keeps the lock until its background thread notices the last handle
+    // is gone, so reopening the same store in one process can fail
+    let mut stores = STORES.lock().unwrap();
+    if let Some(database) = stores.get(path) {
+        return Ok(database.clone());
+    }
+
+    let wait = std::time::Duration::from_millis(WAIT.load(Ordering::Relaxed));
+    match storage::open(backend, path, wait) {
+        Ok(database) => {
+            stores.insert(path.to_path_buf(), database.clone());
+            Ok(database)
         }
-    };
+        Err(StorageError::Locked) => Err(Failure::new(
+            ErrorCode::Busy,
+            format!(
+                "the store at {} is in use by another tinymark, like `tinymark serve`; \
+                 use its API, try again with --read-only, or wait longer with --wait",
+                path.display()
+            ),
+        )),
+        Err(error) => Err(Failure::new(
+            ErrorCode::NoInput,
+            format!("error opening the store at {}: {}", path.display(), error),
+        )),
+    }
+}
 
-    let keyspace_str = match keyspace {
-        Keyspace::Bookmarks => "bookmarks",
-        Keyspace::Containers => "containers",
-    };
+/// Forget the store at `path`, so it can be moved or opened again
+pub fn close(path: &std::path::Path) {
+    STORES.lock().unwrap().remove(path);
+}
 
-    let database: Option<sled::Tree> = match db.open_tree(keyspace_str) {
-        Ok(nya) => Some(nya),
-        Err(e) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": e.to_string(),
-                    })
-                );
-            } else {
-                error!("error in opening Tree: {}", e);
+/// Copy every tree of the store at `from` into a new store at `to` kept in
+/// `backend`, returning how many entries were copied
+pub fn convert(
+    json: bool,
+    from: &std::path::Path,
+    to: &std::path::Path,
+    backend: Backend,
+) -> usize {
+    let source = open_store(json, Some(from.to_path_buf()));
+    let target = open_backend(to, backend).unwrap_or_else(|failure| failure.exit(json));
+
+    let mut copied = 0;
+    for keyspace in Keyspace::ALL {
+        let mut writes: Vec<Write> = Vec::new();
+        for pair in source.scan(keyspace, &[]) {
+            match pair {
+                Ok((key, value)) => writes.push((keyspace, key, Some(value))),
+                Err(error) => storage_fail(
+                    json,
+                    &format!("failed reading the {} tree", keyspace.name()),
+                    error,
+                ),
             }
-            None
         }
+        copied += writes.len();
+        if let Err(error) = target.batch(writes) {
+            storage_fail(json, &format!("failed writing {}", to.display()), error);
+        }
+    }
+    copied
+}
+
+/// Copy the store somewhere new, where it can be opened while another process
+/// holds the original. Changes to the copy are thrown away, and it's removed
+/// when tinymark exits.
+pub fn snapshot(json: bool, path: Option<PathBuf>) -> PathBuf {
+    let database_path = path.unwrap_or_else(|| default_path(json));
+    if !database_path.exists() {
+        fail(
+            json,
+            ErrorCode::NoInput,
+            &format!("there's no store at {}", database_path.display()),
+        );
+    }
+    let copy = env::temp_dir().join(format!("tinymark-snapshot-{}", Uuid::new_v4()));
+    errors::remove_on_exit(copy.clone());
+    let copied = if database_path.is_dir() {
+        copy_dir(&database_path, &copy)
+    } else {
+        std::fs::copy(&database_path, &copy).map(|_| ())
     };
-    return database;
+    if let Err(e) = copied {
+        fail(
+            json,
+            ErrorCode::Io,
+            &format!(
+                "error copying the store at {}: {}",
+                database_path.display(),
+                e
+            ),
+        );
+    }
+    copy
+}
+
+fn copy_dir(from: &std::path::Path, to: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
 }
 
-pub fn insert_multiple(
-    entries: &Vec<Bookmark>,
+/// Write the folders and bookmarks of an import as a single change, so it's undone at once
+pub fn insert_imported(
     json: bool,
     path: Option<PathBuf>,
-    keyspace: Keyspace,
+    containers: &[Container],
+    bookmarks: &[Bookmark],
 ) {
-    let db = match open_database(json, path, keyspace) {
-        Some(database) => database,
-        None => std::process::exit(exitcode::NOINPUT),
+    let db = open_store(json, path);
+
+    let mut writes = Vec::new();
+    for c in containers {
+        writes.push((
+            Keyspace::Containers,
+            c.id.to_string(),
+            Some(serialize(c, json)),
+        ));
+    }
+    for b in bookmarks {
+        writes.push((
+            Keyspace::Bookmarks,
+            b.link.to_string(),
+            Some(serialize(b, json)),
+        ));
+    }
+
+    let description = match containers.len() {
+        0 => format!("import {} bookmarks", bookmarks.len()),
+        folders => format!(
+            "import {} bookmarks and {} folders",
+            bookmarks.len(),
+            folders
+        ),
     };
+    commit(&db, json, &description, OperationKind::Change, writes);
+}
+
+pub fn insert_entry(json: bool, path: Option<PathBuf>, keyspace: Keyspace, entry: &Bookmark) {
+    write_entry(json, path, keyspace, entry);
+    // The command prints the result in JSON mode
+    if !json {
+        info!("succesfully inserted entry <i>{}", entry.link);
+    }
+}
+
+/// Store `entry` without reporting it, for callers that own stdout
+pub fn write_entry(json: bool, path: Option<PathBuf>, keyspace: Keyspace, entry: &Bookmark) {
+    try_write_entry(json, path, keyspace, entry).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_write_entry(
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+    entry: &Bookmark,
+) -> Result<(), Failure> {
+    let db = try_open_store(json, path)?;
+    let bytes = try_serialize(entry)?;
 
-    let mut batch = sled::Batch::default();
+    let name = entry.link.to_string();
+    let description = format!("add {}", name);
+    try_commit(
+        &db,
+        &description,
+        OperationKind::Change,
+        vec![(keyspace, name, Some(bytes))],
+    )
+    .map_err(|error| storage_failure(&format!("failed to {}", description), error))?;
+    Ok(())
+}
+
+pub fn get_entry(
+    link: &Url,
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+) -> Option<Bookmark> {
+    try_get_entry(link, json, path, keyspace).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_get_entry(
+    link: &Url,
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+) -> Result<Option<Bookmark>, Failure> {
+    let db = try_open_store(json, path)?;
+
+    match db.get(keyspace, link.as_str().as_bytes()) {
+        Ok(Some(bytes)) => try_deserialize_entry(&bytes).map(Some),
+        Ok(None) => Ok(None),
+        Err(error) => Err(storage_failure(
+            &format!("failed to get entry {}", link),
+            error,
+        )),
+    }
+}
+
+/// Look up several entries at once, in the same order as `links`
+pub fn get_multiple(
+    links: &[Url],
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+) -> Vec<Option<Bookmark>> {
+    let db = open_store(json, path);
 
-    for i in entries {
-        let bytes;
-        match bincode::serialize(&i) {
-            Ok(result) => bytes = result,
+    let mut entries = Vec::new();
+    for link in links {
+        match db.get(keyspace, link.as_str().as_bytes()) {
+            Ok(entry) => entries.push(entry.map(|bytes| deserialize_entry(&bytes, json))),
+            Err(error) => storage_fail(json, &format!("failed to get entry {}", link), error),
+        }
+    }
+    entries
+}
+
+/// Move the bookmarks at `links` to the trash as a single change, returning the ones that existed
+pub fn remove_entries(links: &[Url], json: bool, path: Option<PathBuf>) -> Vec<Bookmark> {
+    let keys: Vec<&str> = links.iter().map(Url::as_str).collect();
+    let items = try_trash_items(&keys, json, path, Keyspace::Bookmarks)
+        .unwrap_or_else(|failure| failure.exit(json));
+    items
+        .into_iter()
+        .filter_map(|item| match item {
+            Trashed::Bookmark(bookmark) => Some(bookmark),
+            Trashed::Container(_) => None,
+        })
+        .collect()
+}
+
+pub fn insert_container(json: bool, path: Option<PathBuf>, container: &Container) {
+    try_insert_container(json, path, container).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_insert_container(
+    json: bool,
+    path: Option<PathBuf>,
+    container: &Container,
+) -> Result<(), Failure> {
+    let db = try_open_store(json, path)?;
+    let bytes = try_serialize(container)?;
+
+    let description = format!("new folder {}", container.label);
+    try_commit(
+        &db,
+        &description,
+        OperationKind::Change,
+        vec![(Keyspace::Containers, container.id.to_string(), Some(bytes))],
+    )
+    .map_err(|error| storage_failure(&format!("failed to {}", description), error))?;
+    Ok(())
+}
+
+pub fn get_containers(json: bool, path: Option<PathBuf>) -> Vec<Container> {
+    try_get_containers(json, path).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_get_containers(json: bool, path: Option<PathBuf>) -> Result<Vec<Container>, Failure> {
+    let db = try_open_store(json, path)?;
+
+    let mut containers = Vec::new();
+    for pair in db.scan(Keyspace::Containers, &[]) {
+        let bytes = match pair {
+            Ok((_, bytes)) => bytes,
+            Err(error) => return Err(storage_failure("failed reading folders", error)),
+        };
+        match bincode::deserialize(&bytes) {
+            Ok(container) => containers.push(container),
             Err(error) => {
-                if json {
-                    println!(
-                        "{}",
-                        json!({
-                            "status": "fail",
-                            "reason": error.to_string(),
-                        })
-                    );
-                } else {
-                    error!("failed serializing entry: {}", error);
-                }
-                std::process::exit(exitcode::DATAERR);
+                return Err(Failure::new(
+                    ErrorCode::InvalidData,
+                    format!("failed deserializing folder: {}", error),
+                ));
             }
         }
+    }
+    Ok(containers)
+}
 
-        batch.insert(i.link.as_str(), bytes);
+/// Move the folder `id` and what's in it to the trash, returning them with the folder first
+pub fn remove_container(id: &Uuid, json: bool, path: Option<PathBuf>) -> Vec<Trashed> {
+    let items = try_trash_folder(id, json, path).unwrap_or_else(|failure| failure.exit(json));
+    if items.is_empty() {
+        fail(
+            json,
+            ErrorCode::NotFound,
+            &format!("folder {} not found", id),
+        );
     }
+    items
+}
 
-    match db.apply_batch(batch) {
-        Ok(_) => {
-            if !json {
-                info!("succesfully applied batch insert");
+/// Move the item stored under `key` into the trash, returning it
+/// or `None` if it doesn't exist
+pub fn try_trash_item(
+    key: &str,
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+) -> Result<Option<Trashed>, Failure> {
+    Ok(try_trash_items(&[key], json, path, keyspace)?.pop())
+}
+
+/// Move the items stored under `keys` into the trash as a single change,
+/// returning the ones that exist
+pub fn try_trash_items(
+    keys: &[&str],
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+) -> Result<Vec<Trashed>, Failure> {
+    let keys: Vec<(Keyspace, &str)> = keys.iter().map(|key| (keyspace, *key)).collect();
+    trash(&keys, json, path)
+}
+
+/// Move the folder `id` into the trash as a single change, with the folders and
+/// bookmarks in it so none of them point at a folder that's gone. Returns what
+/// was trashed, the folder first, or nothing if it doesn't exist.
+pub fn try_trash_folder(
+    id: &Uuid,
+    json: bool,
+    path: Option<PathBuf>,
+) -> Result<Vec<Trashed>, Failure> {
+    let containers = try_get_containers(json, path.clone())?;
+    if !containers.iter().any(|c| c.id == *id) {
+        return Ok(Vec::new());
+    }
+    // The visited check keeps folders in a cycle from being walked forever
+    let mut folders = vec![*id];
+    let mut next = 0;
+    while next < folders.len() {
+        let parent = folders[next];
+        for c in &containers {
+            if c.container == Some(parent) && !folders.contains(&c.id) {
+                folders.push(c.id);
             }
         }
-        Err(e) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": e.to_string(),
-                    })
+        next += 1;
+    }
+    let mut links = Vec::new();
+    try_for_each(json, path.clone(), Keyspace::Bookmarks, |bookmark| {
+        if bookmark.container.is_some_and(|c| folders.contains(&c)) {
+            links.push(bookmark.link.to_string());
+        }
+    })?;
+
+    let ids: Vec<String> = folders.iter().map(Uuid::to_string).collect();
+    let keys: Vec<(Keyspace, &str)> = ids
+        .iter()
+        .map(|id| (Keyspace::Containers, id.as_str()))
+        .chain(
+            links
+                .iter()
+                .map(|link| (Keyspace::Bookmarks, link.as_str())),
+        )
+        .collect();
+    trash(&keys, json, path)
+}
+
+/// Trash the items under `keys` at the same time, so they can be restored together
+fn trash(
+    keys: &[(Keyspace, &str)],
+    json: bool,
+    path: Option<PathBuf>,
+) -> Result<Vec<Trashed>, Failure> {
+    let db = try_open_store(json, path)?;
+
+    let deleted_at = Utc::now();
+    let mut items = Vec::new();
+    let mut writes = Vec::new();
+    for &(keyspace, key) in keys {
+        let bytes = match db.get(keyspace, key.as_bytes()) {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => continue,
+            Err(error) => return Err(storage_failure(&format!("failed to get {}", key), error)),
+        };
+        let item = match keyspace {
+            Keyspace::Containers => bincode::deserialize(&bytes).map(Trashed::Container),
+            _ => bincode::deserialize(&bytes).map(Trashed::Bookmark),
+        };
+        let entry = TrashEntry {
+            deleted_at,
+            item: item.map_err(|error| {
+                Failure::new(
+                    ErrorCode::InvalidData,
+                    format!("failed deserializing {}: {}", key, error),
+                )
+            })?,
+        };
+        writes.push((keyspace, key.to_string(), None));
+        writes.push((
+            Keyspace::Trash,
+            key.to_string(),
+            Some(try_serialize(&entry)?),
+        ));
+        items.push(entry.item);
+    }
+    if items.is_empty() {
+        return Ok(items);
+    }
+
+    let description = match items.as_slice() {
+        [item] => format!("delete {}", item.key()),
+        _ => format!("delete {} entries", items.len()),
+    };
+    try_commit(&db, &description, OperationKind::Change, writes)
+        .map_err(|error| storage_failure(&format!("failed to {}", description), error))?;
+    Ok(items)
+}
+
+pub fn get_trash(json: bool, path: Option<PathBuf>) -> Vec<TrashEntry> {
+    let db = open_store(json, path);
+
+    let mut entries = Vec::new();
+    for pair in db.scan(Keyspace::Trash, &[]) {
+        let (_, bytes) = match pair {
+            Ok(pair) => pair,
+            Err(error) => storage_fail(json, "failed reading the trash", error),
+        };
+        match bincode::deserialize::<TrashEntry>(&bytes) {
+            Ok(entry) => entries.push(entry),
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing trash entry: {}", error),
                 );
-            } else {
-                warn!("error in applying batch insert: {}", e);
             }
         }
     }
+    entries.sort_by_key(|e| e.deleted_at);
+    entries
 }
 
-pub fn insert_entry(json: bool, path: Option<PathBuf>, keyspace: Keyspace, _entry: &Bookmark) {
-    let entry = _entry;
-    let db = match open_database(json, path, keyspace) {
-        Some(database) => database,
-        None => std::process::exit(exitcode::NOINPUT),
+/// Move an item from the trash back to where it came from
+pub fn restore_trash(id: &str, json: bool, path: Option<PathBuf>) -> TrashEntry {
+    let db = open_store(json, path);
+
+    // Links are stored normalized, so `https://example.com` is found as `https://example.com/`
+    let key = match db.get(Keyspace::Trash, id.as_bytes()) {
+        Ok(None) => match Url::parse(id) {
+            Ok(url) => url.to_string(),
+            Err(_) => id.to_string(),
+        },
+        _ => id.to_string(),
     };
 
-    let bytes;
-    match bincode::serialize(&entry) {
-        Ok(result) => bytes = result,
-        Err(error) => {
-            if json {
-                println!("{}",
-                    json!({
-                        "status": "fail",
-                        "reason": error.to_string(),
-                    })
+    let entry: TrashEntry = match db.get(Keyspace::Trash, key.as_bytes()) {
+        Ok(Some(bytes)) => match bincode::deserialize(&bytes) {
+            Ok(entry) => entry,
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing {}: {}", key, error),
                 );
-            } else {
-                error!("failed serializing entry: {}", error);
             }
-            std::process::exit(exitcode::DATAERR);
+        },
+        Ok(None) => {
+            fail(
+                json,
+                ErrorCode::NotFound,
+                &format!("{} is not in the trash", id),
+            );
         }
+        Err(error) => storage_fail(json, &format!("failed to restore {}", id), error),
+    };
+
+    if let Ok(Some(_)) = db.get(stored_in(&entry.item), key.as_bytes()) {
+        fail(
+            json,
+            ErrorCode::InvalidData,
+            &format!("failed to restore {}: it already exists", id),
+        );
     }
+    let mut restored = vec![entry.item.clone()];
+    if let Trashed::Container(folder) = &entry.item {
+        restored.extend(trashed_with(&db, folder, entry.deleted_at, json));
+    }
+
+    let mut writes = Vec::new();
+    for item in &restored {
+        let key = item.key();
+        // What was recreated since is left in the trash
+        if item.key() != entry.item.key() {
+            if let Ok(Some(_)) = db.get(stored_in(item), key.as_bytes()) {
+                continue;
+            }
+        }
+        let bytes = match item {
+            Trashed::Bookmark(b) => serialize(b, json),
+            Trashed::Container(c) => serialize(c, json),
+        };
+        writes.push((Keyspace::Trash, key.clone(), None));
+        writes.push((stored_in(item), key, Some(bytes)));
+    }
+
+    let description = format!("restore {}", key);
+    commit(&db, json, &description, OperationKind::Change, writes);
+    entry
+}
 
-    let name = &entry.link.to_string();
+fn stored_in(item: &Trashed) -> Keyspace {
+    match item {
+        Trashed::Bookmark(_) => Keyspace::Bookmarks,
+        Trashed::Container(_) => Keyspace::Containers,
+    }
+}
 
-    match db.insert(&name, bytes) {
-        Ok(_) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "success",
-                        "reason": "inserted entry",
-                    })
+/// What was trashed along with `folder`, which are the entries in it deleted at the same time
+fn trashed_with(
+    db: &Arc<dyn Storage>,
+    folder: &Container,
+    deleted_at: DateTime<Utc>,
+    json: bool,
+) -> Vec<Trashed> {
+    let mut together = Vec::new();
+    for pair in db.scan(Keyspace::Trash, &[]) {
+        let (_, bytes) = match pair {
+            Ok(pair) => pair,
+            Err(error) => storage_fail(json, "failed reading the trash", error),
+        };
+        match bincode::deserialize::<TrashEntry>(&bytes) {
+            Ok(other)
+                if other.deleted_at == deleted_at && other.item.key() != folder.id.to_string() =>
+            {
+                together.push(other.item)
+            }
+            Ok(_) => {}
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing trash entry: {}", error),
                 );
-            } else {
-                info!("succesfully inserted entry <i>{}", name);
             }
         }
-        Err(error) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": error.to_string(),
-                    })
+    }
+
+    let mut folders = vec![folder.id];
+    let mut inside = Vec::new();
+    let mut found = true;
+    while found {
+        found = false;
+        together.retain(|item| {
+            let parent = match item {
+                Trashed::Bookmark(b) => b.container,
+                Trashed::Container(c) => c.container,
+            };
+            if !parent.is_some_and(|p| folders.contains(&p)) {
+                return true;
+            }
+            if let Trashed::Container(c) = item {
+                folders.push(c.id);
+            }
+            inside.push(item.clone());
+            found = true;
+            false
+        });
+    }
+    inside
+}
+
+/// Permanently delete trash entries, optionally only those older than `older_than`
+pub fn empty_trash(
+    older_than: Option<Duration>,
+    json: bool,
+    path: Option<PathBuf>,
+) -> Vec<TrashEntry> {
+    let entries = get_trash(json, path.clone());
+    let db = open_store(json, path);
+
+    let cutoff = older_than.map(|age| Utc::now() - age);
+    let mut writes = Vec::new();
+    let mut removed = Vec::new();
+    for entry in entries {
+        if cutoff.is_none_or(|cutoff| entry.deleted_at < cutoff) {
+            writes.push((Keyspace::Trash, entry.item.key(), None));
+            removed.push(entry);
+        }
+    }
+
+    if !writes.is_empty() {
+        let description = format!("empty trash ({} entries)", removed.len());
+        commit(&db, json, &description, OperationKind::Change, writes);
+    }
+    removed
+}
+
+/// The key of the actor a store replicates as, next to the sync states, which
+/// can't clash with it since their keys have a newline
+const ACTOR_KEY: &[u8] = b"actor";
+
+/// The actor this store replicates as, saving `default` the first time
+pub fn store_actor(default: Uuid, json: bool, path: Option<PathBuf>) -> Uuid {
+    let db = open_store(json, path);
+
+    let mut actor = default;
+    let result = db.transaction(&mut |transaction| {
+        actor = match transaction.get(Keyspace::Sync, ACTOR_KEY)? {
+            Some(bytes) => {
+                Uuid::from_slice(&bytes).map_err(|e| StorageError::Abort(e.to_string()))?
+            }
+            None => {
+                transaction.put(Keyspace::Sync, ACTOR_KEY, default.as_bytes())?;
+                default
+            }
+        };
+        Ok(())
+    });
+    if let Err(error) = result {
+        storage_fail(json, "failed reading the actor", error);
+    }
+    actor
+}
+
+/// Sync states are kept per shared directory
+fn sync_key(dir: &str, link: &str) -> String {
+    format!("{}\n{}", dir, link)
+}
+
+/// When each bookmark was last reconciled with the shared directory `dir`, by link
+pub fn get_sync_states(dir: &str, json: bool, path: Option<PathBuf>) -> HashMap<String, SyncState> {
+    let db = open_store(json, path);
+
+    let prefix = sync_key(dir, "");
+    let mut states = HashMap::new();
+    for pair in db.scan(Keyspace::Sync, prefix.as_bytes()) {
+        let (key, bytes) = match pair {
+            Ok(pair) => pair,
+            Err(error) => storage_fail(json, "failed reading the sync state", error),
+        };
+        let link = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+        match bincode::deserialize(&bytes) {
+            Ok(state) => {
+                states.insert(link, state);
+            }
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing sync state: {}", error),
                 );
-            } else {
-                error!("failed to insert entry <i>{}</i>!\n {}", name, error);
             }
-            std::process::exit(exitcode::IOERR);
         }
     }
+    states
+}
 
-    db.flush().unwrap();
+/// Record what was reconciled with `dir`. This isn't journaled, so undoing a
+/// sync counts as a local change the next time.
+pub fn set_sync_states(
+    dir: &str,
+    states: &[(String, SyncState)],
+    json: bool,
+    path: Option<PathBuf>,
+) {
+    let db = open_store(json, path);
+
+    let writes = states
+        .iter()
+        .map(|(link, state)| {
+            (
+                Keyspace::Sync,
+                sync_key(dir, link).into_bytes(),
+                Some(serialize(state, json)),
+            )
+        })
+        .collect();
+    if let Err(error) = db.batch(writes) {
+        storage_fail(json, "failed writing the sync state", error);
+    }
 }
 
-pub fn remove_entry(link: &Url, json: bool, path: Option<PathBuf>, keyspace: Keyspace) {
-    let db = match open_database(json, path, keyspace) {
-        Some(database) => database,
-        None => std::process::exit(exitcode::NOINPUT),
-    };
+/// The replicated state of every bookmark this store has seen, by link
+pub fn get_replicas(json: bool, path: Option<PathBuf>) -> HashMap<String, Replica> {
+    let db = open_store(json, path);
 
-    match db.remove(link.to_string()) {
-        Ok(_) => info!("succesfully removed entry <i>{}", link),
-        Err(error) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": error.to_string(),
-                    })
+    let mut replicas = HashMap::new();
+    for pair in db.scan(Keyspace::Replica, &[]) {
+        let (key, bytes) = match pair {
+            Ok(pair) => pair,
+            Err(error) => storage_fail(json, "failed reading replicas", error),
+        };
+        match bincode::deserialize(&bytes) {
+            Ok(replica) => {
+                replicas.insert(String::from_utf8_lossy(&key).into_owned(), replica);
+            }
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing replica: {}", error),
                 );
-            } else {
-                error!("failed to remove entry <i>{}</i>!\n {}", link, error);
             }
-            std::process::exit(exitcode::IOERR);
         }
     }
+    replicas
+}
+
+/// Store replicated state. Like the sync state this isn't journaled, undoing
+/// what a delta changed is replicated as a new change instead.
+pub fn set_replicas(replicas: &[&Replica], json: bool, path: Option<PathBuf>) {
+    let db = open_store(json, path);
 
-    db.flush().unwrap();
+    let writes = replicas
+        .iter()
+        .map(|replica| {
+            (
+                Keyspace::Replica,
+                replica.link.as_str().as_bytes().to_vec(),
+                Some(serialize(replica, json)),
+            )
+        })
+        .collect();
+    if let Err(error) = db.batch(writes) {
+        storage_fail(json, "failed writing replicas", error);
+    }
 }
 
-pub fn get_all(json: bool, path: Option<PathBuf>, keyspace: Keyspace) -> Option<Vec<Bookmark>> {
-    let db = match open_database(json, path, keyspace) {
-        Some(database) => database,
-        None => return None,
-    };
+/// Write the bookmarks pulled from another store or a shared directory and move
+/// the ones deleted there to the trash, as one operation. Returns when it was recorded.
+pub fn apply_sync(
+    pulled: &[Bookmark],
+    deleted: &[Bookmark],
+    description: &str,
+    json: bool,
+    path: Option<PathBuf>,
+) -> DateTime<Utc> {
+    if pulled.is_empty() && deleted.is_empty() {
+        return Utc::now();
+    }
+    let db = open_store(json, path);
 
-    let first_key = match db.first() {
-        Ok(pair) => match pair {
-            Some(key) => key.0,
-            None => {
-                if json {
-                    println!(
-                        "{}",
-                        json!({
-                        "status": "error",
-                        "reason": "could not get first key",
-                        })
-                    );
-                } else {
-                    error!("error in getting first key");
-                }
-                std::process::exit(exitcode::IOERR);
+    let mut writes = Vec::new();
+    for bookmark in pulled {
+        writes.push((
+            Keyspace::Bookmarks,
+            bookmark.link.to_string(),
+            Some(serialize(bookmark, json)),
+        ));
+    }
+    for bookmark in deleted {
+        let entry = TrashEntry {
+            deleted_at: Utc::now(),
+            item: Trashed::Bookmark(bookmark.clone()),
+        };
+        let key = bookmark.link.to_string();
+        writes.push((Keyspace::Bookmarks, key.clone(), None));
+        writes.push((Keyspace::Trash, key, Some(serialize(&entry, json))));
+    }
+    commit(&db, json, description, OperationKind::Change, writes).at
+}
+
+fn serialize<T: Serialize>(value: &T, json: bool) -> Vec<u8> {
+    try_serialize(value).unwrap_or_else(|failure| failure.exit(json))
+}
+
+fn try_serialize<T: Serialize>(value: &T) -> Result<Vec<u8>, Failure> {
+    bincode::serialize(value).map_err(|error| {
+        Failure::new(
+            ErrorCode::InvalidData,
+            format!("failed serializing entry: {}", error),
+        )
+    })
+}
+
+/// Apply `writes` atomically and record them in the journal.
+///
+/// A write with a value of `None` removes the key.
+fn commit(
+    db: &Arc<dyn Storage>,
+    json: bool,
+    description: &str,
+    kind: OperationKind,
+    writes: Vec<(Keyspace, String, Option<Vec<u8>>)>,
+) -> Operation {
+    match try_commit(db, description, kind, writes) {
+        Ok(operation) => operation,
+        Err(error) => storage_fail(json, &format!("failed to {}", description), error),
+    }
+}
+
+/// How often to try again when another process took the next journal id
+const COMMIT_ATTEMPTS: usize = 100;
+
+/// [`commit`], for callers that can't exit on failure
+pub fn try_commit(
+    db: &Arc<dyn Storage>,
+    description: &str,
+    kind: OperationKind,
+    writes: Vec<(Keyspace, String, Option<Vec<u8>>)>,
+) -> Result<Operation, StorageError> {
+    for _ in 0..COMMIT_ATTEMPTS {
+        // SQLite stores are shared between processes, so another one can take
+        // this id before the transaction starts. It's checked again inside.
+        let id = match db.last(Keyspace::Journal)? {
+            Some((key, _)) => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&key);
+                u64::from_be_bytes(bytes) + 1
             }
-        },
-        Err(error) => {
-            if json {
-                println!(
-                    "{}",
-                    json!({
-                        "status": "fail",
-                        "reason": error.to_string(),
-                    })
+            None => 1,
+        };
+
+        let mut committed = None;
+        let result = db.transaction(&mut |transaction| {
+            if transaction
+                .get(Keyspace::Journal, &id.to_be_bytes())?
+                .is_some()
+            {
+                return Err(StorageError::Conflict);
+            }
+            let mut changes = Vec::new();
+            for (keyspace, key, value) in &writes {
+                debug_assert!(JOURNALED.contains(keyspace));
+                let before = match value {
+                    Some(value) => transaction.put(*keyspace, key.as_bytes(), value)?,
+                    None => transaction.delete(*keyspace, key.as_bytes())?,
+                };
+                changes.push(Change {
+                    keyspace: *keyspace,
+                    key: key.clone(),
+                    before,
+                    after: value.clone(),
+                });
+            }
+
+            let operation = Operation {
+                id,
+                at: Utc::now(),
+                description: description.to_string(),
+                kind,
+                changes,
+            };
+            let bytes =
+                bincode::serialize(&operation).map_err(|e| StorageError::Abort(e.to_string()))?;
+            transaction.put(Keyspace::Journal, &id.to_be_bytes(), &bytes)?;
+            committed = Some(operation);
+            Ok(())
+        });
+
+        match (result, committed) {
+            (Ok(()), Some(operation)) => return Ok(operation),
+            (Err(StorageError::Conflict), _) => continue,
+            (Err(error), _) => return Err(error),
+            (Ok(()), None) => unreachable!("a transaction that succeeded ran to its end"),
+        }
+    }
+    Err(StorageError::Conflict)
+}
+
+pub fn get_journal(json: bool, path: Option<PathBuf>) -> Vec<Operation> {
+    let db = open_store(json, path);
+
+    let mut operations = Vec::new();
+    for pair in db.scan(Keyspace::Journal, &[]) {
+        let bytes = match pair {
+            Ok((_, bytes)) => bytes,
+            Err(error) => storage_fail(json, "failed reading the journal", error),
+        };
+        match bincode::deserialize(&bytes) {
+            Ok(operation) => operations.push(operation),
+            Err(error) => {
+                fail(
+                    json,
+                    ErrorCode::InvalidData,
+                    &format!("failed deserializing operation: {}", error),
                 );
-            } else {
-                error!("failed to get first key: {}", error);
             }
-            return None;
         }
-    };
+    }
+    operations
+}
 
-    let mut bookmarks_vector: Vec<Bookmark> = Vec::new();
-    let mut iter = db.range(first_key..);
-
-    loop {
-        match iter.next() {
-            Some(x) => {
-                let read_entry;
-                match bincode::deserialize(&x.unwrap().1) {
-                    Ok(result) => read_entry = result,
-                    Err(error) => {
-                        if json {
-                            println!(
-                                "{}",
-                                json!({
-                                    "status": "fail",
-                                    "reason": error.to_string(),
-                                })
-                            );
-                        } else {
-                            error!("failed deserializing entry: {}", error);
-                        }
-                        return None;
-                    }
-                }
-                bookmarks_vector.push(read_entry);
+/// Replay the journal to find which operations are currently applied
+/// and which have been undone, most recent last
+pub fn history(operations: &[Operation]) -> (Vec<u64>, Vec<u64>) {
+    let mut done = Vec::new();
+    let mut undone = Vec::new();
+    for operation in operations {
+        match operation.kind {
+            OperationKind::Change => {
+                done.push(operation.id);
+                undone.clear();
+            }
+            OperationKind::Undo(target) => {
+                done.retain(|id| *id != target);
+                undone.push(target);
+            }
+            OperationKind::Redo(target) => {
+                undone.retain(|id| *id != target);
+                done.push(target);
             }
-            None => break,
         }
     }
-    return Some(bookmarks_vector);
+    (done, undone)
+}
+
+/// Revert the most recent operation that hasn't been undone yet
+pub fn undo(json: bool, path: Option<PathBuf>) -> Option<Operation> {
+    replay(json, path, true)
+}
+
+/// Reapply the most recently undone operation
+pub fn redo(json: bool, path: Option<PathBuf>) -> Option<Operation> {
+    replay(json, path, false)
+}
+
+fn replay(json: bool, path: Option<PathBuf>, undo: bool) -> Option<Operation> {
+    let operations = get_journal(json, path.clone());
+    let (done, undone) = history(&operations);
+    let target_id = if undo { done.last() } else { undone.last() }?;
+    let target = operations.iter().find(|o| o.id == *target_id)?;
+
+    let db = open_store(json, path);
+
+    // Only replay on top of the state the operation left behind
+    for change in &target.changes {
+        let expected = if undo { &change.after } else { &change.before };
+        let current = match db.get(change.keyspace, change.key.as_bytes()) {
+            Ok(value) => value,
+            Err(error) => storage_fail(json, &format!("failed to read {}", change.key), error),
+        };
+        if &current != expected {
+            fail(
+                json,
+                ErrorCode::InvalidData,
+                &format!(
+                    "{} has changed since #{}, refusing to replay it",
+                    change.key, target.id
+                ),
+            );
+        }
+    }
+
+    let (writes, kind, verb): (Vec<_>, _, _) = if undo {
+        (
+            target
+                .changes
+                .iter()
+                .rev()
+                .map(|c| (c.keyspace, c.key.clone(), c.before.clone()))
+                .collect(),
+            OperationKind::Undo(target.id),
+            "undo",
+        )
+    } else {
+        (
+            target
+                .changes
+                .iter()
+                .map(|c| (c.keyspace, c.key.clone(), c.after.clone()))
+                .collect(),
+            OperationKind::Redo(target.id),
+            "redo",
+        )
+    };
+
+    let description = format!("{} #{}: {}", verb, target.id, target.description);
+    commit(&db, json, &description, kind, writes);
+    Some(target.clone())
+}
+
+fn deserialize_entry(bytes: &[u8], json: bool) -> Bookmark {
+    try_deserialize_entry(bytes).unwrap_or_else(|failure| failure.exit(json))
+}
+
+fn try_deserialize_entry(bytes: &[u8]) -> Result<Bookmark, Failure> {
+    bincode::deserialize(bytes).map_err(|error| {
+        Failure::new(
+            ErrorCode::InvalidData,
+            format!("failed deserializing entry: {}", error),
+        )
+    })
+}
+
+/// Call `f` with every entry in turn, without reading them all into memory first
+pub fn for_each(json: bool, path: Option<PathBuf>, keyspace: Keyspace, f: impl FnMut(Bookmark)) {
+    try_for_each(json, path, keyspace, f).unwrap_or_else(|failure| failure.exit(json))
+}
+
+pub fn try_for_each(
+    json: bool,
+    path: Option<PathBuf>,
+    keyspace: Keyspace,
+    mut f: impl FnMut(Bookmark),
+) -> Result<(), Failure> {
+    let db = try_open_store(json, path)?;
+
+    for pair in db.scan(keyspace, &[]) {
+        match pair {
+            Ok((_, bytes)) => f(try_deserialize_entry(&bytes)?),
+            Err(error) => return Err(storage_failure("failed reading entries", error)),
+        }
+    }
+    Ok(())
+}
+
+/// Every entry, in key order, which is nothing for a new store
+pub fn get_all(json: bool, path: Option<PathBuf>, keyspace: Keyspace) -> Vec<Bookmark> {
+    let mut entries = Vec::new();
+    for_each(json, path, keyspace, |entry| entries.push(entry));
+    entries
 }
 
 /* not working for some reason
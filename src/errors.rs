@@ -0,0 +1,111 @@
+//! The failures tinymark reports, each with a code for scripts and an exit code.
+//!
+//! With `--json` a failure is printed in the envelope of [`crate::output`],
+//! with an error like `{"code": "not_found", "reason": "..."}`, and otherwise
+//! as an error message on stderr. Either way the process exits with the code's
+//! exit code, from the table in the README.
+
+use crate::output;
+use paris::*;
+use serde_derive::Serialize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Files and directories to remove before exiting, like the snapshot `--read-only` reads
+static TEMPORARY: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The arguments don't make sense, or need confirming interactively
+    Usage,
+    /// There's no such bookmark, folder, trash entry or operation
+    NotFound,
+    /// Input or stored data couldn't be understood, or changed underneath
+    InvalidData,
+    /// The store or an input file couldn't be opened
+    NoInput,
+    /// Something tinymark relies on, like an editor or an address, isn't available
+    Unavailable,
+    /// A bug in tinymark
+    Internal,
+    /// An output file or directory couldn't be created
+    CantCreate,
+    /// Reading or writing failed halfway
+    Io,
+    /// Another process has the store open, and didn't close it in time
+    Busy,
+    /// The configuration or the environment is wrong
+    Config,
+}
+
+impl ErrorCode {
+    pub fn exit_code(self) -> exitcode::ExitCode {
+        match self {
+            ErrorCode::Usage => exitcode::USAGE,
+            ErrorCode::NotFound | ErrorCode::InvalidData => exitcode::DATAERR,
+            ErrorCode::NoInput => exitcode::NOINPUT,
+            ErrorCode::Unavailable => exitcode::UNAVAILABLE,
+            ErrorCode::Internal => exitcode::SOFTWARE,
+            ErrorCode::CantCreate => exitcode::CANTCREAT,
+            ErrorCode::Io => exitcode::IOERR,
+            ErrorCode::Busy => exitcode::TEMPFAIL,
+            ErrorCode::Config => exitcode::CONFIG,
+        }
+    }
+}
+
+/// A failure for code that has to keep going, like `serve`, to report itself
+#[derive(Debug)]
+pub struct Failure {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl Failure {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Failure {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Report it and exit, like [`fail`]
+    pub fn exit(&self, json: bool) -> ! {
+        fail(json, self.code, &self.reason)
+    }
+}
+
+/// Report a failure without exiting, for when there's more to do first
+pub fn report(json: bool, code: ErrorCode, reason: &str) {
+    if json {
+        println!("{}", output::failure(code, reason));
+    } else {
+        error!("{}", reason);
+    }
+}
+
+/// Report a failure and exit with its exit code
+pub fn fail(json: bool, code: ErrorCode, reason: &str) -> ! {
+    report(json, code, reason);
+    remove_temporary();
+    std::process::exit(code.exit_code())
+}
+
+/// Remove `path` once tinymark is done, whether it fails or not
+pub fn remove_on_exit(path: PathBuf) {
+    TEMPORARY.lock().unwrap().push(path);
+}
+
+/// Remove what [`remove_on_exit`] was given
+pub fn remove_temporary() {
+    // A panic while it was held doesn't make the paths any less temporary
+    let mut temporary = TEMPORARY.lock().unwrap_or_else(|e| e.into_inner());
+    for path in temporary.drain(..) {
+        let _ = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+    }
+}
@@ -0,0 +1,145 @@
+//! Atom and RSS 2.0 feeds of recently added bookmarks.
+
+use crate::structures::Bookmark;
+use chrono::Utc;
+
+/// What a feed is about, shown to the subscriber
+pub struct FeedInfo<'a> {
+    pub title: &'a str,
+    /// A permanent, unique IRI for the feed
+    pub id: &'a str,
+    /// Where the feed's content can be seen, if it's published somewhere
+    pub link: Option<&'a str>,
+    pub description: &'a str,
+}
+
+/// Escape text for use in XML or HTML content and attributes
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The `limit` most recently created bookmarks, optionally only those with `tag`
+pub fn recent(mut bookmarks: Vec<Bookmark>, tag: Option<&str>, limit: usize) -> Vec<Bookmark> {
+    if let Some(tag) = tag {
+        bookmarks.retain(|b| b.tags.iter().any(|t| t == tag));
+    }
+    bookmarks.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+    bookmarks.truncate(limit);
+    bookmarks
+}
+
+pub fn atom(info: &FeedInfo, bookmarks: &[Bookmark]) -> String {
+    let updated = bookmarks
+        .iter()
+        .map(|b| b.created_at)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    out.push_str(&format!("  <title>{}</title>\n", escape(info.title)));
+    out.push_str(&format!("  <id>{}</id>\n", escape(info.id)));
+    out.push_str(&format!("  <updated>{}</updated>\n", updated.to_rfc3339()));
+    if let Some(link) = info.link {
+        out.push_str(&format!("  <link href=\"{}\"/>\n", escape(link)));
+    }
+    out.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        escape(info.title)
+    ));
+    out.push_str("  <generator>tinymark</generator>\n");
+
+    for b in bookmarks {
+        out.push_str("  <entry>\n");
+        out.push_str(&format!("    <title>{}</title>\n", escape(&b.label)));
+        out.push_str(&format!("    <id>{}</id>\n", escape(b.link.as_str())));
+        out.push_str(&format!(
+            "    <link href=\"{}\"/>\n",
+            escape(b.link.as_str())
+        ));
+        out.push_str(&format!(
+            "    <published>{}</published>\n",
+            b.created_at.to_rfc3339()
+        ));
+        out.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            b.created_at.to_rfc3339()
+        ));
+        if let Some(description) = &b.description {
+            out.push_str(&format!("    <summary>{}</summary>\n", escape(description)));
+        }
+        for tag in &b.tags {
+            out.push_str(&format!("    <category term=\"{}\"/>\n", escape(tag)));
+        }
+        out.push_str("  </entry>\n");
+    }
+    out.push_str("</feed>\n");
+    out
+}
+
+/// RSS requires a link for the channel, the id is used when `info.link` isn't set
+pub fn rss(info: &FeedInfo, bookmarks: &[Bookmark]) -> String {
+    let updated = bookmarks
+        .iter()
+        .map(|b| b.created_at)
+        .max()
+        .unwrap_or_else(Utc::now);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    out.push_str("<rss version=\"2.0\">\n");
+    out.push_str("  <channel>\n");
+    out.push_str(&format!("    <title>{}</title>\n", escape(info.title)));
+    out.push_str(&format!(
+        "    <link>{}</link>\n",
+        escape(info.link.unwrap_or(info.id))
+    ));
+    out.push_str(&format!(
+        "    <description>{}</description>\n",
+        escape(info.description)
+    ));
+    out.push_str(&format!(
+        "    <lastBuildDate>{}</lastBuildDate>\n",
+        updated.to_rfc2822()
+    ));
+    out.push_str("    <generator>tinymark</generator>\n");
+
+    for b in bookmarks {
+        out.push_str("    <item>\n");
+        out.push_str(&format!("      <title>{}</title>\n", escape(&b.label)));
+        out.push_str(&format!("      <link>{}</link>\n", escape(b.link.as_str())));
+        out.push_str(&format!(
+            "      <guid isPermaLink=\"true\">{}</guid>\n",
+            escape(b.link.as_str())
+        ));
+        out.push_str(&format!(
+            "      <pubDate>{}</pubDate>\n",
+            b.created_at.to_rfc2822()
+        ));
+        if let Some(description) = &b.description {
+            out.push_str(&format!(
+                "      <description>{}</description>\n",
+                escape(description)
+            ));
+        }
+        for tag in &b.tags {
+            out.push_str(&format!("      <category>{}</category>\n", escape(tag)));
+        }
+        out.push_str("    </item>\n");
+    }
+    out.push_str("  </channel>\n");
+    out.push_str("</rss>\n");
+    out
+}
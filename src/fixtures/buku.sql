@@ -0,0 +1,4 @@
+CREATE TABLE bookmarks (id integer PRIMARY KEY, URL text NOT NULL UNIQUE, metadata text default '', tags text default ',', desc text default '', flags integer default 0);
+INSERT INTO bookmarks VALUES (1, 'https://github.com/jarun/buku', 'buku', ',bookmarks,cli,python,', 'Personal mini-web in text', 0);
+INSERT INTO bookmarks VALUES (2, 'https://example.com', '', ',', '', 1);
+INSERT INTO bookmarks VALUES (3, 'example.org/bare', 'Bare domain', ',web,', '', 0);
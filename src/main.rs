@@ -1,51 +1,216 @@
+mod buku;
 mod commands;
+mod completions;
+mod config;
 mod database;
+mod errors;
+mod feed;
+mod manual;
+mod native;
+mod outline;
+mod output;
+mod replica;
+mod server;
+mod services;
+mod site;
+pub mod storage;
 pub mod structures;
+mod sync;
+#[cfg(test)]
 mod tests;
 
-use crate::structures::{Cli, Commands, Config, Keyspace};
+use crate::errors::ErrorCode;
+use crate::structures::{
+    Cli, Commands, Config, ConfigCommands, DbCommands, ProfileCommands, ReplicateCommands,
+    TrashCommands,
+};
 use clap::Parser;
-use paris::*;
 
 fn main() {
-    let cfg: Config = confy::load("tinymark").unwrap();
-    confy::store("tinymark", &cfg).unwrap();
+    let args = Cli::parse();
+
+    // These have to work when the configuration can't be read
+    match &args.command {
+        Commands::Config(ConfigCommands::Path) => {
+            return commands::config_path(&args.config, args.json)
+        }
+        Commands::Config(ConfigCommands::Edit) => {
+            return commands::edit_config(&args.config, args.json)
+        }
+        _ => {}
+    }
+
+    let mut cfg = commands::load_config(&args.config, args.json);
+    let json = cfg.json || args.json;
+    // Creating the profile `default_profile` names has to work before it exists
+    let profile = match &args.command {
+        Commands::Profile(ProfileCommands::Create { .. }) => None,
+        _ => commands::selected_profile(&args.profile, &cfg, json),
+    };
+    // `--db` and `TINYMARK_DB` take precedence over the profile and configuration
+    let storage = args
+        .db
+        .clone()
+        .or_else(|| {
+            profile
+                .as_ref()
+                .map(|p| cfg.profiles[p].storage_location.clone())
+        })
+        .or_else(|| cfg.storage_location.clone());
+    database::set_wait(std::time::Duration::from_secs(args.wait));
+    database::set_backend(cfg.backend);
+    let snapshot = args.read_only.then(|| {
+        if args.command.writes() {
+            errors::fail(
+                json,
+                ErrorCode::Usage,
+                "--read-only only works with commands that don't change the store",
+            );
+        }
+        database::snapshot(json, storage.clone())
+    });
+    let storage = snapshot.or(storage);
     /*
     println!("The configuration is:");
     println!("{:#?}", cfg);*/
 
     if cfg.tui {
-        warn!("TUI is not implemented yet!");
-        std::process::exit(exitcode::DATAERR);
+        errors::fail(json, ErrorCode::Unavailable, "TUI is not implemented yet!");
     }
 
-    let args = Cli::parse();
-    let json: bool;
-
-    if cfg.json {
-        json = true;
-    } else {
-        json = args.json;
-    }
+    run(&args, &mut cfg, &profile, json, storage);
+    errors::remove_temporary();
+}
 
+fn run(
+    args: &Cli,
+    cfg: &mut Config,
+    profile: &Option<String>,
+    json: bool,
+    storage: Option<std::path::PathBuf>,
+) {
     match &args.command {
-        Commands::New_Folder { name } => commands::new_folder(name, json),
+        Commands::NewFolder { name, parent } => commands::new_folder(name, parent, json, storage),
+        Commands::DeleteFolder { id } => commands::delete_folder(id, json, storage),
         Commands::Add {
             url,
             name,
             description,
             tags,
-        } => commands::add_bookmark(url, name, description, tags, json, cfg.storage_location),
-        Commands::Edit { url } => commands::edit_bookmark(json, url, cfg.storage_location),
-        Commands::Delete { url } => {
-            database::remove_entry(url, json, cfg.storage_location, Keyspace::Bookmarks)
+            folder,
+        } => commands::add_bookmark(
+            &cfg.normalize.apply(url),
+            name,
+            description,
+            tags,
+            folder,
+            json,
+            storage,
+        ),
+        Commands::Edit { url } => commands::edit_bookmark(json, url, storage),
+        Commands::Delete {
+            urls,
+            query,
+            dry_run,
+            yes,
+        } => commands::delete_bookmarks(urls, query, *dry_run, *yes, json, storage),
+        Commands::List { sort } => {
+            commands::list_bookmarks(sort.unwrap_or(cfg.sort), json, storage)
+        }
+        Commands::Export { file, format } => commands::export(
+            file.to_path_buf(),
+            *format,
+            cfg.default_format,
+            json,
+            storage,
+        ),
+        Commands::Import {
+            file,
+            format,
+            on_conflict,
+        } => commands::import(
+            file.to_path_buf(),
+            *format,
+            cfg.default_format,
+            *on_conflict,
+            &cfg.normalize,
+            json,
+            storage,
+        ),
+        Commands::Publish {
+            dir,
+            title,
+            base_url,
+            templates,
+            init_templates,
+        } => commands::publish(
+            dir,
+            title,
+            base_url,
+            templates
+                .clone()
+                .unwrap_or_else(|| commands::templates_dir(&args.config, json)),
+            *init_templates,
+            json,
+            storage,
+        ),
+        Commands::Feed {
+            tag,
+            limit,
+            format,
+            title,
+            link,
+        } => commands::feed(tag, *limit, *format, title, link, json, storage),
+        Commands::Trash(TrashCommands::List) => commands::list_trash(json, storage),
+        Commands::Trash(TrashCommands::Restore { id }) => {
+            commands::restore_trash(id, json, storage)
+        }
+        Commands::Trash(TrashCommands::Empty { older_than }) => {
+            commands::empty_trash(older_than, json, storage)
+        }
+        Commands::Undo => commands::undo(json, storage),
+        Commands::Redo => commands::redo(json, storage),
+        Commands::Sync { dir } => commands::sync(dir, json, storage),
+        Commands::Replicate(ReplicateCommands::ExportDelta { file, since }) => {
+            let actor =
+                commands::store_actor(cfg, profile, args.db.is_none(), json, storage.clone());
+            commands::export_delta(file, since, actor, json, storage)
+        }
+        Commands::Replicate(ReplicateCommands::ApplyDelta { file }) => {
+            let actor =
+                commands::store_actor(cfg, profile, args.db.is_none(), json, storage.clone());
+            commands::apply_delta(file, actor, json, storage)
+        }
+        Commands::Profile(ProfileCommands::List) => commands::list_profiles(cfg, profile, json),
+        Commands::Profile(ProfileCommands::Create {
+            name,
+            path,
+            default,
+        }) => commands::create_profile(name, path, *default, cfg, &args.config, json),
+        Commands::Profile(ProfileCommands::CopyTo {
+            other,
+            query,
+            on_conflict,
+        }) => commands::copy_to_profile(other, query, *on_conflict, cfg, json, storage),
+        Commands::Config(ConfigCommands::Get { key }) => commands::get_config(key, cfg, json),
+        Commands::Config(ConfigCommands::Set { key, value }) => {
+            commands::set_config(key, value, cfg, &args.config, json)
+        }
+        Commands::Config(ConfigCommands::Unset { key }) => {
+            commands::unset_config(key, cfg, &args.config, json)
         }
-        Commands::List {} => commands::list_bookmarks(json, cfg.storage_location),
-        Commands::Export { file } => {
-            commands::export(file.to_path_buf(), json, cfg.storage_location)
+        Commands::Config(ConfigCommands::Path | ConfigCommands::Edit) => unreachable!(),
+        Commands::Serve { bind, allow_origin } => {
+            commands::serve(bind, allow_origin, json, storage)
         }
-        Commands::Import { file } => {
-            commands::import(file.to_path_buf(), json, cfg.storage_location)
+        Commands::NativeHost { .. } => commands::native_host(storage),
+        Commands::Completions { shell } => commands::completions(*shell),
+        Commands::Complete { kind } => commands::complete(*kind, storage),
+        Commands::Man { command, out_dir } => commands::man(command, out_dir, json),
+        Commands::Schema => commands::schema(),
+        Commands::Db(DbCommands::Convert { to, out }) => {
+            commands::convert_store(*to, out, json, storage)
         }
+        Commands::Log { limit } => commands::show_log(limit, json, storage),
     }
 }
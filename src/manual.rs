@@ -0,0 +1,99 @@
+//! Man pages for `tinymark man`, rendered from the clap definitions.
+//!
+//! Every command gets a page named after its path, like `tinymark-trash-empty(1)`,
+//! with the examples from its long help in an EXAMPLES section.
+
+use crate::structures::Cli;
+use clap::{Command, CommandFactory};
+use clap_mangen::Man;
+use std::io;
+
+/// The visible subcommands of `command`, leaving out the generated `help`
+fn subcommands<'a>(command: &'a Command<'static>) -> impl Iterator<Item = &'a Command<'static>> {
+    command
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+}
+
+/// Escape text for roff, keeping it as typed
+fn escape(line: &str) -> String {
+    let escaped = line.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{}", escaped)
+    } else {
+        escaped
+    }
+}
+
+/// Render the page of `command`, already named after its path
+fn render(command: Command<'static>) -> io::Result<Vec<u8>> {
+    let examples = command.get_after_long_help();
+    let has_subcommands = subcommands(&command).next().is_some();
+    let has_version = command.get_version().is_some();
+    let man = Man::new(command.clone()).source(format!("tinymark {}", env!("CARGO_PKG_VERSION")));
+
+    let mut page = Vec::new();
+    man.render_title(&mut page)?;
+    man.render_name_section(&mut page)?;
+    man.render_synopsis_section(&mut page)?;
+    man.render_description_section(&mut page)?;
+    man.render_options_section(&mut page)?;
+    if has_subcommands {
+        man.render_subcommands_section(&mut page)?;
+    }
+    // The long help has them under a heading and indented, which clap_mangen
+    // would fill into one paragraph
+    if let Some(examples) = examples {
+        page.extend_from_slice(b".SH EXAMPLES\n.nf\n");
+        for line in examples.lines().skip(1) {
+            page.extend_from_slice(escape(line.strip_prefix("    ").unwrap_or(line)).as_bytes());
+            page.push(b'\n');
+        }
+        page.extend_from_slice(b".fi\n");
+    }
+    if has_version {
+        man.render_version_section(&mut page)?;
+    }
+    Ok(page)
+}
+
+fn collect(
+    command: &Command<'static>,
+    name: &str,
+    pages: &mut Vec<(String, Vec<u8>)>,
+) -> io::Result<()> {
+    pages.push((
+        format!("{}.1", name),
+        render(command.clone().name(name.to_string()))?,
+    ));
+    for subcommand in subcommands(command) {
+        collect(
+            subcommand,
+            &format!("{}-{}", name, subcommand.get_name()),
+            pages,
+        )?;
+    }
+    Ok(())
+}
+
+/// Every page, as file names and their contents
+pub fn pages() -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut pages = Vec::new();
+    collect(&Cli::command(), "tinymark", &mut pages)?;
+    Ok(pages)
+}
+
+/// The page of the command at `path`, e.g. `["trash", "empty"]`
+pub fn page(path: &[String]) -> Result<Vec<u8>, String> {
+    let mut command = Cli::command();
+    let mut name = String::from("tinymark");
+    for part in path {
+        let found = subcommands(&command)
+            .find(|c| c.get_name() == part)
+            .cloned();
+        command = found
+            .ok_or_else(|| format!("there's no command `{} {}`", name.replace('-', " "), part))?;
+        name = format!("{}-{}", name, part);
+    }
+    render(command.name(name)).map_err(|e| e.to_string())
+}
@@ -0,0 +1,163 @@
+//! The WebExtensions native messaging host of `tinymark native-host`.
+//!
+//! The browser starts the host and exchanges JSON messages with it over
+//! stdin and stdout, each preceded by its length as a 32 bit integer in
+//! native byte order. Requests look like `{"action": "add", ...}`, and every
+//! reply is in the envelope `--json` prints, see `output`, with the request's
+//! `id` in its `meta` if it had one.
+//! Nothing else may be written to stdout, so the store is used without
+//! `--json` and its errors end up on stderr.
+
+use crate::database;
+use crate::errors::{ErrorCode, Failure};
+use crate::output;
+use crate::structures::{Bookmark, Keyspace};
+use chrono::Utc;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use url::Url;
+use uuid::Uuid;
+
+/// Browsers send at most 4 GiB, but nothing tinymark understands is this big
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// Read the next message, or `None` once the browser closed stdin
+pub fn read_message(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let length = u32::from_ne_bytes(length) as usize;
+    if length > MAX_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes is too long", length),
+        ));
+    }
+    let mut message = vec![0; length];
+    reader.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    writer.write_all(&(bytes.len() as u32).to_ne_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum Request {
+    /// Save a page, replacing it if it's already bookmarked
+    Add {
+        link: Url,
+        #[serde(default)]
+        label: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        folder: Option<Uuid>,
+    },
+    /// Find bookmarks like `tinymark` with a query does
+    Search {
+        query: String,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    /// Whether a page is bookmarked, to show it in the toolbar
+    Check { link: Url },
+}
+
+fn fail(code: ErrorCode, reason: &str) -> Value {
+    output::failure(code, reason)
+}
+
+/// Answer a single message
+pub fn handle(message: &[u8], path: &Option<PathBuf>) -> Value {
+    let raw: Value = match serde_json::from_slice(message) {
+        Ok(raw) => raw,
+        Err(e) => return fail(ErrorCode::InvalidData, &format!("invalid message: {}", e)),
+    };
+    let mut reply = match serde_json::from_value(raw.clone()) {
+        Ok(request) => answer(request, path),
+        Err(e) => fail(ErrorCode::Usage, &format!("invalid request: {}", e)),
+    };
+    if let Some(id) = raw.get("id") {
+        reply["meta"]["id"] = id.clone();
+    }
+    reply
+}
+
+fn answer(request: Request, path: &Option<PathBuf>) -> Value {
+    // The host keeps answering when the store can't, like when it's busy
+    try_answer(request, path).unwrap_or_else(|failure| fail(failure.code, &failure.reason))
+}
+
+fn try_answer(request: Request, path: &Option<PathBuf>) -> Result<Value, Failure> {
+    Ok(match request {
+        Request::Add {
+            link,
+            label,
+            description,
+            tags,
+            folder,
+        } => {
+            if let Some(id) = folder {
+                let folders = database::try_get_containers(false, path.clone())?;
+                if !folders.iter().any(|f| f.id == id) {
+                    return Ok(fail(
+                        ErrorCode::NotFound,
+                        &format!("folder {} not found", id),
+                    ));
+                }
+            }
+            let stored = database::try_get_entry(&link, false, path.clone(), Keyspace::Bookmarks)?;
+            let bookmark = Bookmark {
+                container: folder,
+                created_at: stored.map_or_else(Utc::now, |b| b.created_at),
+                description: description.filter(|d| !d.trim().is_empty()),
+                label: label
+                    .filter(|l| !l.trim().is_empty())
+                    .unwrap_or_else(|| link.to_string()),
+                link,
+                tags,
+            };
+            database::try_write_entry(false, path.clone(), Keyspace::Bookmarks, &bookmark)?;
+            output::success(bookmark, json!({ "reason": "inserted entry" }))
+        }
+        Request::Search { query, limit } => {
+            let mut bookmarks = Vec::new();
+            database::try_for_each(false, path.clone(), Keyspace::Bookmarks, |bookmark| {
+                if bookmark.matches(&query) {
+                    bookmarks.push(bookmark);
+                }
+            })?;
+            bookmarks.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+            bookmarks.truncate(limit.unwrap_or(usize::MAX));
+            output::success(bookmarks, Value::Null)
+        }
+        Request::Check { link } => {
+            let stored = database::try_get_entry(&link, false, path.clone(), Keyspace::Bookmarks)?;
+            output::success(&stored, json!({ "bookmarked": stored.is_some() }))
+        }
+    })
+}
+
+/// Answer messages until the browser closes stdin
+pub fn run(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    path: &Option<PathBuf>,
+) -> io::Result<()> {
+    while let Some(message) = read_message(reader)? {
+        write_message(writer, &handle(&message, path))?;
+    }
+    Ok(())
+}
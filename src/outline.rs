@@ -0,0 +1,467 @@
+//! Markdown and Org-mode outlines of the folder hierarchy.
+//!
+//! Folders become list items or headings, with their bookmarks nested inside.
+//! Markdown looks like
+//!
+//! ```markdown
+//! - Work
+//!   - [Example](https://example.com/) #rust #cli <!-- 2022-01-01T00:00:00+00:00 -->
+//!     A short description
+//! ```
+//!
+//! and Org-mode like
+//!
+//! ```org
+//! * Work
+//! ** [[https://example.com/][Example]] :rust:cli:
+//! :PROPERTIES:
+//! :CREATED: 2022-01-01T00:00:00+00:00
+//! :END:
+//! A short description
+//! ```
+//!
+//! Text that would be read differently, like a `#` in a tag or an indented
+//! description line, is escaped with a `\`, so an outline reads back the same.
+//! Parsing gives every folder a fresh id, see `commands::match_folders`
+//! for how they're matched up with the stored ones.
+
+use crate::structures::{Bookmark, Container, ContainerTypes};
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use url::Url;
+use uuid::Uuid;
+
+enum Item<'a> {
+    Folder(&'a Container),
+    Bookmark(&'a Bookmark),
+}
+
+/// Walk the hierarchy depth first, calling `f` with each item and its nesting depth
+fn walk(containers: &[Container], bookmarks: &[Bookmark], f: &mut dyn FnMut(usize, Item)) {
+    let ids: HashSet<Uuid> = containers.iter().map(|c| c.id).collect();
+    let is_root = |parent: &Option<Uuid>| parent.is_none_or(|id| !ids.contains(&id));
+
+    let mut sorted_bookmarks: Vec<&Bookmark> = bookmarks.iter().collect();
+    sorted_bookmarks.sort_by(|a, b| (&a.label, &a.link).cmp(&(&b.label, &b.link)));
+    let mut sorted_containers: Vec<&Container> = containers.iter().collect();
+    sorted_containers.sort_by(|a, b| (&a.label, a.id).cmp(&(&b.label, b.id)));
+
+    let mut state = Walk {
+        bookmarks: &sorted_bookmarks,
+        containers: &sorted_containers,
+        visited: HashSet::new(),
+        f,
+    };
+
+    for b in sorted_bookmarks.iter().filter(|b| is_root(&b.container)) {
+        (state.f)(0, Item::Bookmark(b));
+    }
+    for c in sorted_containers.iter().filter(|c| is_root(&c.container)) {
+        state.visit(0, c);
+    }
+    // Folders in a cycle have no root, list them at the top instead of losing them
+    for c in &sorted_containers {
+        if !state.visited.contains(&c.id) {
+            state.visit(0, c);
+        }
+    }
+}
+
+struct Walk<'a> {
+    bookmarks: &'a [&'a Bookmark],
+    containers: &'a [&'a Container],
+    visited: HashSet<Uuid>,
+    f: &'a mut dyn FnMut(usize, Item),
+}
+
+impl Walk<'_> {
+    fn visit(&mut self, depth: usize, container: &Container) {
+        if !self.visited.insert(container.id) {
+            return;
+        }
+        (self.f)(depth, Item::Folder(container));
+        for b in self.bookmarks {
+            if b.container == Some(container.id) {
+                (self.f)(depth + 1, Item::Bookmark(b));
+            }
+        }
+        let containers = self.containers;
+        for c in containers {
+            if c.container == Some(container.id) {
+                self.visit(depth + 1, c);
+            }
+        }
+    }
+}
+
+/// Escape the whitespace and `separator` that would split a tag with a `\`
+fn escape_tag(tag: &str, separator: char) -> String {
+    let mut escaped = String::new();
+    for c in tag.chars() {
+        if c == '\\' || c == separator || c.is_whitespace() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split `text` at the characters `is_separator` accepts, unless they're escaped
+fn split_escaped(text: &str, is_separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if is_separator(c) {
+            parts.push(&text[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn escape_brackets(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '\\' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether every `)` in `text` closes an earlier `(`
+fn balanced(text: &str) -> bool {
+    let mut depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            _ => {}
+        }
+    }
+    depth == 0
+}
+
+/// Links like `Rust_(programming_language)` are fine as they are, others with
+/// parentheses would end early and go in `<…>` instead
+fn markdown_link(link: &Url) -> String {
+    if balanced(link.as_str()) {
+        link.to_string()
+    } else {
+        format!("<{}>", link)
+    }
+}
+
+/// Description lines that would be read differently start with a `\`, which
+/// keeps the rest of them as it is: blank ones, indented ones, and ones
+/// starting with one of `starts`
+fn escape_description(text: &str, starts: &[&str]) -> String {
+    if text.is_empty()
+        || text.starts_with(char::is_whitespace)
+        || starts.iter().any(|start| text.starts_with(start))
+    {
+        format!("\\{}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn to_markdown(containers: &[Container], bookmarks: &[Bookmark]) -> String {
+    let mut out = String::new();
+    walk(containers, bookmarks, &mut |depth, item| {
+        let indent = "  ".repeat(depth);
+        match item {
+            Item::Folder(c) => {
+                out.push_str(&format!("{}- {}\n", indent, escape_brackets(&c.label)));
+            }
+            Item::Bookmark(b) => {
+                out.push_str(&format!(
+                    "{}- [{}]({})",
+                    indent,
+                    escape_brackets(&b.label),
+                    markdown_link(&b.link)
+                ));
+                for tag in &b.tags {
+                    out.push_str(&format!(" #{}", escape_tag(tag, '#')));
+                }
+                out.push_str(&format!(" <!-- {} -->\n", b.created_at.to_rfc3339()));
+                if let Some(description) = &b.description {
+                    for text in description.split('\n') {
+                        let text = escape_description(text, &["- ", "\\"]);
+                        out.push_str(&format!("{}  {}\n", indent, text));
+                    }
+                }
+            }
+        }
+    });
+    out
+}
+
+pub fn to_org(containers: &[Container], bookmarks: &[Bookmark]) -> String {
+    let mut out = String::new();
+    walk(containers, bookmarks, &mut |depth, item| {
+        let stars = "*".repeat(depth + 1);
+        match item {
+            Item::Folder(c) => {
+                out.push_str(&format!("{} {}\n", stars, escape_brackets(&c.label)));
+            }
+            Item::Bookmark(b) => {
+                out.push_str(&format!(
+                    "{} [[{}][{}]]",
+                    stars,
+                    b.link,
+                    escape_brackets(&b.label)
+                ));
+                if !b.tags.is_empty() {
+                    let tags: Vec<String> = b.tags.iter().map(|t| escape_tag(t, ':')).collect();
+                    out.push_str(&format!(" :{}:", tags.join(":")));
+                }
+                out.push_str("\n:PROPERTIES:\n");
+                out.push_str(&format!(":CREATED: {}\n", b.created_at.to_rfc3339()));
+                out.push_str(":END:\n");
+                if let Some(description) = &b.description {
+                    for text in description.split('\n') {
+                        out.push_str(&escape_description(text, &["*", ":", "\\"]));
+                        out.push('\n');
+                    }
+                }
+            }
+        }
+    });
+    out
+}
+
+/// Builds up the hierarchy while parsing, tracking the open folder at each depth
+#[derive(Default)]
+struct Builder {
+    containers: Vec<Container>,
+    bookmarks: Vec<Bookmark>,
+    /// (depth, id) of the folders enclosing the current line
+    stack: Vec<(usize, Uuid)>,
+    /// Description lines of the last bookmark
+    description: Vec<String>,
+    /// Whether the last item was a bookmark, so the lines after it describe it
+    in_bookmark: bool,
+}
+
+impl Builder {
+    fn parent(&mut self, depth: usize) -> Option<Uuid> {
+        while self.stack.last().is_some_and(|(d, _)| *d >= depth) {
+            self.stack.pop();
+        }
+        self.stack.last().map(|(_, id)| *id)
+    }
+
+    /// Add a line to the description, where blank lines have been escaped
+    fn describe(&mut self, line: &str) {
+        if self.in_bookmark && !line.is_empty() {
+            let line = line.strip_prefix('\\').unwrap_or(line);
+            self.description.push(line.to_string());
+        }
+    }
+
+    fn folder(&mut self, depth: usize, label: String) {
+        self.finish_description();
+        self.in_bookmark = false;
+        let container = self.parent(depth);
+        let id = Uuid::new_v4();
+        self.stack.push((depth, id));
+        self.containers
+            .push(Container::new(container, ContainerTypes::Folder, id, label));
+    }
+
+    fn bookmark(&mut self, depth: usize, bookmark: Bookmark) {
+        self.finish_description();
+        self.in_bookmark = true;
+        let container = self.parent(depth);
+        self.bookmarks.push(Bookmark {
+            container,
+            ..bookmark
+        });
+    }
+
+    fn finish_description(&mut self) {
+        if self.description.is_empty() {
+            return;
+        }
+        if let Some(bookmark) = self.bookmarks.last_mut() {
+            bookmark.description = Some(self.description.join("\n"));
+        }
+        self.description.clear();
+    }
+
+    fn finish(mut self) -> (Vec<Container>, Vec<Bookmark>) {
+        self.finish_description();
+        (self.containers, self.bookmarks)
+    }
+}
+
+fn new_bookmark(
+    link: Url,
+    label: String,
+    tags: Vec<String>,
+    created_at: Option<DateTime<Utc>>,
+) -> Bookmark {
+    Bookmark {
+        container: None,
+        created_at: created_at.unwrap_or_else(Utc::now),
+        description: None,
+        label,
+        link,
+        tags,
+    }
+}
+
+/// Read an escaped label up to its closing `]`, returning the rest after it
+fn split_label(text: &str) -> Option<(String, &str)> {
+    let mut label = String::new();
+    let mut chars = text.char_indices();
+    loop {
+        match chars.next()? {
+            (_, '\\') => label.push(chars.next()?.1),
+            (i, ']') => return Some((label, &text[i + 1..])),
+            (_, c) => label.push(c),
+        }
+    }
+}
+
+/// Split `[label](link)` off the front of `text`, returning the rest. The link ends at
+/// the `)` that isn't closing one of its own, or is in `<…>`
+fn parse_markdown_link(text: &str) -> Option<(String, &str, &str)> {
+    let (label, rest) = split_label(text.strip_prefix('[')?)?;
+    let rest = rest.strip_prefix('(')?;
+    if let Some(rest) = rest.strip_prefix('<') {
+        let close = rest.find(">)")?;
+        return Some((label, &rest[..close], &rest[close + 2..]));
+    }
+    let mut depth = 0usize;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some((label, &rest[..i], &rest[i + 1..])),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+pub fn from_markdown(text: &str) -> Result<(Vec<Container>, Vec<Bookmark>), String> {
+    let mut builder = Builder::default();
+    for (number, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let depth = (line.len() - trimmed.len()) / 2;
+
+        let item = match trimmed.strip_prefix("- ") {
+            Some(item) => item,
+            None => {
+                builder.describe(trimmed);
+                continue;
+            }
+        };
+
+        match parse_markdown_link(item) {
+            Some((label, link, rest)) => {
+                let link = Url::parse(link)
+                    .map_err(|e| format!("invalid link on line {}: {}", number + 1, e))?;
+                let (rest, created_at) = match rest.rfind("<!--") {
+                    Some(start) => {
+                        let comment = rest[start + 4..].trim_end().trim_end_matches("-->").trim();
+                        let created_at = DateTime::parse_from_rfc3339(comment)
+                            .map(|d| d.with_timezone(&Utc))
+                            .ok();
+                        (&rest[..start], created_at)
+                    }
+                    None => (rest, None),
+                };
+                let tags = split_escaped(rest, char::is_whitespace)
+                    .into_iter()
+                    .filter_map(|word| word.strip_prefix('#'))
+                    .filter(|tag| !tag.is_empty())
+                    .map(unescape)
+                    .collect();
+                builder.bookmark(depth, new_bookmark(link, label, tags, created_at));
+            }
+            None => builder.folder(depth, unescape(item.trim())),
+        }
+    }
+    Ok(builder.finish())
+}
+
+pub fn from_org(text: &str) -> Result<(Vec<Container>, Vec<Bookmark>), String> {
+    let mut builder = Builder::default();
+    let mut in_properties = false;
+    for (number, line) in text.lines().enumerate() {
+        let stars = line.chars().take_while(|c| *c == '*').count();
+        let heading = match line[stars..].strip_prefix(' ') {
+            Some(heading) if stars > 0 => heading.trim(),
+            _ => {
+                let trimmed = line.trim();
+                if trimmed == ":PROPERTIES:" {
+                    in_properties = true;
+                } else if trimmed == ":END:" {
+                    in_properties = false;
+                } else if in_properties {
+                    if let Some(created) = trimmed.strip_prefix(":CREATED:") {
+                        let created = DateTime::parse_from_rfc3339(created.trim())
+                            .map_err(|e| format!("invalid date on line {}: {}", number + 1, e))?;
+                        if let Some(bookmark) =
+                            builder.bookmarks.last_mut().filter(|_| builder.in_bookmark)
+                        {
+                            bookmark.created_at = created.with_timezone(&Utc);
+                        }
+                    }
+                } else {
+                    builder.describe(line.trim_start());
+                }
+                continue;
+            }
+        };
+        let depth = stars - 1;
+
+        let link = heading.strip_prefix("[[").and_then(|rest| {
+            let (link, rest) = rest.split_once("][")?;
+            let (label, rest) = split_label(rest)?;
+            Some((link, label, rest.strip_prefix(']')?.trim()))
+        });
+
+        match link {
+            Some((link, label, rest)) => {
+                let link = Url::parse(link)
+                    .map_err(|e| format!("invalid link on line {}: {}", number + 1, e))?;
+                let tags = if rest.len() > 1 && rest.starts_with(':') && rest.ends_with(':') {
+                    split_escaped(&rest[1..rest.len() - 1], |c| c == ':')
+                        .into_iter()
+                        .filter(|t| !t.is_empty())
+                        .map(unescape)
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                builder.bookmark(depth, new_bookmark(link, label, tags, None));
+            }
+            None => builder.folder(depth, unescape(heading)),
+        }
+    }
+    Ok(builder.finish())
+}
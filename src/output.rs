@@ -0,0 +1,57 @@
+//! The envelope every `--json` response is printed in, one per command:
+//!
+//! ```json
+//! {"status": "success", "data": ..., "errors": [], "meta": {"version": 1, "reason": "..."}}
+//! ```
+//!
+//! `data` is what the command produced, like a bookmark or a list of them, and
+//! `null` when there's only a message. `errors` has a `{"code", "reason"}`
+//! object for each failure and is empty on success. `meta` always has the
+//! envelope `version`, usually a human readable `reason`, and whatever else a
+//! command reports, like counts. [`SCHEMA`] describes all of it.
+
+use crate::errors::ErrorCode;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Bumped when the envelope changes in a way scripts would notice
+pub const VERSION: u32 = 1;
+
+/// The JSON Schema of the envelope, printed by `tinymark schema`
+pub const SCHEMA: &str = include_str!("../schema/envelope.schema.json");
+
+fn meta(extra: Value) -> Value {
+    let mut meta = json!({ "version": VERSION });
+    if let Value::Object(extra) = extra {
+        meta.as_object_mut().unwrap().extend(extra);
+    }
+    meta
+}
+
+pub fn success(data: impl Serialize, extra: Value) -> Value {
+    json!({
+        "status": "success",
+        "data": data,
+        "errors": [],
+        "meta": meta(extra),
+    })
+}
+
+pub fn failure(code: ErrorCode, reason: &str) -> Value {
+    json!({
+        "status": "fail",
+        "data": null,
+        "errors": [{ "code": code, "reason": reason }],
+        "meta": meta(Value::Null),
+    })
+}
+
+/// Print the envelope of a successful command, with `extra` added to its `meta`
+pub fn print(data: impl Serialize, extra: Value) {
+    println!("{}", success(data, extra));
+}
+
+/// Print the envelope of a command that only has a message
+pub fn print_reason(reason: &str) {
+    print(Value::Null, json!({ "reason": reason }));
+}
@@ -0,0 +1,243 @@
+//! Conflict-free replicated state for `tinymark replicate`.
+//!
+//! Every bookmark is replicated as a [`Replica`]: the label, description,
+//! folder and whether it's deleted are last-writer-wins registers and the tags
+//! an observed-remove set. Merging replicas is commutative, associative and
+//! idempotent, so stores that exchange deltas in any order end up the same.
+//!
+//! The store itself isn't replicated, instead [`observe`] turns the
+//! differences between a bookmark and its replica into writes by this store's
+//! actor, timed by when the journal recorded the change.
+
+use crate::structures::Bookmark;
+use crate::sync::Version;
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use url::Url;
+use uuid::Uuid;
+
+/// The version of [`Delta`] written by this build
+pub const DELTA_VERSION: u32 = 1;
+
+/// A register where the latest write wins, ties are broken by the actor id
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Lww<T> {
+    pub value: T,
+    pub at: DateTime<Utc>,
+    pub actor: Uuid,
+}
+
+impl<T: Clone + PartialEq> Lww<T> {
+    fn new(value: T, at: DateTime<Utc>, actor: Uuid) -> Self {
+        Self { value, at, actor }
+    }
+
+    /// Write a local change, which has to win over everything seen so far
+    fn set(&mut self, value: T, at: DateTime<Utc>, actor: Uuid) {
+        let at = if at > self.at {
+            at
+        } else {
+            Utc::now().max(self.at)
+        };
+        *self = Self { value, at, actor };
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if (other.at, other.actor) > (self.at, self.actor) {
+            *self = other.clone();
+        }
+    }
+}
+
+/// A set where adding an element wins over concurrently removing it. Each add
+/// is tagged with a unique id, and a remove only removes the tags it has seen.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct OrSet {
+    pub adds: BTreeMap<String, BTreeSet<Uuid>>,
+    pub removed: BTreeSet<Uuid>,
+}
+
+impl OrSet {
+    pub fn contains(&self, element: &str) -> bool {
+        self.adds
+            .get(element)
+            .is_some_and(|tags| tags.iter().any(|t| !self.removed.contains(t)))
+    }
+
+    /// The elements in the set, sorted
+    pub fn elements(&self) -> Vec<String> {
+        self.adds
+            .keys()
+            .filter(|e| self.contains(e))
+            .cloned()
+            .collect()
+    }
+
+    fn add(&mut self, element: &str) {
+        self.adds
+            .entry(element.to_string())
+            .or_default()
+            .insert(Uuid::new_v4());
+    }
+
+    fn remove(&mut self, element: &str) {
+        if let Some(tags) = self.adds.get(element) {
+            self.removed.extend(tags.iter().copied());
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (element, tags) in &other.adds {
+            self.adds
+                .entry(element.clone())
+                .or_default()
+                .extend(tags.iter().copied());
+        }
+        self.removed.extend(other.removed.iter().copied());
+    }
+}
+
+/// The replicated state of a bookmark
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Replica {
+    pub link: Url,
+    /// The earliest creation date any store has seen
+    pub created_at: DateTime<Utc>,
+    pub label: Lww<String>,
+    pub description: Lww<Option<String>>,
+    pub container: Lww<Option<Uuid>>,
+    pub tags: OrSet,
+    pub deleted: Lww<bool>,
+    /// When this replica last changed in this store, to find what goes in a delta
+    pub changed_at: DateTime<Utc>,
+}
+
+impl Replica {
+    fn new(bookmark: &Bookmark, at: DateTime<Utc>, actor: Uuid) -> Self {
+        let mut tags = OrSet::default();
+        for tag in &bookmark.tags {
+            tags.add(tag);
+        }
+        Self {
+            link: bookmark.link.clone(),
+            created_at: bookmark.created_at,
+            label: Lww::new(bookmark.label.clone(), at, actor),
+            description: Lww::new(bookmark.description.clone(), at, actor),
+            container: Lww::new(bookmark.container, at, actor),
+            tags,
+            deleted: Lww::new(false, at, actor),
+            changed_at: Utc::now(),
+        }
+    }
+
+    /// Merge `other` into this replica, returning whether anything changed
+    pub fn merge(&mut self, other: &Replica) -> bool {
+        let before = self.clone();
+        self.created_at = self.created_at.min(other.created_at);
+        self.label.merge(&other.label);
+        self.description.merge(&other.description);
+        self.container.merge(&other.container);
+        self.tags.merge(&other.tags);
+        self.deleted.merge(&other.deleted);
+        let changed = *self != before;
+        if changed {
+            self.changed_at = Utc::now();
+        }
+        changed
+    }
+
+    /// The bookmark as the replica currently has it, or `None` if it's deleted.
+    /// Tags are sorted since the set doesn't keep their order.
+    pub fn bookmark(&self) -> Option<Bookmark> {
+        if self.deleted.value {
+            return None;
+        }
+        Some(Bookmark {
+            container: self.container.value,
+            created_at: self.created_at,
+            description: self.description.value.clone(),
+            label: self.label.value.clone(),
+            link: self.link.clone(),
+            tags: self.tags.elements(),
+        })
+    }
+}
+
+/// Bring `replicas` up to date with the `local` versions of the bookmarks,
+/// writing any differences as `actor`. A folder that doesn't exist in this
+/// store isn't overwritten, since folders aren't replicated. Returns the
+/// links of the replicas that changed.
+pub fn observe(
+    replicas: &mut HashMap<String, Replica>,
+    local: &HashMap<String, Version>,
+    folders: &HashSet<Uuid>,
+    actor: Uuid,
+) -> Vec<String> {
+    let mut changed = Vec::new();
+    for (key, version) in local {
+        let replica = replicas.get_mut(key);
+        let (bookmark, at) = match (version, replica) {
+            (Version::Present(bookmark, at), None) => {
+                replicas.insert(key.clone(), Replica::new(bookmark, *at, actor));
+                changed.push(key.clone());
+                continue;
+            }
+            (Version::Deleted(_), None) => continue,
+            (Version::Deleted(at), Some(replica)) => {
+                if !replica.deleted.value {
+                    replica.deleted.set(true, *at, actor);
+                    replica.changed_at = Utc::now();
+                    changed.push(key.clone());
+                }
+                continue;
+            }
+            (Version::Present(bookmark, at), Some(_)) => (bookmark, *at),
+        };
+
+        let replica = replicas.get_mut(key).unwrap();
+        let before = replica.clone();
+        if replica.deleted.value {
+            replica.deleted.set(false, at, actor);
+        }
+        if replica.label.value != bookmark.label {
+            replica.label.set(bookmark.label.clone(), at, actor);
+        }
+        if replica.description.value != bookmark.description {
+            replica
+                .description
+                .set(bookmark.description.clone(), at, actor);
+        }
+        let known = |id: &Option<Uuid>| id.is_none_or(|id| folders.contains(&id));
+        if replica.container.value != bookmark.container && known(&replica.container.value) {
+            replica.container.set(bookmark.container, at, actor);
+        }
+        for tag in &bookmark.tags {
+            if !replica.tags.contains(tag) {
+                replica.tags.add(tag);
+            }
+        }
+        for tag in replica.tags.elements() {
+            if !bookmark.tags.contains(&tag) {
+                replica.tags.remove(&tag);
+            }
+        }
+        replica.created_at = replica.created_at.min(bookmark.created_at);
+
+        if *replica != before {
+            replica.changed_at = Utc::now();
+            changed.push(key.clone());
+        }
+    }
+    changed
+}
+
+/// The replicas changed since some time, as exchanged between stores
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Delta {
+    pub version: u32,
+    /// The store it was exported from
+    pub actor: Uuid,
+    pub exported_at: DateTime<Utc>,
+    pub bookmarks: Vec<Replica>,
+}
@@ -0,0 +1,348 @@
+//! The REST API of `tinymark serve`.
+//!
+//! Bookmarks, folders and tags are read and written as the same JSON that
+//! `--json` prints, and every response is in the same envelope too, see
+//! `output`. Failures have a matching status code.
+//!
+//! - `GET /bookmarks`, optionally narrowed with `?q=`, `?tag=` and `?folder=<id>`
+//! - `POST /bookmarks` with a bookmark, whose `created_at` is optional
+//! - `GET`, `PUT` and `DELETE /bookmarks/<link>`, with the link percent-encoded
+//! - `GET` and `POST /containers`, and `DELETE /containers/<id>`
+//! - `GET /tags`
+//! - `GET /search?q=`
+//!
+//! Bodies have to be sent as `application/json`, and browsers only get answers for pages
+//! served by the server itself or an origin passed with `--allow-origin`. Otherwise any page
+//! could change the bookmarks with a form or a `text/plain` request, which skip CORS checks.
+
+use crate::commands;
+use crate::database;
+use crate::errors::{ErrorCode, Failure};
+use crate::output;
+use crate::structures::{Bookmark, Container, ContainerTypes, Keyspace, Trashed};
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tiny_http::{Header, Server};
+use url::Url;
+use uuid::Uuid;
+
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+/// A response in the envelope `--json` prints, with `extra` added to its `meta`
+fn success(status: u16, data: impl Serialize, extra: Value) -> Response {
+    Response {
+        status,
+        body: output::success(data, extra),
+    }
+}
+
+fn fail(status: u16, reason: &str) -> Response {
+    let code = match status {
+        404 => ErrorCode::NotFound,
+        409 | 422 => ErrorCode::InvalidData,
+        _ => ErrorCode::Usage,
+    };
+    Response {
+        status,
+        body: output::failure(code, reason),
+    }
+}
+
+/// A request the store couldn't answer, like when it's busy or an entry is broken
+fn failed(failure: Failure) -> Response {
+    let status = match failure.code {
+        ErrorCode::Busy | ErrorCode::Unavailable => 503,
+        _ => 500,
+    };
+    Response {
+        status,
+        body: output::failure(failure.code, &failure.reason),
+    }
+}
+
+/// A bookmark sent by a client
+#[derive(Deserialize)]
+struct BookmarkBody {
+    link: Url,
+    label: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    container: Option<Uuid>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// A folder sent by a client, which gets a new id
+#[derive(Deserialize)]
+struct ContainerBody {
+    label: String,
+    #[serde(default)]
+    container: Option<Uuid>,
+}
+
+fn parse_body<'a, T: serde::Deserialize<'a>>(body: &'a str) -> Result<T, Response> {
+    serde_json::from_str(body).map_err(|e| fail(400, &format!("invalid body: {}", e)))
+}
+
+fn folder_exists(id: &Option<Uuid>, json: bool, path: &Option<PathBuf>) -> Result<bool, Response> {
+    match id {
+        Some(id) => Ok(database::try_get_containers(json, path.clone())
+            .map_err(failed)?
+            .iter()
+            .any(|c| c.id == *id)),
+        None => Ok(true),
+    }
+}
+
+/// Answer a single request, `url` being the path and query the client asked for
+pub fn handle(method: &str, url: &str, body: &str, json: bool, path: &Option<PathBuf>) -> Response {
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(url)) {
+        Ok(url) => url,
+        Err(e) => return fail(400, &format!("invalid url: {}", e)),
+    };
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    let collection = segments.first().map_or("", String::as_str);
+    // Links that weren't percent-encoded are split on their slashes
+    let item = (segments.len() > 1).then(|| segments[1..].join("/"));
+
+    let result = match (method, collection, item) {
+        ("GET", "bookmarks", None) => list_bookmarks(&query, json, path),
+        ("POST", "bookmarks", None) => create_bookmark(body, json, path),
+        ("GET", "bookmarks", Some(link)) => {
+            get_bookmark(&link, json, path).map(|b| success(200, b, Value::Null))
+        }
+        ("PUT", "bookmarks", Some(link)) => update_bookmark(&link, body, json, path),
+        ("DELETE", "bookmarks", Some(link)) => delete_bookmark(&link, json, path),
+        ("GET", "containers", None) => database::try_get_containers(json, path.clone())
+            .map(|containers| success(200, containers, Value::Null))
+            .map_err(failed),
+        ("POST", "containers", None) => create_container(body, json, path),
+        ("DELETE", "containers", Some(id)) => delete_container(&id, json, path),
+        ("GET", "tags", None) => commands::try_tag_counts(json, path.clone())
+            .map(|tags| success(200, tags, Value::Null))
+            .map_err(failed),
+        ("GET", "search", None) => match query.get("q") {
+            Some(_) => list_bookmarks(&query, json, path),
+            None => Err(fail(400, "missing the q parameter")),
+        },
+        (_, "bookmarks" | "containers" | "tags" | "search", _) => {
+            Err(fail(405, &format!("{} is not allowed here", method)))
+        }
+        _ => Err(fail(404, &format!("{} not found", url.path()))),
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+fn list_bookmarks(
+    query: &HashMap<String, String>,
+    json: bool,
+    path: &Option<PathBuf>,
+) -> Result<Response, Response> {
+    let folder = query.get("folder").and_then(|id| Uuid::parse_str(id).ok());
+    let mut bookmarks = Vec::new();
+    database::try_for_each(json, path.clone(), Keyspace::Bookmarks, |bookmark| {
+        let matches = query.get("q").is_none_or(|q| bookmark.matches(q))
+            && query
+                .get("tag")
+                .is_none_or(|tag| bookmark.tags.contains(tag))
+            && (folder.is_none() || bookmark.container == folder);
+        if matches {
+            bookmarks.push(bookmark);
+        }
+    })
+    .map_err(failed)?;
+    Ok(success(200, bookmarks, Value::Null))
+}
+
+fn get_bookmark(link: &str, json: bool, path: &Option<PathBuf>) -> Result<Bookmark, Response> {
+    let link = Url::parse(link).map_err(|e| fail(400, &format!("invalid link: {}", e)))?;
+    database::try_get_entry(&link, json, path.clone(), Keyspace::Bookmarks)
+        .map_err(failed)?
+        .ok_or_else(|| fail(404, &format!("entry {} not found", link)))
+}
+
+/// Check the folder exists and build the bookmark to store
+fn to_bookmark(
+    body: BookmarkBody,
+    created_at: DateTime<Utc>,
+    json: bool,
+    path: &Option<PathBuf>,
+) -> Result<Bookmark, Response> {
+    if !folder_exists(&body.container, json, path)? {
+        return Err(fail(
+            422,
+            &format!("folder {} not found", body.container.unwrap()),
+        ));
+    }
+    Ok(Bookmark {
+        container: body.container,
+        created_at: body.created_at.unwrap_or(created_at),
+        description: body.description,
+        label: body.label,
+        link: body.link,
+        tags: body.tags,
+    })
+}
+
+fn create_bookmark(body: &str, json: bool, path: &Option<PathBuf>) -> Result<Response, Response> {
+    let body: BookmarkBody = parse_body(body)?;
+    if database::try_get_entry(&body.link, json, path.clone(), Keyspace::Bookmarks)
+        .map_err(failed)?
+        .is_some()
+    {
+        return Err(fail(
+            409,
+            &format!("entry {} already exists, use PUT to change it", body.link),
+        ));
+    }
+    let bookmark = to_bookmark(body, Utc::now(), json, path)?;
+    database::try_write_entry(json, path.clone(), Keyspace::Bookmarks, &bookmark)
+        .map_err(failed)?;
+    Ok(success(201, bookmark, Value::Null))
+}
+
+fn update_bookmark(
+    link: &str,
+    body: &str,
+    json: bool,
+    path: &Option<PathBuf>,
+) -> Result<Response, Response> {
+    let current = get_bookmark(link, json, path)?;
+    let body: BookmarkBody = parse_body(body)?;
+    if body.link != current.link {
+        return Err(fail(422, "the link of a bookmark can't be changed"));
+    }
+    let bookmark = to_bookmark(body, current.created_at, json, path)?;
+    database::try_write_entry(json, path.clone(), Keyspace::Bookmarks, &bookmark)
+        .map_err(failed)?;
+    Ok(success(200, bookmark, Value::Null))
+}
+
+fn delete_bookmark(link: &str, json: bool, path: &Option<PathBuf>) -> Result<Response, Response> {
+    let current = get_bookmark(link, json, path)?;
+    let removed = database::try_trash_item(
+        current.link.as_str(),
+        json,
+        path.clone(),
+        Keyspace::Bookmarks,
+    )
+    .map_err(failed)?;
+    let removed = match removed {
+        Some(Trashed::Bookmark(bookmark)) => bookmark,
+        _ => return Err(fail(404, &format!("entry {} not found", current.link))),
+    };
+    let reason = format!("moved entry {} to the trash", removed.link);
+    Ok(success(200, [removed], json!({ "reason": reason })))
+}
+
+fn create_container(body: &str, json: bool, path: &Option<PathBuf>) -> Result<Response, Response> {
+    let body: ContainerBody = parse_body(body)?;
+    if !folder_exists(&body.container, json, path)? {
+        return Err(fail(
+            422,
+            &format!("folder {} not found", body.container.unwrap()),
+        ));
+    }
+    let folder = Container::new(
+        body.container,
+        ContainerTypes::Folder,
+        Uuid::new_v4(),
+        body.label,
+    );
+    database::try_insert_container(json, path.clone(), &folder).map_err(failed)?;
+    Ok(success(201, folder, Value::Null))
+}
+
+fn delete_container(id: &str, json: bool, path: &Option<PathBuf>) -> Result<Response, Response> {
+    let id = Uuid::parse_str(id).map_err(|e| fail(400, &format!("invalid id: {}", e)))?;
+    let removed = database::try_trash_folder(&id, json, path.clone()).map_err(failed)?;
+    if removed.is_empty() {
+        return Err(fail(404, &format!("folder {} not found", id)));
+    }
+    let reason = format!("moved folder {} and what's in it to the trash", id);
+    Ok(success(200, removed, json!({ "reason": reason })))
+}
+
+/// Refuse requests from other web pages, and bodies that aren't JSON
+pub fn check(
+    method: &str,
+    body: &str,
+    content_type: Option<&str>,
+    origin: Option<&str>,
+    host: Option<&str>,
+    allowed_origins: &[String],
+) -> Result<(), Response> {
+    // Only browsers send an origin, other clients can't be tricked into a request
+    if let Some(origin) = origin {
+        let same = |other: &str| origin.eq_ignore_ascii_case(other.trim_end_matches('/'));
+        let own = host.map(|host| format!("http://{}", host));
+        if !own.iter().chain(allowed_origins).any(|other| same(other)) {
+            return Err(fail(
+                403,
+                &format!("requests from {} are not allowed", origin),
+            ));
+        }
+    }
+    if !body.is_empty() || matches!(method, "POST" | "PUT") {
+        let media_type = content_type.and_then(|value| value.split(';').next());
+        if !media_type.is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json")) {
+            return Err(fail(415, "the body has to be sent as application/json"));
+        }
+    }
+    Ok(())
+}
+
+/// Answer requests one at a time until the process is stopped
+pub fn run(server: Server, allowed_origins: &[String], json: bool, path: Option<PathBuf>) {
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    for mut request in server.incoming_requests() {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(name))
+                .map(|header| header.value.as_str().to_string())
+        };
+        let (sent_type, origin, host) = (header("Content-Type"), header("Origin"), header("Host"));
+        let method = request.method().as_str().to_string();
+        let mut body = String::new();
+        let response = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => match check(
+                &method,
+                &body,
+                sent_type.as_deref(),
+                origin.as_deref(),
+                host.as_deref(),
+                allowed_origins,
+            ) {
+                Ok(()) => handle(&method, request.url(), &body, json, &path),
+                Err(refused) => refused,
+            },
+            Err(e) => fail(400, &format!("error reading the body: {}", e)),
+        };
+        let reply = tiny_http::Response::from_string(response.body.to_string())
+            .with_status_code(response.status)
+            .with_header(content_type.clone());
+        // The client going away isn't our problem
+        let _ = request.respond(reply);
+    }
+}
@@ -0,0 +1,359 @@
+//! Readers for the exports of other bookmarking services.
+//!
+//! - Pinboard's JSON export, an array of posts with space separated tags
+//! - Raindrop.io's CSV export, where each bookmark's collection becomes a folder
+//! - Pocket's HTML export, where the "Unread" and "Read Archive" lists become folders
+//! - Linkding's JSON from its bookmarks API, either a page of results or a bare array
+//!
+//! Like the outlines, these give every folder a fresh id and list parents
+//! before their children, see `commands::match_folders`.
+
+use crate::structures::{Bookmark, Container, ContainerTypes};
+use chrono::{DateTime, TimeZone, Utc};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use url::Url;
+use uuid::Uuid;
+
+type Parsed = (Vec<Container>, Vec<Bookmark>);
+
+/// Creates folders by their path, reusing ones that were already created
+#[derive(Default)]
+struct Folders {
+    containers: Vec<Container>,
+    ids: HashMap<Vec<String>, Uuid>,
+}
+
+impl Folders {
+    /// The folder at `path`, creating it and any missing parents
+    fn get(&mut self, path: &[&str]) -> Option<Uuid> {
+        let mut parent = None;
+        for depth in 1..=path.len() {
+            let key: Vec<String> = path[..depth].iter().map(|s| s.to_string()).collect();
+            let id = match self.ids.get(&key) {
+                Some(id) => *id,
+                None => {
+                    let id = Uuid::new_v4();
+                    self.containers.push(Container::new(
+                        parent,
+                        ContainerTypes::Folder,
+                        id,
+                        path[depth - 1].to_string(),
+                    ));
+                    self.ids.insert(key, id);
+                    id
+                }
+            };
+            parent = Some(id);
+        }
+        parent
+    }
+}
+
+fn parse_link(link: &str, entry: usize) -> Result<Url, String> {
+    Url::parse(link.trim()).map_err(|e| format!("invalid link in entry {}: {}", entry + 1, e))
+}
+
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date.trim())
+        .map(|d| d.with_timezone(&Utc))
+        .ok()
+}
+
+/// `text` unless it's blank
+fn non_empty(text: &str) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Split tags on `separator`, dropping empty ones and duplicates
+fn split_tags(tags: &str, separator: impl Fn(char) -> bool) -> Vec<String> {
+    let mut split: Vec<String> = Vec::new();
+    for tag in tags.split(separator).map(str::trim) {
+        if !tag.is_empty() && !split.iter().any(|t| t == tag) {
+            split.push(tag.to_string());
+        }
+    }
+    split
+}
+
+#[derive(Deserialize)]
+struct PinboardPost {
+    href: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    extended: String,
+    #[serde(default)]
+    time: String,
+    #[serde(default)]
+    tags: String,
+}
+
+/// Pinboard has no folders, its `description` is the title and `extended` the notes
+pub fn from_pinboard(text: &str) -> Result<Parsed, String> {
+    let posts: Vec<PinboardPost> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let mut bookmarks = Vec::new();
+    for (entry, post) in posts.into_iter().enumerate() {
+        let link = parse_link(&post.href, entry)?;
+        bookmarks.push(Bookmark {
+            container: None,
+            created_at: parse_date(&post.time).unwrap_or_else(Utc::now),
+            description: non_empty(&post.extended),
+            label: non_empty(&post.description).unwrap_or_else(|| link.to_string()),
+            link,
+            tags: split_tags(&post.tags, char::is_whitespace),
+        });
+    }
+    Ok((Vec::new(), bookmarks))
+}
+
+#[derive(Deserialize)]
+struct RaindropRow {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    note: String,
+    #[serde(default)]
+    excerpt: String,
+    #[serde(default)]
+    folder: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    created: String,
+}
+
+/// The note is used as the description, falling back to the excerpt. Nested
+/// collections are written as `Parent/Child` in the folder column.
+pub fn from_raindrop(text: &str) -> Result<Parsed, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut folders = Folders::default();
+    let mut bookmarks = Vec::new();
+    for (entry, row) in reader.deserialize::<RaindropRow>().enumerate() {
+        let row = row.map_err(|e| format!("invalid row {}: {}", entry + 1, e))?;
+        let link = parse_link(&row.url, entry)?;
+        let path: Vec<&str> = row
+            .folder
+            .split('/')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .collect();
+        bookmarks.push(Bookmark {
+            container: folders.get(&path),
+            created_at: parse_date(&row.created).unwrap_or_else(Utc::now),
+            description: non_empty(&row.note).or_else(|| non_empty(&row.excerpt)),
+            label: non_empty(&row.title).unwrap_or_else(|| link.to_string()),
+            link,
+            tags: split_tags(&row.tags, |c| c == ','),
+        });
+    }
+    Ok((folders.containers, bookmarks))
+}
+
+/// Replace the character references Pocket escapes its export with
+fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let decoded = reference.and_then(|name| match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => name
+                    .strip_prefix('#')?
+                    .parse()
+                    .ok()
+                    .and_then(char::from_u32),
+            },
+        });
+        match (reference, decoded) {
+            (Some(name), Some(c)) => {
+                unescaped.push(c);
+                rest = &rest[name.len() + 2..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Split the inside of a tag like `a href="..." tags='x'` into its name and attributes
+fn parse_tag(tag: &str) -> (String, HashMap<String, String>) {
+    let tag = tag.trim().trim_end_matches('/');
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let name = tag[..name_end].to_lowercase();
+    let mut attributes = HashMap::new();
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = rest[..key_end].to_lowercase();
+        rest = rest[key_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (value, remaining) = match value.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let end = value[1..].find(quote).map_or(value.len(), |i| i + 1);
+                        (&value[1..end], value.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                        (&value[..end], &value[end..])
+                    }
+                };
+                rest = remaining.trim_start();
+                unescape_html(value)
+            }
+            None => String::new(),
+        };
+        if !key.is_empty() {
+            attributes.insert(key, value);
+        }
+    }
+    (name, attributes)
+}
+
+/// Each `<h1>` heading starts a folder for the links listed after it
+pub fn from_pocket(text: &str) -> Result<Parsed, String> {
+    let mut folders = Folders::default();
+    let mut bookmarks = Vec::new();
+    let mut folder = None;
+    // The text since the last opened `<h1>` or `<a>`, and that element's attributes
+    let mut open: Option<(String, HashMap<String, String>)> = None;
+    let mut content = String::new();
+
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        content.push_str(&rest[..start]);
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let (name, attributes) = parse_tag(&rest[start + 1..end]);
+        rest = &rest[end + 1..];
+
+        match name.as_str() {
+            "h1" | "a" => {
+                open = Some((name, attributes));
+                content.clear();
+            }
+            "/h1" if matches!(&open, Some((n, _)) if n == "h1") => {
+                let label = unescape_html(content.trim());
+                folder = folders.get(&[label.as_str()]);
+                open = None;
+            }
+            "/a" => {
+                let attributes = match open.take() {
+                    Some((n, attributes)) if n == "a" => attributes,
+                    _ => continue,
+                };
+                let href = match attributes.get("href") {
+                    Some(href) => href,
+                    None => continue,
+                };
+                let link = parse_link(href, bookmarks.len())?;
+                let created_at = attributes
+                    .get("time_added")
+                    .and_then(|t| t.trim().parse().ok())
+                    .and_then(|t| Utc.timestamp_opt(t, 0).single());
+                bookmarks.push(Bookmark {
+                    container: folder,
+                    created_at: created_at.unwrap_or_else(Utc::now),
+                    description: None,
+                    label: non_empty(&unescape_html(&content)).unwrap_or_else(|| link.to_string()),
+                    link,
+                    tags: attributes
+                        .get("tags")
+                        .map(|t| split_tags(t, |c| c == ','))
+                        .unwrap_or_default(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok((folders.containers, bookmarks))
+}
+
+#[derive(Deserialize)]
+struct LinkdingBookmark {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    notes: String,
+    #[serde(default)]
+    website_title: Option<String>,
+    #[serde(default)]
+    website_description: Option<String>,
+    #[serde(default)]
+    is_archived: bool,
+    #[serde(default)]
+    tag_names: Vec<String>,
+    #[serde(default)]
+    date_added: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkdingFile {
+    Page { results: Vec<LinkdingBookmark> },
+    Bookmarks(Vec<LinkdingBookmark>),
+}
+
+/// Titles and descriptions fall back to the ones Linkding scraped from the
+/// website, notes are appended to the description and archived bookmarks go
+/// in an "Archived" folder.
+pub fn from_linkding(text: &str) -> Result<Parsed, String> {
+    let file: LinkdingFile = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let results = match file {
+        LinkdingFile::Page { results } => results,
+        LinkdingFile::Bookmarks(results) => results,
+    };
+
+    let mut folders = Folders::default();
+    let mut bookmarks = Vec::new();
+    for (entry, result) in results.into_iter().enumerate() {
+        let link = parse_link(&result.url, entry)?;
+        let website_description = result.website_description.as_deref().unwrap_or("");
+        let description = non_empty(&result.description).or_else(|| non_empty(website_description));
+        let description = match (description, non_empty(&result.notes)) {
+            (Some(description), Some(notes)) => Some(format!("{}\n\n{}", description, notes)),
+            (description, notes) => description.or(notes),
+        };
+        let label = non_empty(&result.title)
+            .or_else(|| non_empty(result.website_title.as_deref().unwrap_or("")))
+            .unwrap_or_else(|| link.to_string());
+        bookmarks.push(Bookmark {
+            container: if result.is_archived {
+                folders.get(&["Archived"])
+            } else {
+                None
+            },
+            created_at: parse_date(&result.date_added).unwrap_or_else(Utc::now),
+            description,
+            label,
+            link,
+            tags: result.tag_names,
+        });
+    }
+    Ok((folders.containers, bookmarks))
+}
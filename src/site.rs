@@ -0,0 +1,399 @@
+//! Renders the store as a static website for `tinymark publish`.
+//!
+//! The site has an index of everything, a page per tag and per folder, a
+//! `search.json` index used by the client side search and an Atom feed.
+//! Pages are rendered from the templates in [`TEMPLATE_FILES`], which are read
+//! from the templates directory when they exist there and built in otherwise.
+//! Templates use `{{name}}` placeholders, see [`render`]. Bookmarks that
+//! aren't http or https links are left out.
+
+use crate::feed::{self, escape, FeedInfo};
+use crate::structures::{Bookmark, Container};
+use chrono::Local;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// The template files and their built in defaults
+pub const TEMPLATE_FILES: [(&str, &str); 4] = [
+    ("page.html", include_str!("templates/page.html")),
+    ("bookmark.html", include_str!("templates/bookmark.html")),
+    ("style.css", include_str!("templates/style.css")),
+    ("search.js", include_str!("templates/search.js")),
+];
+
+/// How many of the newest bookmarks go in the feed
+const FEED_LENGTH: usize = 50;
+
+pub struct Templates {
+    /// The layout of every page, with `title`, `site_title`, `root`, `content` and `generated_at`
+    page: String,
+    /// A single bookmark, with `label`, `link`, `description`, `tags`, `folder`, `created_at` and `date`
+    bookmark: String,
+    style: String,
+    script: String,
+}
+
+impl Templates {
+    /// Load the templates from `dir`, using the built in ones for any that are missing
+    pub fn load(dir: &Path) -> io::Result<Templates> {
+        let mut loaded = Vec::new();
+        for (name, default) in TEMPLATE_FILES {
+            let path = dir.join(name);
+            if path.exists() {
+                loaded.push(fs::read_to_string(path)?);
+            } else {
+                loaded.push(default.to_string());
+            }
+        }
+        let [page, bookmark, style, script]: [String; 4] = loaded.try_into().unwrap();
+        Ok(Templates {
+            page,
+            bookmark,
+            style,
+            script,
+        })
+    }
+
+    /// Write the built in templates to `dir` so they can be customized,
+    /// leaving existing ones alone. Returns the files that were written.
+    pub fn write_defaults(dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::create_dir_all(dir)?;
+        let mut written = Vec::new();
+        for (name, default) in TEMPLATE_FILES {
+            let path = dir.join(name);
+            if !path.exists() {
+                fs::write(&path, default)?;
+                written.push(path);
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Replace every `{{name}}` in `template` with its value, unknown names are left as they are.
+/// Values are inserted as is, so text has to be escaped first.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match values.iter().find(|(key, _)| *key == name) {
+                    Some((_, value)) => out.push_str(value),
+                    None => out.push_str(&rest[start..start + 2 + end + 2]),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A file name safe version of `text`
+pub fn slug(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-');
+    if slug.is_empty() {
+        "tag".to_string()
+    } else {
+        slug.to_string()
+    }
+}
+
+pub struct Site<'a> {
+    pub title: &'a str,
+    /// Where the site will be served from, used for absolute links in the feed
+    pub base_url: Option<&'a Url>,
+    pub templates: Templates,
+}
+
+struct Renderer<'a> {
+    site: &'a Site<'a>,
+    containers: HashMap<uuid::Uuid, &'a Container>,
+    tag_slugs: BTreeMap<&'a str, String>,
+    generated_at: String,
+}
+
+impl Renderer<'_> {
+    fn page(&self, root: &str, title: &str, content: &str) -> String {
+        render(
+            &self.site.templates.page,
+            &[
+                ("title", &escape(title)),
+                ("site_title", &escape(self.site.title)),
+                ("root", root),
+                ("content", content),
+                ("generated_at", &self.generated_at),
+            ],
+        )
+    }
+
+    fn bookmarks(&self, root: &str, bookmarks: &[&Bookmark]) -> String {
+        let mut out = String::from("<ul class=\"bookmarks\">\n");
+        for b in bookmarks {
+            let tags: Vec<String> = b
+                .tags
+                .iter()
+                .map(|t| {
+                    format!(
+                        "<a href=\"{}tags/{}.html\">#{}</a>",
+                        root,
+                        self.tag_slugs[t.as_str()],
+                        escape(t)
+                    )
+                })
+                .collect();
+            let folder = match b.container.and_then(|id| self.containers.get(&id)) {
+                Some(c) => format!(
+                    "<a href=\"{}folders/{}.html\">{}</a>",
+                    root,
+                    c.id,
+                    escape(&c.label)
+                ),
+                None => String::new(),
+            };
+            out.push_str(&render(
+                &self.site.templates.bookmark,
+                &[
+                    ("label", &escape(&b.label)),
+                    ("link", &escape(b.link.as_str())),
+                    (
+                        "description",
+                        &escape(b.description.as_deref().unwrap_or("")),
+                    ),
+                    ("tags", &tags.join(" ")),
+                    ("folder", &folder),
+                    ("created_at", &b.created_at.to_rfc3339()),
+                    (
+                        "date",
+                        &b.created_at
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d")
+                            .to_string(),
+                    ),
+                ],
+            ));
+        }
+        out.push_str("</ul>\n");
+        out
+    }
+
+    fn folder_list(&self, root: &str, folders: &[&Container]) -> String {
+        if folders.is_empty() {
+            return String::new();
+        }
+        let mut out = String::from("<ul class=\"folders\">\n");
+        for c in folders {
+            out.push_str(&format!(
+                "<li><a href=\"{}folders/{}.html\">{}</a></li>\n",
+                root,
+                c.id,
+                escape(&c.label)
+            ));
+        }
+        out.push_str("</ul>\n");
+        out
+    }
+
+    /// Links to the folders above `container`, outermost first
+    fn breadcrumbs(&self, container: &Container) -> String {
+        let mut trail = Vec::new();
+        let mut seen = HashSet::new();
+        let mut parent = container.container;
+        while let Some(c) = parent.and_then(|id| self.containers.get(&id)) {
+            if !seen.insert(c.id) {
+                break;
+            }
+            trail.push(format!(
+                "<a href=\"{}.html\">{}</a>",
+                c.id,
+                escape(&c.label)
+            ));
+            parent = c.container;
+        }
+        trail.reverse();
+        if trail.is_empty() {
+            return String::new();
+        }
+        format!("<nav class=\"breadcrumbs\">{}</nav>\n", trail.join(" / "))
+    }
+}
+
+/// Write the site into `dir`, returning the number of pages
+pub fn publish(
+    dir: &Path,
+    site: &Site,
+    containers: &[Container],
+    bookmarks: &[Bookmark],
+) -> io::Result<usize> {
+    fs::create_dir_all(dir.join("tags"))?;
+    fs::create_dir_all(dir.join("folders"))?;
+
+    // A `javascript:` link would run on the site when clicked, so only web links are shown
+    let bookmarks: Vec<Bookmark> = bookmarks
+        .iter()
+        .filter(|b| matches!(b.link.scheme(), "http" | "https"))
+        .cloned()
+        .collect();
+    let bookmarks = &bookmarks[..];
+
+    let mut sorted: Vec<&Bookmark> = bookmarks.iter().collect();
+    sorted.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+
+    let mut tag_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for b in bookmarks {
+        for t in &b.tags {
+            *tag_counts.entry(t.as_str()).or_default() += 1;
+        }
+    }
+    let mut used = HashSet::new();
+    let mut tag_slugs = BTreeMap::new();
+    for tag in tag_counts.keys() {
+        let base = slug(tag);
+        let mut candidate = base.clone();
+        let mut n = 1;
+        while !used.insert(candidate.clone()) {
+            n += 1;
+            candidate = format!("{}-{}", base, n);
+        }
+        tag_slugs.insert(*tag, candidate);
+    }
+
+    let renderer = Renderer {
+        site,
+        containers: containers.iter().map(|c| (c.id, c)).collect(),
+        tag_slugs,
+        generated_at: Local::now().format("%Y-%m-%d %H:%M").to_string(),
+    };
+    let is_root = |parent: &Option<uuid::Uuid>| {
+        parent.is_none_or(|id| !renderer.containers.contains_key(&id))
+    };
+    let mut pages = 0;
+
+    // The index lists the top level folders, every tag and all bookmarks
+    let mut folders: Vec<&Container> = containers
+        .iter()
+        .filter(|c| is_root(&c.container))
+        .collect();
+    folders.sort_by(|a, b| a.label.cmp(&b.label));
+    let mut content = String::new();
+    if !folders.is_empty() {
+        content.push_str("<h2>Folders</h2>\n");
+        content.push_str(&renderer.folder_list("", &folders));
+    }
+    if !tag_counts.is_empty() {
+        content.push_str("<h2>Tags</h2>\n<ul class=\"tag-list\">\n");
+        for (tag, count) in &tag_counts {
+            content.push_str(&format!(
+                "<li><a href=\"tags/{}.html\">#{}</a> ({})</li>\n",
+                renderer.tag_slugs[tag],
+                escape(tag),
+                count
+            ));
+        }
+        content.push_str("</ul>\n");
+    }
+    content.push_str("<h2>Recent</h2>\n");
+    content.push_str(&renderer.bookmarks("", &sorted));
+    fs::write(
+        dir.join("index.html"),
+        renderer.page("", "Bookmarks", &content),
+    )?;
+    pages += 1;
+
+    for (tag, slug) in &renderer.tag_slugs {
+        let tagged: Vec<&Bookmark> = sorted
+            .iter()
+            .copied()
+            .filter(|b| b.tags.iter().any(|t| t == tag))
+            .collect();
+        let page = renderer.page(
+            "../",
+            &format!("#{}", tag),
+            &renderer.bookmarks("../", &tagged),
+        );
+        fs::write(dir.join("tags").join(format!("{}.html", slug)), page)?;
+        pages += 1;
+    }
+
+    for container in containers {
+        let mut children: Vec<&Container> = containers
+            .iter()
+            .filter(|c| c.container == Some(container.id))
+            .collect();
+        children.sort_by(|a, b| a.label.cmp(&b.label));
+        let inside: Vec<&Bookmark> = sorted
+            .iter()
+            .copied()
+            .filter(|b| b.container == Some(container.id))
+            .collect();
+
+        let mut content = renderer.breadcrumbs(container);
+        content.push_str(&renderer.folder_list("../", &children));
+        content.push_str(&renderer.bookmarks("../", &inside));
+        let page = renderer.page("../", &container.label, &content);
+        fs::write(
+            dir.join("folders").join(format!("{}.html", container.id)),
+            page,
+        )?;
+        pages += 1;
+    }
+
+    let index: Vec<serde_json::Value> = sorted
+        .iter()
+        .map(|b| {
+            json!({
+                "label": b.label,
+                "link": b.link,
+                "description": b.description,
+                "tags": b.tags,
+                "folder": b.container.and_then(|id| renderer.containers.get(&id)).map(|c| &c.label),
+            })
+        })
+        .collect();
+    fs::write(dir.join("search.json"), serde_json::to_string(&index)?)?;
+
+    let feed_id = match site.base_url {
+        Some(url) => url
+            .join("feed.atom")
+            .map(|u| u.to_string())
+            .unwrap_or_default(),
+        None => format!("urn:tinymark:{}", slug(site.title)),
+    };
+    let feed_link = site
+        .base_url
+        .and_then(|url| url.join("index.html").ok())
+        .map(|u| u.to_string());
+    let recent = feed::recent(bookmarks.to_vec(), None, FEED_LENGTH);
+    let info = FeedInfo {
+        title: site.title,
+        id: &feed_id,
+        link: feed_link.as_deref(),
+        description: "Recently added bookmarks",
+    };
+    fs::write(dir.join("feed.atom"), feed::atom(&info, &recent))?;
+
+    fs::write(dir.join("style.css"), &site.templates.style)?;
+    fs::write(dir.join("search.js"), &site.templates.script)?;
+
+    Ok(pages)
+}
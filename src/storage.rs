@@ -0,0 +1,602 @@
+//! The backends a store can be kept in, behind the [`Storage`] trait.
+//!
+//! A store is a set of sorted key-value trees, one per [`Keyspace`]. sled keeps
+//! them in a directory, SQLite in a single file, and [`MemoryStorage`] only
+//! until the process exits, which is what the tests use. Keys are compared
+//! byte by byte everywhere, so every backend iterates in the same order.
+
+use crate::structures::{Backend, Keyspace};
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError,
+};
+use sled::Transactional;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A key and its value
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// A write to one key, which removes it when there's no value
+pub type Write = (Keyspace, Vec<u8>, Option<Vec<u8>>);
+
+/// How many entries the scans of SQLite and memory stores read at a time
+const SCAN_PAGE: usize = 256;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The backend couldn't read or write
+    Io(String),
+    /// A transaction gave up, and nothing it wrote was kept
+    Abort(String),
+    /// Another process has the store open and didn't close it in time
+    Locked,
+    /// Another change got there first, so trying again can work
+    Conflict,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(reason) | StorageError::Abort(reason) => write!(f, "{}", reason),
+            StorageError::Locked => write!(f, "the store is in use by another process"),
+            StorageError::Conflict => write!(f, "another change was made at the same time"),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+pub trait Storage: Send + Sync {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, returning what was there before
+    fn put(&self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Remove `key`, returning what was there before
+    fn delete(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// The entries whose keys start with `prefix`, in key order
+    fn scan<'a>(
+        &'a self,
+        keyspace: Keyspace,
+        prefix: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+    /// The entry with the largest key
+    fn last(&self, keyspace: Keyspace) -> Result<Option<Entry>> {
+        self.scan(keyspace, &[]).last().transpose()
+    }
+
+    /// Apply all of `writes`, or none of them if one fails
+    fn batch(&self, writes: Vec<Write>) -> Result<()>;
+
+    /// Run `f` as one atomic change across all the trees. Nothing it wrote is
+    /// kept when it fails, and it can be run again if another transaction got
+    /// in the way, so it shouldn't keep state between runs.
+    fn transaction(&self, f: &mut dyn FnMut(&mut dyn Transaction) -> Result<()>) -> Result<()>;
+}
+
+/// Scan a page at a time, so big stores aren't read into memory at once.
+/// `page` gets the key the last page ended at, or `None` for the first, and
+/// returns the next entries starting with the prefix, up to [`SCAN_PAGE`] of them.
+fn paged<'a>(
+    mut page: impl FnMut(Option<&[u8]>) -> Result<Vec<Entry>> + 'a,
+) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+    let mut after: Option<Vec<u8>> = None;
+    let mut entries = Vec::new().into_iter();
+    let mut done = false;
+    Box::new(std::iter::from_fn(move || {
+        if let Some(entry) = entries.next() {
+            return Some(Ok(entry));
+        }
+        if done {
+            return None;
+        }
+        match page(after.as_deref()) {
+            Ok(next) => {
+                done = next.len() < SCAN_PAGE;
+                after = next.last().map(|(key, _)| key.clone());
+                entries = next.into_iter();
+                entries.next().map(Ok)
+            }
+            Err(error) => {
+                done = true;
+                Some(Err(error))
+            }
+        }
+    }))
+}
+
+/// The reads and writes inside [`Storage::transaction`]
+pub trait Transaction {
+    fn get(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put(&mut self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn delete(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+/// Open the store at `path` kept in `backend`, creating it if it doesn't exist,
+/// and waiting up to `wait` for another process to close it
+pub fn open(backend: Backend, path: &Path, wait: Duration) -> Result<Arc<dyn Storage>> {
+    Ok(match backend {
+        Backend::Sled => Arc::new(SledStorage::open(path, wait)?),
+        Backend::Sqlite => Arc::new(SqliteStorage::open(path, wait)?),
+    })
+}
+
+/// How often to check whether a locked sled store has been closed
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+fn sled_error(error: sled::Error) -> StorageError {
+    StorageError::Io(error.to_string())
+}
+
+/// sled only tells a held lock apart by its message
+fn is_locked(error: &sled::Error) -> bool {
+    matches!(error, sled::Error::Io(e) if e.to_string().starts_with("could not acquire lock"))
+}
+
+impl SledStorage {
+    pub fn open(path: &Path, wait: Duration) -> Result<Self> {
+        let deadline = Instant::now() + wait;
+        loop {
+            match sled::open(path) {
+                Ok(db) => return Ok(SledStorage { db }),
+                Err(error) if is_locked(&error) => {
+                    if Instant::now() >= deadline {
+                        return Err(StorageError::Locked);
+                    }
+                    std::thread::sleep(LOCK_POLL);
+                }
+                Err(error) => return Err(sled_error(error)),
+            }
+        }
+    }
+
+    fn tree(&self, keyspace: Keyspace) -> Result<sled::Tree> {
+        self.db.open_tree(keyspace.name()).map_err(sled_error)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush().map(|_| ()).map_err(sled_error)
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = self.tree(keyspace)?.get(key).map_err(sled_error)?;
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    fn put(&self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let before = self
+            .tree(keyspace)?
+            .insert(key, value)
+            .map_err(sled_error)?;
+        self.flush()?;
+        Ok(before.map(|v| v.to_vec()))
+    }
+
+    fn delete(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let before = self.tree(keyspace)?.remove(key).map_err(sled_error)?;
+        self.flush()?;
+        Ok(before.map(|v| v.to_vec()))
+    }
+
+    fn scan<'a>(
+        &'a self,
+        keyspace: Keyspace,
+        prefix: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+        match self.tree(keyspace) {
+            Ok(tree) => Box::new(tree.scan_prefix(prefix).map(|pair| {
+                pair.map(|(k, v)| (k.to_vec(), v.to_vec()))
+                    .map_err(sled_error)
+            })),
+            Err(error) => Box::new(std::iter::once(Err(error))),
+        }
+    }
+
+    fn last(&self, keyspace: Keyspace) -> Result<Option<Entry>> {
+        let last = self.tree(keyspace)?.last().map_err(sled_error)?;
+        Ok(last.map(|(k, v)| (k.to_vec(), v.to_vec())))
+    }
+
+    fn batch(&self, writes: Vec<Write>) -> Result<()> {
+        // sled batches are atomic within a single tree only
+        let keyspace = match writes.first() {
+            Some((keyspace, _, _)) => *keyspace,
+            None => return Ok(()),
+        };
+        if writes.iter().any(|(k, _, _)| *k != keyspace) {
+            return self.transaction(&mut |transaction| {
+                for (keyspace, key, value) in &writes {
+                    match value {
+                        Some(value) => transaction.put(*keyspace, key, value)?,
+                        None => transaction.delete(*keyspace, key)?,
+                    };
+                }
+                Ok(())
+            });
+        }
+
+        let mut batch = sled::Batch::default();
+        for (_, key, value) in writes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.tree(keyspace)?
+            .apply_batch(batch)
+            .map_err(sled_error)?;
+        self.flush()
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&mut dyn Transaction) -> Result<()>) -> Result<()> {
+        let trees = Keyspace::ALL
+            .iter()
+            .map(|keyspace| self.tree(*keyspace))
+            .collect::<Result<Vec<_>>>()?;
+
+        // sled wants a closure it can call again, which `f` can be through this
+        let f = RefCell::new(f);
+        let result = trees.transaction(|trees| {
+            let mut transaction = SledTransaction {
+                trees,
+                failure: None,
+            };
+            match (f.borrow_mut())(&mut transaction) {
+                Ok(()) => Ok(()),
+                Err(error) => match transaction.failure {
+                    // Lets sled retry after a conflict
+                    Some(failure) => Err(failure.into()),
+                    None => Err(ConflictableTransactionError::Abort(error)),
+                },
+            }
+        });
+        match result {
+            Ok(()) => self.flush(),
+            Err(TransactionError::Abort(error)) => Err(error),
+            Err(TransactionError::Storage(error)) => Err(sled_error(error)),
+        }
+    }
+}
+
+struct SledTransaction<'a> {
+    trees: &'a [TransactionalTree],
+    /// What went wrong in sled, which it has to be told about
+    failure: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn tree(&self, keyspace: Keyspace) -> &TransactionalTree {
+        let index = Keyspace::ALL.iter().position(|k| *k == keyspace).unwrap();
+        &self.trees[index]
+    }
+
+    fn check(
+        &mut self,
+        result: std::result::Result<Option<sled::IVec>, UnabortableTransactionError>,
+    ) -> Result<Option<Vec<u8>>> {
+        match result {
+            Ok(value) => Ok(value.map(|v| v.to_vec())),
+            Err(error) => {
+                let reason = StorageError::Io(error.to_string());
+                self.failure = Some(error);
+                Err(reason)
+            }
+        }
+    }
+}
+
+impl Transaction for SledTransaction<'_> {
+    fn get(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.tree(keyspace).get(key);
+        self.check(result)
+    }
+
+    fn put(&mut self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.tree(keyspace).insert(key, value);
+        self.check(result)
+    }
+
+    fn delete(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let result = self.tree(keyspace).remove(key);
+        self.check(result)
+    }
+}
+
+type Trees = BTreeMap<&'static str, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+/// A store that's gone when the process exits
+#[derive(Default)]
+pub struct MemoryStorage {
+    trees: Mutex<Trees>,
+}
+
+struct MemoryTransaction<'a>(&'a mut Trees);
+
+impl Transaction for MemoryTransaction<'_> {
+    fn get(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .0
+            .get(keyspace.name())
+            .and_then(|tree| tree.get(key))
+            .cloned())
+    }
+
+    fn put(&mut self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.0.entry(keyspace.name()).or_default();
+        Ok(tree.insert(key.to_vec(), value.to_vec()))
+    }
+
+    fn delete(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let tree = self.0.entry(keyspace.name()).or_default();
+        Ok(tree.remove(key))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        MemoryTransaction(&mut self.trees.lock().unwrap()).get(keyspace, key)
+    }
+
+    fn put(&self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        MemoryTransaction(&mut self.trees.lock().unwrap()).put(keyspace, key, value)
+    }
+
+    fn delete(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        MemoryTransaction(&mut self.trees.lock().unwrap()).delete(keyspace, key)
+    }
+
+    fn scan<'a>(
+        &'a self,
+        keyspace: Keyspace,
+        prefix: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+        let prefix = prefix.to_vec();
+        paged(move |after| {
+            let start = match after {
+                Some(key) => Bound::Excluded(key.to_vec()),
+                None => Bound::Included(prefix.clone()),
+            };
+            let trees = self.trees.lock().unwrap();
+            Ok(trees
+                .get(keyspace.name())
+                .into_iter()
+                .flat_map(|tree| tree.range((start.clone(), Bound::Unbounded)))
+                .take_while(|(key, _)| key.starts_with(&prefix))
+                .take(SCAN_PAGE)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect())
+        })
+    }
+
+    fn batch(&self, writes: Vec<Write>) -> Result<()> {
+        let mut trees = self.trees.lock().unwrap();
+        let mut transaction = MemoryTransaction(&mut trees);
+        for (keyspace, key, value) in writes {
+            match value {
+                Some(value) => transaction.put(keyspace, &key, &value)?,
+                None => transaction.delete(keyspace, &key)?,
+            };
+        }
+        Ok(())
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&mut dyn Transaction) -> Result<()>) -> Result<()> {
+        let mut trees = self.trees.lock().unwrap();
+        // Work on a copy, so a failure leaves everything as it was
+        let mut changed = trees.clone();
+        f(&mut MemoryTransaction(&mut changed))?;
+        *trees = changed;
+        Ok(())
+    }
+}
+
+/// A store in a single SQLite file, which other processes can read and write
+/// at the same time
+pub struct SqliteStorage {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+fn sqlite_error(error: rusqlite::Error) -> StorageError {
+    match &error {
+        rusqlite::Error::SqliteFailure(failure, _)
+            if matches!(
+                failure.code,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+            ) =>
+        {
+            StorageError::Locked
+        }
+        _ => StorageError::Io(error.to_string()),
+    }
+}
+
+fn sqlite_get(
+    connection: &rusqlite::Connection,
+    keyspace: Keyspace,
+    key: &[u8],
+) -> rusqlite::Result<Option<Vec<u8>>> {
+    connection
+        .query_row(
+            "SELECT value FROM entries WHERE keyspace = ?1 AND key = ?2",
+            params![keyspace.name(), key],
+            |row| row.get(0),
+        )
+        .optional()
+}
+
+fn sqlite_put(
+    connection: &rusqlite::Connection,
+    keyspace: Keyspace,
+    key: &[u8],
+    value: &[u8],
+) -> rusqlite::Result<Option<Vec<u8>>> {
+    let before = sqlite_get(connection, keyspace, key)?;
+    connection.execute(
+        "INSERT OR REPLACE INTO entries (keyspace, key, value) VALUES (?1, ?2, ?3)",
+        params![keyspace.name(), key, value],
+    )?;
+    Ok(before)
+}
+
+fn sqlite_delete(
+    connection: &rusqlite::Connection,
+    keyspace: Keyspace,
+    key: &[u8],
+) -> rusqlite::Result<Option<Vec<u8>>> {
+    let before = sqlite_get(connection, keyspace, key)?;
+    connection.execute(
+        "DELETE FROM entries WHERE keyspace = ?1 AND key = ?2",
+        params![keyspace.name(), key],
+    )?;
+    Ok(before)
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path, wait: Duration) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| StorageError::Io(e.to_string()))?;
+        }
+        let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        connection.busy_timeout(wait).map_err(sqlite_error)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (
+                    keyspace TEXT NOT NULL,
+                    key BLOB NOT NULL,
+                    value BLOB NOT NULL,
+                    PRIMARY KEY (keyspace, key)
+                ) WITHOUT ROWID",
+            )
+            .map_err(sqlite_error)?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Run `f` in a transaction that takes the write lock right away
+    fn write<T>(&self, f: impl FnOnce(&rusqlite::Transaction) -> rusqlite::Result<T>) -> Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_error)?;
+        let result = f(&transaction).map_err(sqlite_error)?;
+        transaction.commit().map_err(sqlite_error)?;
+        Ok(result)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        sqlite_get(&self.connection.lock().unwrap(), keyspace, key).map_err(sqlite_error)
+    }
+
+    fn put(&self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.write(|transaction| sqlite_put(transaction, keyspace, key, value))
+    }
+
+    fn delete(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.write(|transaction| sqlite_delete(transaction, keyspace, key))
+    }
+
+    fn scan<'a>(
+        &'a self,
+        keyspace: Keyspace,
+        prefix: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+        let prefix = prefix.to_vec();
+        paged(move |after| {
+            let connection = self.connection.lock().unwrap();
+            let (query, start) = match after {
+                Some(key) => ("key > ?2", key),
+                None => ("key >= ?2", prefix.as_slice()),
+            };
+            let mut statement = connection
+                .prepare_cached(&format!(
+                    "SELECT key, value FROM entries WHERE keyspace = ?1 AND {} ORDER BY key LIMIT ?3",
+                    query
+                ))
+                .map_err(sqlite_error)?;
+            let rows = statement
+                .query_map(params![keyspace.name(), start, SCAN_PAGE], |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?))
+                })
+                .map_err(sqlite_error)?;
+            let mut entries = Vec::new();
+            for row in rows {
+                let (key, value) = row.map_err(sqlite_error)?;
+                if !key.starts_with(&prefix) {
+                    break;
+                }
+                entries.push((key, value));
+            }
+            Ok(entries)
+        })
+    }
+
+    fn last(&self, keyspace: Keyspace) -> Result<Option<Entry>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT key, value FROM entries WHERE keyspace = ?1 ORDER BY key DESC LIMIT 1",
+                params![keyspace.name()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(sqlite_error)
+    }
+
+    fn batch(&self, writes: Vec<Write>) -> Result<()> {
+        self.write(|transaction| {
+            for (keyspace, key, value) in &writes {
+                match value {
+                    Some(value) => sqlite_put(transaction, *keyspace, key, value)?,
+                    None => sqlite_delete(transaction, *keyspace, key)?,
+                };
+            }
+            Ok(())
+        })
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&mut dyn Transaction) -> Result<()>) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_error)?;
+        // Dropping the transaction when `f` fails rolls it back
+        f(&mut SqliteTransaction(&transaction))?;
+        transaction.commit().map_err(sqlite_error)
+    }
+}
+
+struct SqliteTransaction<'a>(&'a rusqlite::Transaction<'a>);
+
+impl Transaction for SqliteTransaction<'_> {
+    fn get(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        sqlite_get(self.0, keyspace, key).map_err(sqlite_error)
+    }
+
+    fn put(&mut self, keyspace: Keyspace, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        sqlite_put(self.0, keyspace, key, value).map_err(sqlite_error)
+    }
+
+    fn delete(&mut self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        sqlite_delete(self.0, keyspace, key).map_err(sqlite_error)
+    }
+}
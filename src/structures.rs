@@ -1,30 +1,93 @@
 use chrono::prelude::*;
-use clap::{AppSettings, Parser, Subcommand};
+use chrono::Duration;
+use clap::{AppSettings, ArgEnum, Parser, Subcommand};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::PathBuf;
 use url::Url;
 use uuid::Uuid;
-use uuid_simd::UuidExt;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The settings in `tinymark.toml`. Tables have to come after the plain
+/// values, or it can't be written as TOML.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub tui: bool,
     pub json: bool,
     pub storage_location: Option<PathBuf>,
+    /// The actor the configured store starts replicating as, which it keeps after that
+    pub actor: Option<Uuid>,
+    /// The profile used when `--profile` isn't given
+    pub default_profile: Option<String>,
+    /// The format of `export` and `import` when there's no `--format` and the
+    /// file's extension doesn't tell, like for `-`
+    pub default_format: Option<FileFormat>,
+    /// The order `list` shows the bookmarks in
+    pub sort: SortOrder,
+    /// What new stores are kept in. Existing ones keep their backend until
+    /// they're converted with `tinymark db convert`
+    pub backend: Backend,
+    /// How links are cleaned up when they're added or imported
+    pub normalize: Normalize,
+    /// Named libraries, each with its own store
+    pub profiles: BTreeMap<String, Profile>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            tui: false,
-            json: false,
-            storage_location: None,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub storage_location: PathBuf,
+    /// Replaces the top level `actor`, since every store needs its own
+    pub actor: Option<Uuid>,
+}
+
+/// Rules applied to the links of new bookmarks, all off by default
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Normalize {
+    /// Drop `utm_*`, `fbclid` and other tracking parameters
+    pub remove_tracking: bool,
+    /// Drop the `#fragment`
+    pub remove_fragment: bool,
+    /// Drop the `/` ending a path, except the root
+    pub remove_trailing_slash: bool,
+}
+
+/// Query parameters only there to track where a visitor came from
+const TRACKING_PARAMETERS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_ga",
+];
+
+impl Normalize {
+    pub fn apply(&self, link: &Url) -> Url {
+        let mut link = link.clone();
+        if self.remove_tracking && link.query().is_some() {
+            let kept: Vec<(String, String)> = link
+                .query_pairs()
+                .into_owned()
+                .filter(|(name, _)| {
+                    !name.starts_with("utm_") && !TRACKING_PARAMETERS.contains(&name.as_str())
+                })
+                .collect();
+            if kept.is_empty() {
+                link.set_query(None);
+            } else {
+                link.query_pairs_mut().clear().extend_pairs(kept);
+            }
+        }
+        if self.remove_fragment {
+            link.set_fragment(None);
         }
+        if self.remove_trailing_slash && link.path().len() > 1 && link.path().ends_with('/') {
+            let path = link.path().trim_end_matches('/').to_string();
+            link.set_path(if path.is_empty() { "/" } else { &path });
+        }
+        link
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Bookmark {
     pub container: Option<Uuid>,
     pub created_at: DateTime<Utc>,
@@ -35,25 +98,54 @@ pub struct Bookmark {
     pub tags: Vec<String>,
 }
 
-fn do_nothing() {
-    //yea
+impl Bookmark {
+    /// Case-insensitive match of `query` against the label, link, description and tags
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.label.to_lowercase().contains(&query)
+            || self.link.as_str().to_lowercase().contains(&query)
+            || self
+                .description
+                .as_ref()
+                .is_some_and(|d| d.to_lowercase().contains(&query))
+            || self.tags.iter().any(|t| t.to_lowercase().contains(&query))
+    }
+
+    /// Combine two copies of the same bookmark, keeping `self`'s label and folder.
+    /// Tags are unioned, the older creation date wins and an empty description
+    /// is replaced by the other one.
+    pub fn merge(&self, other: &Bookmark) -> Bookmark {
+        let mut merged = self.clone();
+        for tag in &other.tags {
+            if !merged.tags.contains(tag) {
+                merged.tags.push(tag.clone());
+            }
+        }
+        merged.created_at = self.created_at.min(other.created_at);
+        if merged.description.as_deref().unwrap_or("").is_empty() {
+            merged.description = other.description.clone();
+        }
+        if merged.container.is_none() {
+            merged.container = other.container;
+        }
+        merged
+    }
 }
 
 impl fmt::Display for Bookmark {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        println!("Bookmark: {}", &self.label);
-        println!("{}", &self.link);
+        writeln!(f, "Bookmark: {}", &self.label)?;
+        writeln!(f, "{}", &self.link)?;
 
-        match &self.description {
-            Some(s) => println!("{}", s),
-            None => do_nothing(),
-        };
+        if let Some(s) = &self.description {
+            writeln!(f, "{}", s)?;
+        }
 
-        print!("Tags: [");
+        write!(f, "Tags: [")?;
         for i in &self.tags {
-            print!("{},", i);
+            write!(f, "{},", i)?;
         }
-        print!("]");
+        write!(f, "]")?;
 
         write!(
             f,
@@ -63,7 +155,7 @@ impl fmt::Display for Bookmark {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Container {
     pub container: Option<Uuid>,
     pub container_type: ContainerTypes,
@@ -87,16 +179,155 @@ impl Container {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ContainerTypes {
     Folder,
     Group,
 }
 
-#[derive(Serialize, Deserialize)]
+/// The version of [`ExportDocument`] written by this build
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Everything in the store, as written by `tinymark export`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportDocument {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub containers: Vec<Container>,
+    /// Derived from the bookmarks, only informational when importing
+    pub tags: Vec<TagInfo>,
+    pub bookmarks: Vec<Bookmark>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagInfo {
+    pub name: String,
+    pub count: usize,
+}
+
+/// Files accepted by `tinymark import`, older versions only exported the bookmarks
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ImportFile {
+    Document(ExportDocument),
+    Bookmarks(Vec<Bookmark>),
+}
+
+/// Something that was deleted and can be restored from the trash
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Trashed {
+    Bookmark(Bookmark),
+    Container(Container),
+}
+
+impl Trashed {
+    /// The key the item is stored under in its own tree
+    pub fn key(&self) -> String {
+        match self {
+            Trashed::Bookmark(b) => b.link.to_string(),
+            Trashed::Container(c) => c.id.to_string(),
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        match self {
+            Trashed::Bookmark(b) => &b.label,
+            Trashed::Container(c) => &c.label,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashEntry {
+    pub deleted_at: DateTime<Utc>,
+    pub item: Trashed,
+}
+
+impl fmt::Display for TrashEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.item {
+            Trashed::Bookmark(_) => "bookmark",
+            Trashed::Container(_) => "folder",
+        };
+        write!(
+            f,
+            "{}  {:<8}  {}  ({})",
+            &self.deleted_at.with_timezone(&Local).to_rfc2822(),
+            kind,
+            self.item.key(),
+            self.item.label()
+        )
+    }
+}
+
+/// When a bookmark was last reconciled with a shared directory, as the time
+/// of the local change and the `updated_at` of the shared file it matched
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncState {
+    pub local: DateTime<Utc>,
+    pub remote: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Keyspace {
     Bookmarks,
     Containers,
+    Trash,
+    Journal,
+    Sync,
+    Replica,
+}
+
+impl Keyspace {
+    pub const ALL: [Keyspace; 6] = [
+        Keyspace::Bookmarks,
+        Keyspace::Containers,
+        Keyspace::Trash,
+        Keyspace::Journal,
+        Keyspace::Sync,
+        Keyspace::Replica,
+    ];
+
+    /// The name of its tree in the store
+    pub fn name(&self) -> &'static str {
+        match self {
+            Keyspace::Bookmarks => "bookmarks",
+            Keyspace::Containers => "containers",
+            Keyspace::Trash => "trash",
+            Keyspace::Journal => "journal",
+            Keyspace::Sync => "sync",
+            Keyspace::Replica => "replica",
+        }
+    }
+}
+
+/// A single key changing in one of the trees, with the serialized values
+/// from before and after the change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    pub keyspace: Keyspace,
+    pub key: String,
+    pub before: Option<Vec<u8>>,
+    pub after: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Change,
+    /// Reverted the operation with this id
+    Undo(u64),
+    /// Reapplied the operation with this id
+    Redo(u64),
+}
+
+/// An entry in the journal of everything that changed the store
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Operation {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    pub description: String,
+    pub kind: OperationKind,
+    pub changes: Vec<Change>,
 }
 /*
 impl Keyspace {
@@ -118,11 +349,44 @@ impl Keyspace {
 }*/
 
 #[derive(Parser)]
+#[clap(version, about, after_long_help = CLI_EXAMPLES)]
 pub struct Cli {
     /// Output as JSON
     #[clap(long)]
     pub json: bool,
 
+    /// Use the store at this path instead of the configured one
+    #[clap(long, global = true, env = "TINYMARK_DB", value_name = "PATH")]
+    pub db: Option<PathBuf>,
+
+    /// Read the configuration from this file instead of the default one
+    #[clap(long, global = true, env = "TINYMARK_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Use the store of this profile instead of the default one
+    #[clap(
+        short,
+        long,
+        global = true,
+        env = "TINYMARK_PROFILE",
+        value_name = "NAME"
+    )]
+    pub profile: Option<String>,
+
+    /// Read a copy of the store, which works while another process has it open
+    #[clap(long, global = true)]
+    pub read_only: bool,
+
+    /// How long to wait for another process to close the store
+    #[clap(
+        long,
+        global = true,
+        env = "TINYMARK_WAIT",
+        value_name = "SECONDS",
+        default_value = "2"
+    )]
+    pub wait: u64,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -131,10 +395,23 @@ pub struct Cli {
 pub enum Commands {
     /// Add a folder
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
-    New_Folder { name: String },
+    #[clap(after_long_help = NEW_FOLDER_EXAMPLES)]
+    NewFolder {
+        name: String,
+
+        /// The folder to create it in
+        #[clap(long)]
+        parent: Option<Uuid>,
+    },
+
+    /// Move a folder and what's in it to the trash
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = DELETE_FOLDER_EXAMPLES)]
+    DeleteFolder { id: Uuid },
 
     /// Add a bookmark
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = ADD_EXAMPLES)]
     Add {
         /// The URL to add
         url: Url,
@@ -147,32 +424,708 @@ pub enum Commands {
 
         /// Optional comma-seperated tags
         tags: Vec<String>,
+
+        /// The folder to add it to
+        #[clap(long)]
+        folder: Option<Uuid>,
     },
 
     /// Edit a bookmark
+    #[clap(after_long_help = EDIT_EXAMPLES)]
     Edit { url: Option<Url> },
 
-    /// Delete a bookmark
+    /// Move one or more bookmarks to the trash
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = DELETE_EXAMPLES)]
     Delete {
-        // The bookmark to delete
-        url: Url,
+        /// The bookmarks to delete
+        #[clap(required_unless_present = "query")]
+        urls: Vec<Url>,
+
+        /// Delete every bookmark matching this search instead
+        #[clap(short, long, conflicts_with = "urls")]
+        query: Option<String>,
+
+        /// Only show what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't ask for confirmation
+        #[clap(short, long)]
+        yes: bool,
     },
 
     /// List all bookmarks
-    List,
+    #[clap(after_long_help = LIST_EXAMPLES)]
+    List {
+        /// The order to list them in, instead of the configured `sort`
+        #[clap(long, arg_enum)]
+        sort: Option<SortOrder>,
+    },
 
-    /// Export the bookmarks to a JSON file
+    /// Export the bookmarks and folders to a JSON file
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = EXPORT_EXAMPLES)]
     Export {
-        /// The output file
+        /// The output file, or `-` for stdout
         file: PathBuf,
+
+        /// The file format, guessed from the extension by default
+        #[clap(short, long, arg_enum)]
+        format: Option<FileFormat>,
     },
 
-    /// Import bookmarks from a JSON file
+    /// Import bookmarks and folders from a file or another service's export
     #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = IMPORT_EXAMPLES)]
     Import {
-        /// The input file
+        /// The input file, or `-` for stdin
+        file: PathBuf,
+
+        /// The file format, guessed from the extension by default
+        #[clap(short, long, arg_enum)]
+        format: Option<FileFormat>,
+
+        /// What to do with bookmarks that already exist
+        #[clap(long, arg_enum, default_value = "overwrite")]
+        on_conflict: ConflictStrategy,
+    },
+
+    /// Render the bookmarks as a static website
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = PUBLISH_EXAMPLES)]
+    Publish {
+        /// The directory to write the site to
+        #[clap(required_unless_present = "init-templates")]
+        dir: Option<PathBuf>,
+
+        /// The title of the site
+        #[clap(long, default_value = "Bookmarks")]
+        title: String,
+
+        /// Where the site will be served from, for absolute links in the feed
+        #[clap(long)]
+        base_url: Option<Url>,
+
+        /// Read templates from here instead of the config directory
+        #[clap(long)]
+        templates: Option<PathBuf>,
+
+        /// Write the built in templates to the templates directory to customize them
+        #[clap(long)]
+        init_templates: bool,
+    },
+
+    /// Print a feed of the most recent bookmarks
+    #[clap(after_long_help = FEED_EXAMPLES)]
+    Feed {
+        /// Only include bookmarks with this tag
+        #[clap(long)]
+        tag: Option<String>,
+
+        /// How many bookmarks to include
+        #[clap(long, default_value = "50")]
+        limit: usize,
+
+        #[clap(short, long, arg_enum, default_value = "atom")]
+        format: FeedFormat,
+
+        /// The title of the feed
+        #[clap(long)]
+        title: Option<String>,
+
+        /// Where the bookmarks can be seen, required for RSS
+        #[clap(long, required_if_eq("format", "rss"))]
+        link: Option<Url>,
+    },
+
+    /// Manage deleted bookmarks and folders
+    #[clap(after_long_help = TRASH_EXAMPLES)]
+    #[clap(subcommand)]
+    Trash(TrashCommands),
+
+    /// Revert the last change
+    #[clap(after_long_help = UNDO_EXAMPLES)]
+    Undo,
+
+    /// Reapply the last undone change
+    #[clap(after_long_help = REDO_EXAMPLES)]
+    Redo,
+
+    /// Reconcile the bookmarks with a shared directory
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = SYNC_EXAMPLES)]
+    Sync {
+        /// The directory, e.g. in a git repository or a Syncthing folder
+        dir: PathBuf,
+    },
+
+    /// Exchange changes with other stores, which always converge
+    #[clap(after_long_help = REPLICATE_EXAMPLES)]
+    #[clap(subcommand)]
+    Replicate(ReplicateCommands),
+
+    /// Manage named libraries, each with its own store
+    #[clap(subcommand, after_long_help = PROFILE_EXAMPLES)]
+    Profile(ProfileCommands),
+
+    /// Show and change the configuration
+    #[clap(subcommand, after_long_help = CONFIG_EXAMPLES)]
+    Config(ConfigCommands),
+
+    /// Manage the store itself
+    #[clap(subcommand, after_long_help = DB_EXAMPLES)]
+    Db(DbCommands),
+
+    /// Serve a REST API for the bookmarks, folders and tags
+    #[clap(after_long_help = SERVE_EXAMPLES)]
+    Serve {
+        /// The address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+
+        /// Another origin whose pages can call the API, like `http://localhost:3000`
+        #[clap(long, value_name = "ORIGIN")]
+        allow_origin: Vec<String>,
+    },
+
+    /// Talk to a browser extension over native messaging
+    #[clap(after_long_help = NATIVE_HOST_EXAMPLES)]
+    NativeHost {
+        /// The caller the browser passes, which isn't checked
+        #[clap(hide = true)]
+        caller: Vec<String>,
+    },
+
+    /// Print a completion script for a shell
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = COMPLETIONS_EXAMPLES)]
+    Completions {
+        #[clap(arg_enum)]
+        shell: CompletionShell,
+    },
+
+    /// Print stored values for the completion scripts, one per line
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(arg_enum)]
+        kind: CompletionKind,
+    },
+
+    /// Print the manual page of tinymark or one of its commands
+    #[clap(after_long_help = MAN_EXAMPLES)]
+    Man {
+        /// The command to show the page of, e.g. `trash empty`
+        command: Vec<String>,
+
+        /// Write the pages of every command to this directory instead
+        #[clap(long, conflicts_with = "command")]
+        out_dir: Option<PathBuf>,
+    },
+
+    /// Print the JSON Schema of the `--json` output
+    #[clap(after_long_help = SCHEMA_EXAMPLES)]
+    Schema,
+
+    /// Show the history of changes
+    #[clap(after_long_help = LOG_EXAMPLES)]
+    Log {
+        /// Only show this many of the most recent changes
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+    },
+}
+
+impl Commands {
+    /// Whether the command changes the store, which `--read-only` refuses
+    pub fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Commands::List { .. }
+                | Commands::Export { .. }
+                | Commands::Publish { .. }
+                | Commands::Feed { .. }
+                | Commands::Trash(TrashCommands::List)
+                | Commands::Profile(ProfileCommands::List | ProfileCommands::Create { .. })
+                | Commands::Config(_)
+                | Commands::Completions { .. }
+                | Commands::Complete { .. }
+                | Commands::Man { .. }
+                | Commands::Schema
+                | Commands::Log { .. }
+        )
+    }
+}
+
+// Examples shown by `--help` and in the man pages
+
+const CLI_EXAMPLES: &str = "EXAMPLES:
+    tinymark add https://example.com Example \"An example site\" web,reference
+    tinymark list
+    tinymark --json delete https://example.com --yes
+    tinymark help add";
+
+const NEW_FOLDER_EXAMPLES: &str = "EXAMPLES:
+    tinymark new-folder Reading
+    tinymark new-folder Rust --parent <id>";
+
+const DELETE_FOLDER_EXAMPLES: &str = "EXAMPLES:
+    tinymark delete-folder <id>
+    tinymark trash restore <id>";
+
+const ADD_EXAMPLES: &str = "EXAMPLES:
+    tinymark add https://example.com Example
+    tinymark add https://example.com Example \"An example site\" web,reference
+    tinymark add https://example.com Example --folder <id>";
+
+const EDIT_EXAMPLES: &str = "EXAMPLES:
+    tinymark edit
+    tinymark edit https://example.com";
+
+const DELETE_EXAMPLES: &str = "EXAMPLES:
+    tinymark delete https://example.com https://example.org
+    tinymark delete --query example --dry-run
+    tinymark delete --query example --yes";
+
+const LIST_EXAMPLES: &str = "EXAMPLES:
+    tinymark list
+    tinymark list --sort newest
+    tinymark --json list
+    tinymark --read-only list";
+
+const EXPORT_EXAMPLES: &str = "EXAMPLES:
+    tinymark export bookmarks.json
+    tinymark export bookmarks.md
+    tinymark export - --format jsonl | gzip > backup.jsonl.gz
+    tinymark export ~/.local/share/buku/bookmarks.db";
+
+const IMPORT_EXAMPLES: &str = "EXAMPLES:
+    tinymark import bookmarks.json --on-conflict merge
+    gunzip -c backup.jsonl.gz | tinymark import - --format jsonl
+    tinymark import pinboard_export.json --format pinboard
+    tinymark import ~/.local/share/buku/bookmarks.db --on-conflict skip";
+
+const PUBLISH_EXAMPLES: &str = "EXAMPLES:
+    tinymark publish site --title \"My bookmarks\" --base-url https://example.com/bookmarks/
+    tinymark publish --init-templates";
+
+const FEED_EXAMPLES: &str = "EXAMPLES:
+    tinymark feed --tag rust --limit 20 > rust.xml
+    tinymark feed --format rss --link https://example.com/bookmarks/";
+
+const TRASH_EXAMPLES: &str = "EXAMPLES:
+    tinymark trash list
+    tinymark trash restore https://example.com
+    tinymark trash empty --older-than 30d";
+
+const TRASH_LIST_EXAMPLES: &str = "EXAMPLES:
+    tinymark trash list
+    tinymark --json trash list";
+
+const TRASH_RESTORE_EXAMPLES: &str = "EXAMPLES:
+    tinymark trash restore https://example.com
+    tinymark trash restore <id>";
+
+const TRASH_EMPTY_EXAMPLES: &str = "EXAMPLES:
+    tinymark trash empty
+    tinymark trash empty --older-than 30d";
+
+const UNDO_EXAMPLES: &str = "EXAMPLES:
+    tinymark log -n 1
+    tinymark undo";
+
+const REDO_EXAMPLES: &str = "EXAMPLES:
+    tinymark undo
+    tinymark redo";
+
+const SYNC_EXAMPLES: &str = "EXAMPLES:
+    tinymark sync ~/Sync/bookmarks
+    git -C ~/bookmarks pull && tinymark sync ~/bookmarks";
+
+const REPLICATE_EXAMPLES: &str = "EXAMPLES:
+    tinymark replicate export-delta delta.json --since 7d
+    tinymark replicate apply-delta delta.json";
+
+const EXPORT_DELTA_EXAMPLES: &str = "EXAMPLES:
+    tinymark replicate export-delta delta.json --since 7d
+    tinymark replicate export-delta - --since 2022-01-01T00:00:00Z | ssh laptop tinymark replicate apply-delta -";
+
+const APPLY_DELTA_EXAMPLES: &str = "EXAMPLES:
+    tinymark replicate apply-delta delta.json
+    ssh desktop tinymark replicate export-delta - | tinymark replicate apply-delta -";
+
+const PROFILE_EXAMPLES: &str = "EXAMPLES:
+    tinymark profile create work
+    tinymark --profile work add https://example.com Example
+    tinymark profile copy-to work --query example";
+
+const PROFILE_LIST_EXAMPLES: &str = "EXAMPLES:
+    tinymark profile list
+    tinymark --json profile list";
+
+const PROFILE_CREATE_EXAMPLES: &str = "EXAMPLES:
+    tinymark profile create personal --default
+    tinymark profile create work --path ~/work/bookmarks";
+
+const PROFILE_COPY_TO_EXAMPLES: &str = "EXAMPLES:
+    tinymark profile copy-to work
+    tinymark --profile personal profile copy-to work --query rust --on-conflict merge";
+
+const CONFIG_EXAMPLES: &str = "EXAMPLES:
+    tinymark config set sort newest
+    tinymark config get normalize
+    tinymark config edit";
+
+const DB_EXAMPLES: &str = "EXAMPLES:
+    tinymark db convert --to sqlite
+    tinymark db convert --to sled --out ~/bookmarks";
+
+const DB_CONVERT_EXAMPLES: &str = "EXAMPLES:
+    tinymark db convert --to sqlite
+    tinymark --profile work db convert --to sqlite --out ~/work.sqlite
+    tinymark --json db convert --to sled";
+
+const CONFIG_GET_EXAMPLES: &str = "EXAMPLES:
+    tinymark config get
+    tinymark config get default_profile
+    tinymark --json config get normalize.remove_tracking";
+
+const CONFIG_SET_EXAMPLES: &str = "EXAMPLES:
+    tinymark config set default_format jsonl
+    tinymark config set normalize.remove_tracking true
+    tinymark config set profiles.work.storage_location ~/work/bookmarks";
+
+const CONFIG_UNSET_EXAMPLES: &str = "EXAMPLES:
+    tinymark config unset default_profile
+    tinymark config unset sort";
+
+const CONFIG_PATH_EXAMPLES: &str = "EXAMPLES:
+    tinymark config path
+    tinymark --config /tmp/tinymark.toml config path";
+
+const CONFIG_EDIT_EXAMPLES: &str = "EXAMPLES:
+    tinymark config edit
+    EDITOR=nano tinymark config edit";
+
+const SERVE_EXAMPLES: &str = "EXAMPLES:
+    tinymark serve
+    tinymark serve --bind 127.0.0.1:9000
+    tinymark serve --allow-origin http://localhost:3000
+    curl 'http://127.0.0.1:8080/search?q=rust'";
+
+const NATIVE_HOST_EXAMPLES: &str = "EXAMPLES:
+    A script to name as the path in the native messaging host manifest:
+        #!/bin/sh
+        exec tinymark native-host \"$@\"";
+
+const COMPLETIONS_EXAMPLES: &str = "EXAMPLES:
+    tinymark completions bash > ~/.local/share/bash-completion/completions/tinymark
+    tinymark completions zsh > ~/.zfunc/_tinymark
+    tinymark completions fish > ~/.config/fish/completions/tinymark.fish";
+
+const MAN_EXAMPLES: &str = "EXAMPLES:
+    tinymark man | man -l -
+    tinymark man trash empty | man -l -
+    tinymark man --out-dir ~/.local/share/man/man1";
+
+const SCHEMA_EXAMPLES: &str = "EXAMPLES:
+    tinymark schema > tinymark.schema.json
+    tinymark --json list | check-jsonschema --schemafile tinymark.schema.json -";
+
+const LOG_EXAMPLES: &str = "EXAMPLES:
+    tinymark log
+    tinymark log -n 10";
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    /// A versioned document with the bookmarks, folders and tags
+    Json,
+    /// One bookmark per line, read and written as a stream
+    Jsonl,
+    /// Nested lists following the folders, with `#tags`
+    Markdown,
+    /// Nested headings following the folders, with `:tags:`
+    Org,
+    /// The bookmarks table of a buku SQLite database
+    Buku,
+    /// Pinboard's JSON export, import only
+    Pinboard,
+    /// Raindrop.io's CSV export, import only
+    Raindrop,
+    /// Pocket's HTML export, import only
+    Pocket,
+    /// Linkding's JSON API results, import only
+    Linkding,
+}
+
+impl FileFormat {
+    /// Formats of other services that tinymark can read but not write
+    pub fn is_import_only(&self) -> bool {
+        matches!(
+            self,
+            FileFormat::Pinboard | FileFormat::Raindrop | FileFormat::Pocket | FileFormat::Linkding
+        )
+    }
+}
+
+/// The order `list` shows the bookmarks in
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// By link, like they're stored
+    #[default]
+    Link,
+    /// By label, ignoring case
+    Label,
+    /// The most recently added first
+    Newest,
+    /// The least recently added first
+    Oldest,
+}
+
+/// What a store is kept in
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A directory managed by sled
+    #[default]
+    Sled,
+    /// A single SQLite file
+    Sqlite,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Sled => "sled",
+            Backend::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl SortOrder {
+    pub fn sort(&self, bookmarks: &mut [Bookmark]) {
+        match self {
+            SortOrder::Link => bookmarks.sort_by(|a, b| a.link.as_str().cmp(b.link.as_str())),
+            SortOrder::Label => bookmarks.sort_by_key(|b| b.label.to_lowercase()),
+            SortOrder::Newest => bookmarks.sort_by_key(|b| std::cmp::Reverse(b.created_at)),
+            SortOrder::Oldest => bookmarks.sort_by_key(|b| b.created_at),
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// The stored values completed by `tinymark __complete`
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    Tags,
+    Urls,
+}
+
+/// How to handle an imported bookmark whose link is already stored
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Keep the stored bookmark
+    Skip,
+    /// Replace the stored bookmark
+    Overwrite,
+    /// Combine both, see [`Bookmark::merge`]
+    Merge,
+    /// Ask for each conflict
+    Ask,
+}
+
+#[derive(Subcommand)]
+pub enum TrashCommands {
+    /// List everything in the trash
+    #[clap(after_long_help = TRASH_LIST_EXAMPLES)]
+    List,
+
+    /// Restore a bookmark or folder from the trash
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = TRASH_RESTORE_EXAMPLES)]
+    Restore {
+        /// The link of the bookmark or the id of the folder
+        id: String,
+    },
+
+    /// Permanently delete items from the trash
+    #[clap(after_long_help = TRASH_EMPTY_EXAMPLES)]
+    Empty {
+        /// Only delete items trashed longer ago than this, e.g. `30d` or `12h`
+        #[clap(long, parse(try_from_str = parse_age))]
+        older_than: Option<Duration>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ReplicateCommands {
+    /// Write the bookmarks that changed to a delta file
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = EXPORT_DELTA_EXAMPLES)]
+    ExportDelta {
+        /// The output file, or `-` for stdout
+        file: PathBuf,
+
+        /// Only include changes since this time, e.g. `2022-01-01T00:00:00Z` or `7d` ago
+        #[clap(long, parse(try_from_str = parse_since))]
+        since: Option<DateTime<Utc>>,
+    },
+
+    /// Merge a delta exported by another store
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = APPLY_DELTA_EXAMPLES)]
+    ApplyDelta {
+        /// The input file, or `-` for stdin
         file: PathBuf,
     },
 }
+
+#[derive(Subcommand)]
+pub enum ProfileCommands {
+    /// List the profiles
+    #[clap(after_long_help = PROFILE_LIST_EXAMPLES)]
+    List,
+
+    /// Create a profile
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = PROFILE_CREATE_EXAMPLES)]
+    Create {
+        /// Letters, digits, `-` and `_`
+        name: String,
+
+        /// Where to keep its store, by default in the data directory.
+        /// It's created when the profile is first used.
+        #[clap(long)]
+        path: Option<PathBuf>,
+
+        /// Use it when no `--profile` is given
+        #[clap(long)]
+        default: bool,
+    },
+
+    /// Copy the bookmarks and their folders to another profile
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = PROFILE_COPY_TO_EXAMPLES)]
+    CopyTo {
+        /// The profile to copy to
+        #[clap(value_name = "PROFILE")]
+        other: String,
+
+        /// Only copy bookmarks matching this search
+        #[clap(short, long)]
+        query: Option<String>,
+
+        /// What to do with bookmarks the other profile already has
+        #[clap(long, arg_enum, default_value = "overwrite")]
+        on_conflict: ConflictStrategy,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print a setting, or the whole configuration
+    #[clap(after_long_help = CONFIG_GET_EXAMPLES)]
+    Get {
+        /// The setting, with nested ones like `normalize.remove_fragment`
+        key: Option<String>,
+    },
+
+    /// Change a setting, checking the value first
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = CONFIG_SET_EXAMPLES)]
+    Set {
+        /// The setting, with nested ones like `normalize.remove_fragment`
+        key: String,
+
+        /// The new value, as a TOML value or plain text
+        value: String,
+    },
+
+    /// Reset a setting to its default
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = CONFIG_UNSET_EXAMPLES)]
+    Unset { key: String },
+
+    /// Print where the configuration file is
+    #[clap(after_long_help = CONFIG_PATH_EXAMPLES)]
+    Path,
+
+    /// Open the configuration in $VISUAL or $EDITOR, checking it before it's saved
+    #[clap(after_long_help = CONFIG_EDIT_EXAMPLES)]
+    Edit,
+}
+
+/// Parse an RFC 3339 date, or an age like `7d` as that long ago
+pub fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    match DateTime::parse_from_rfc3339(s.trim()) {
+        Ok(date) => Ok(date.with_timezone(&Utc)),
+        Err(_) => parse_age(s)
+            .map(|age| Utc::now() - age)
+            .map_err(|e| format!("expected a date like 2022-01-01T00:00:00Z or an age, {}", e)),
+    }
+}
+
+/// Parse an age like `30d`, `12h`, `45m`, `90s` or `2w`
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in '{}', expected one of s, m, h, d, w", s))?;
+    let (amount, unit) = s.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("invalid amount in '{}'", s))?;
+
+    let unit_seconds: i64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown unit '{}', expected one of s, m, h, d, w",
+                unit
+            ))
+        }
+    };
+    // Durations panic past their milliseconds overflowing, and dates end long before that
+    let too_long = || format!("'{}' is further back than dates go", s);
+    let seconds = amount
+        .checked_mul(unit_seconds)
+        .filter(|seconds| *seconds <= i64::MAX / 1000)
+        .ok_or_else(too_long)?;
+    let age = Duration::seconds(seconds);
+    Utc::now().checked_sub_signed(age).ok_or_else(too_long)?;
+    Ok(age)
+}
+
+#[derive(Subcommand)]
+pub enum DbCommands {
+    /// Copy the store to another backend, keeping the old one as a backup
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    #[clap(after_long_help = DB_CONVERT_EXAMPLES)]
+    Convert {
+        /// The backend to convert to
+        #[clap(long, arg_enum)]
+        to: Backend,
+
+        /// Write the new store here instead of replacing the current one
+        #[clap(long, value_name = "PATH")]
+        out: Option<PathBuf>,
+    },
+}
@@ -0,0 +1,252 @@
+//! The shared directory used by `tinymark sync`.
+//!
+//! It holds one JSON file per bookmark in `bookmarks/`, named after a hash of
+//! its link, and an append-only `tombstones.jsonl` log of deleted links, so it
+//! can be shared through git or Syncthing without running a server. Every file
+//! and tombstone carries an `updated_at`, and when a bookmark changed on both
+//! sides since the last sync the newer one wins. If the losing side still had
+//! the bookmark it's kept in `conflicts/` so the change isn't lost.
+//!
+//! The store doesn't keep modification times, so a bookmark's local
+//! `updated_at` is when the journal last recorded a change to it.
+
+use crate::structures::{Bookmark, Keyspace, Operation, SyncState};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use url::Url;
+
+const BOOKMARKS_DIR: &str = "bookmarks";
+const CONFLICTS_DIR: &str = "conflicts";
+const TOMBSTONES_FILE: &str = "tombstones.jsonl";
+
+/// A bookmark file in the shared directory
+#[derive(Serialize, Deserialize)]
+struct Record {
+    updated_at: DateTime<Utc>,
+    bookmark: Bookmark,
+}
+
+/// A line of the tombstone log
+#[derive(Serialize, Deserialize)]
+struct Tombstone {
+    link: Url,
+    deleted_at: DateTime<Utc>,
+}
+
+/// The latest version of a bookmark on one side
+#[derive(Debug, Clone, PartialEq)]
+pub enum Version {
+    Present(Bookmark, DateTime<Utc>),
+    Deleted(DateTime<Utc>),
+}
+
+impl Version {
+    pub fn updated_at(&self) -> DateTime<Utc> {
+        match self {
+            Version::Present(_, at) | Version::Deleted(at) => *at,
+        }
+    }
+
+    fn same(&self, other: &Version) -> bool {
+        match (self, other) {
+            (Version::Present(a, _), Version::Present(b, _)) => a == b,
+            (Version::Deleted(_), Version::Deleted(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Neither side changed, or both changed the same way
+    Unchanged,
+    /// Write the local version to the shared directory, and keep the shared
+    /// one as a conflict copy if `conflict`
+    Push { conflict: bool },
+    /// Apply the shared version locally, and keep the local one as a
+    /// conflict copy if `conflict`
+    Pull { conflict: bool },
+}
+
+/// Decide which side of a bookmark wins, given what was reconciled last time
+pub fn resolve(
+    local: Option<&Version>,
+    remote: Option<&Version>,
+    state: Option<&SyncState>,
+) -> Resolution {
+    let local_changed = local.is_some_and(|v| state.is_none_or(|s| v.updated_at() > s.local));
+    let remote_changed = remote.is_some_and(|v| state.is_none_or(|s| v.updated_at() > s.remote));
+
+    match (local, remote) {
+        (Some(local), Some(remote)) if local_changed && remote_changed => {
+            if local.same(remote) {
+                return Resolution::Unchanged;
+            }
+            // A deletion that loses doesn't need to be kept
+            if local.updated_at() >= remote.updated_at() {
+                Resolution::Push {
+                    conflict: matches!(remote, Version::Present(..)),
+                }
+            } else {
+                Resolution::Pull {
+                    conflict: matches!(local, Version::Present(..)),
+                }
+            }
+        }
+        _ if local_changed => Resolution::Push { conflict: false },
+        _ if remote_changed => Resolution::Pull { conflict: false },
+        _ => Resolution::Unchanged,
+    }
+}
+
+/// The local version of every bookmark that exists or was deleted, by link
+pub fn local_versions(
+    bookmarks: Vec<Bookmark>,
+    operations: &[Operation],
+) -> HashMap<String, Version> {
+    let mut changed_at = HashMap::new();
+    for operation in operations {
+        for change in &operation.changes {
+            if change.keyspace == Keyspace::Bookmarks {
+                changed_at.insert(change.key.clone(), operation.at);
+            }
+        }
+    }
+
+    let mut versions = HashMap::new();
+    for bookmark in bookmarks {
+        let key = bookmark.link.to_string();
+        let at = changed_at.remove(&key).unwrap_or(bookmark.created_at);
+        versions.insert(key, Version::Present(bookmark, at));
+    }
+    // Whatever is left was changed but isn't stored anymore
+    for (key, at) in changed_at {
+        versions.insert(key, Version::Deleted(at));
+    }
+    versions
+}
+
+/// A stable file name for `link`, from its 64 bit FNV-1a hash
+fn file_name(link: &Url) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in link.as_str().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// The version of every bookmark in the shared directory, by link
+pub fn read_dir(dir: &Path) -> Result<HashMap<String, Version>, String> {
+    let mut versions = HashMap::new();
+
+    let bookmarks_dir = dir.join(BOOKMARKS_DIR);
+    if bookmarks_dir.is_dir() {
+        let entries = fs::read_dir(&bookmarks_dir).map_err(|e| e.to_string())?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let text = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+            let record: Record = serde_json::from_str(&text)
+                .map_err(|e| format!("invalid bookmark in {}: {}", path.display(), e))?;
+            versions.insert(
+                record.bookmark.link.to_string(),
+                Version::Present(record.bookmark, record.updated_at),
+            );
+        }
+    }
+
+    let tombstones = dir.join(TOMBSTONES_FILE);
+    if tombstones.is_file() {
+        let text = fs::read_to_string(&tombstones).map_err(|e| e.to_string())?;
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let tombstone: Tombstone = serde_json::from_str(line).map_err(|e| {
+                format!(
+                    "invalid tombstone on line {} of {}: {}",
+                    number + 1,
+                    TOMBSTONES_FILE,
+                    e
+                )
+            })?;
+            let key = tombstone.link.to_string();
+            // A bookmark can be added again after it was deleted
+            if versions
+                .get(&key)
+                .is_none_or(|v| v.updated_at() < tombstone.deleted_at)
+            {
+                versions.insert(key, Version::Deleted(tombstone.deleted_at));
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Write a file without leaving it half written if we're interrupted
+fn write_atomically(path: &Path, contents: &str) -> Result<(), String> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, contents).map_err(|e| e.to_string())?;
+    fs::rename(&temporary, path).map_err(|e| e.to_string())
+}
+
+fn to_json(bookmark: &Bookmark, updated_at: DateTime<Utc>) -> Result<String, String> {
+    let record = Record {
+        updated_at,
+        bookmark: bookmark.clone(),
+    };
+    serde_json::to_string_pretty(&record).map_err(|e| e.to_string())
+}
+
+/// Write `version` of the bookmark at `link` to the shared directory
+pub fn write(dir: &Path, link: &Url, version: &Version) -> Result<(), String> {
+    let bookmarks_dir = dir.join(BOOKMARKS_DIR);
+    let path = bookmarks_dir.join(file_name(link)).with_extension("json");
+    match version {
+        Version::Present(bookmark, updated_at) => {
+            fs::create_dir_all(&bookmarks_dir).map_err(|e| e.to_string())?;
+            write_atomically(&path, &to_json(bookmark, *updated_at)?)
+        }
+        Version::Deleted(deleted_at) => {
+            if path.exists() {
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+            }
+            let tombstone = Tombstone {
+                link: link.clone(),
+                deleted_at: *deleted_at,
+            };
+            let line = serde_json::to_string(&tombstone).map_err(|e| e.to_string())?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(TOMBSTONES_FILE))
+                .map_err(|e| e.to_string())?;
+            writeln!(file, "{}", line).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Keep the version of `bookmark` that lost a conflict, returning where it was written
+pub fn write_conflict(
+    dir: &Path,
+    bookmark: &Bookmark,
+    updated_at: DateTime<Utc>,
+) -> Result<String, String> {
+    let conflicts_dir = dir.join(CONFLICTS_DIR);
+    fs::create_dir_all(&conflicts_dir).map_err(|e| e.to_string())?;
+    let name = format!(
+        "{}-{}.json",
+        file_name(&bookmark.link),
+        updated_at.format("%Y%m%dT%H%M%S%.fZ")
+    );
+    let path = conflicts_dir.join(name);
+    write_atomically(&path, &to_json(bookmark, updated_at)?)?;
+    Ok(path.display().to_string())
+}
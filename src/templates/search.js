@@ -0,0 +1,58 @@
+// Filters the bookmarks in search.json as you type
+(function () {
+  var input = document.getElementById("search");
+  var results = document.getElementById("results");
+  var root = input.dataset.root;
+  var index = null;
+
+  function text(tag, className, content) {
+    var element = document.createElement(tag);
+    element.className = className;
+    element.textContent = content;
+    return element;
+  }
+
+  function render(query) {
+    results.replaceChildren();
+    if (!query) {
+      return;
+    }
+    var terms = query.toLowerCase().split(/\s+/);
+    index
+      .filter(function (entry) {
+        var haystack = [entry.label, entry.link, entry.description || "", entry.tags.join(" ")]
+          .join(" ")
+          .toLowerCase();
+        return terms.every(function (term) {
+          return haystack.indexOf(term) !== -1;
+        });
+      })
+      .forEach(function (entry) {
+        var item = document.createElement("li");
+        item.className = "bookmark";
+        var link = text("a", "label", entry.label);
+        link.href = entry.link;
+        item.appendChild(link);
+        item.appendChild(text("span", "link", entry.link));
+        if (entry.description) {
+          item.appendChild(text("p", "description", entry.description));
+        }
+        results.appendChild(item);
+      });
+  }
+
+  input.addEventListener("input", function () {
+    if (index) {
+      render(input.value.trim());
+      return;
+    }
+    fetch(root + "search.json")
+      .then(function (response) {
+        return response.json();
+      })
+      .then(function (entries) {
+        index = entries;
+        render(input.value.trim());
+      });
+  });
+})();
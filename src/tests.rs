@@ -1,7 +1,1658 @@
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+use crate::errors::ErrorCode;
+use crate::storage::{MemoryStorage, SledStorage, SqliteStorage, Storage, StorageError};
+use crate::structures::{
+    parse_age, parse_since, Backend, Bookmark, Change, Cli, CompletionShell, Config,
+    ConflictStrategy, Container, ContainerTypes, ExportDocument, FileFormat, Keyspace, Normalize,
+    Operation, OperationKind, SortOrder, SyncState,
+};
+use crate::{
+    buku, commands, completions, config, database, feed, manual, native, outline, output, server,
+    services, site, sync,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use clap::{IntoApp, Parser};
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+#[test]
+fn it_works() {
+    assert_eq!(2 + 2, 4);
+}
+
+#[test]
+fn parses_ages() {
+    assert_eq!(parse_age("30d"), Ok(Duration::days(30)));
+    assert_eq!(parse_age("12h"), Ok(Duration::hours(12)));
+    assert_eq!(parse_age("2w"), Ok(Duration::weeks(2)));
+    assert!(parse_age("30").is_err());
+    assert!(parse_age("d").is_err());
+    assert!(parse_age("3y").is_err());
+    assert!(parse_age("99999999999d").is_err());
+    assert!(parse_age("9223372036854775807w").is_err());
+    assert!(parse_since("99999999999d").is_err());
+}
+
+fn operation(id: u64, kind: OperationKind) -> Operation {
+    Operation {
+        id,
+        at: Utc::now(),
+        description: String::new(),
+        kind,
+        changes: Vec::new(),
+    }
+}
+
+#[test]
+fn replays_history() {
+    let operations = vec![
+        operation(1, OperationKind::Change),
+        operation(2, OperationKind::Change),
+        operation(3, OperationKind::Undo(2)),
+        operation(4, OperationKind::Undo(1)),
+        operation(5, OperationKind::Redo(1)),
+    ];
+    assert_eq!(database::history(&operations), (vec![1], vec![2]));
+
+    // a new change can't be followed by a redo of older undone changes
+    let mut operations = operations;
+    operations.push(operation(6, OperationKind::Change));
+    assert_eq!(database::history(&operations), (vec![1, 6], vec![]));
+}
+
+fn bookmark(link: &str, tags: &[&str], description: Option<&str>) -> Bookmark {
+    Bookmark {
+        container: None,
+        created_at: Utc::now(),
+        description: description.map(String::from),
+        label: link.to_string(),
+        link: Url::parse(link).unwrap(),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+    }
+}
+
+#[test]
+fn merges_bookmarks() {
+    let mut stored = bookmark("https://example.com", &["a", "b"], None);
+    stored.created_at = Utc::now() - Duration::days(1);
+    let imported = bookmark("https://example.com", &["b", "c"], Some("imported"));
+
+    let merged = stored.merge(&imported);
+    assert_eq!(merged.tags, vec!["a", "b", "c"]);
+    assert_eq!(merged.created_at, stored.created_at);
+    assert_eq!(merged.description.as_deref(), Some("imported"));
+
+    let merged = imported.merge(&stored);
+    assert_eq!(merged.created_at, stored.created_at);
+    assert_eq!(merged.description.as_deref(), Some("imported"));
+}
+
+fn folder(label: &str, parent: Option<Uuid>) -> Container {
+    Container::new(
+        parent,
+        ContainerTypes::Folder,
+        Uuid::new_v4(),
+        label.to_string(),
+    )
+}
+
+#[test]
+fn remaps_imported_containers() {
+    let same = folder("same", None);
+    let clashing = folder("stored", None);
+    let existing = vec![same.clone(), clashing.clone()];
+
+    let mut renamed = clashing.clone();
+    renamed.label = "imported".to_string();
+    let child = folder("child", Some(renamed.id));
+    let orphan = folder("orphan", Some(Uuid::new_v4()));
+    let imported = vec![same.clone(), renamed.clone(), child.clone(), orphan.clone()];
+
+    let (containers, mapping) = commands::remap_containers(imported, &existing);
+
+    // the identical folder is reused, the clashing one gets a new id
+    assert_eq!(mapping[&same.id], same.id);
+    assert_ne!(mapping[&renamed.id], renamed.id);
+    assert_eq!(containers.len(), 3);
+    assert!(containers.iter().all(|c| c.id != same.id));
+
+    let new_child = containers.iter().find(|c| c.label == "child").unwrap();
+    assert_eq!(new_child.container, Some(mapping[&renamed.id]));
+    let new_orphan = containers.iter().find(|c| c.label == "orphan").unwrap();
+    assert_eq!(new_orphan.container, None);
+}
+
+fn sample_hierarchy() -> (Vec<Container>, Vec<Bookmark>) {
+    let work = folder("Work [old]", None);
+    let sub = folder("Sub", Some(work.id));
+    let mut a = bookmark(
+        "https://a.example.com",
+        &["rust", "cli"],
+        Some("two\nlines"),
+    );
+    a.label = "A [link]".to_string();
+    a.container = Some(sub.id);
+    let mut b = bookmark(
+        "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+        &["two words", "c#", "a:b", "back\\slash", "<!--"],
+        Some("- not an item\n* nor a heading\n  - indented\n\n\\ backslash\n:END:\n"),
+    );
+    b.container = Some(work.id);
+    let c = bookmark("https://c.example.com", &["top"], None);
+    let d = bookmark("https://d.example.com/smile:)", &[], None);
+    (vec![work, sub], vec![a, b, c, d])
+}
+
+/// The bookmarks without their folders, which get new ids when parsed
+fn without_folders(bookmarks: &[Bookmark]) -> Vec<Bookmark> {
+    let mut kept: Vec<Bookmark> = bookmarks
+        .iter()
+        .map(|b| Bookmark {
+            container: None,
+            ..b.clone()
+        })
+        .collect();
+    kept.sort_by(|a, b| a.link.cmp(&b.link));
+    kept
+}
+
+#[test]
+fn round_trips_markdown() {
+    let (containers, bookmarks) = sample_hierarchy();
+    let text = outline::to_markdown(&containers, &bookmarks);
+    let (parsed_containers, parsed_bookmarks) = outline::from_markdown(&text).unwrap();
+    assert_eq!(
+        outline::to_markdown(&parsed_containers, &parsed_bookmarks),
+        text
+    );
+    assert_eq!(
+        without_folders(&parsed_bookmarks),
+        without_folders(&bookmarks)
+    );
+
+    let a = parsed_bookmarks
+        .iter()
+        .find(|b| b.label == "A [link]")
+        .unwrap();
+    assert_eq!(a.tags, vec!["rust", "cli"]);
+    assert_eq!(a.description.as_deref(), Some("two\nlines"));
+    assert_eq!(a.created_at, bookmarks[0].created_at);
+    let sub = parsed_containers.iter().find(|c| c.label == "Sub").unwrap();
+    assert_eq!(a.container, Some(sub.id));
+}
+
+#[test]
+fn round_trips_org() {
+    let (containers, bookmarks) = sample_hierarchy();
+    let text = outline::to_org(&containers, &bookmarks);
+    let (parsed_containers, parsed_bookmarks) = outline::from_org(&text).unwrap();
+    assert_eq!(outline::to_org(&parsed_containers, &parsed_bookmarks), text);
+    assert_eq!(
+        without_folders(&parsed_bookmarks),
+        without_folders(&bookmarks)
+    );
+    assert!(parsed_containers.iter().any(|c| c.label == "Work [old]"));
+
+    let c = parsed_bookmarks
+        .iter()
+        .find(|b| b.link.as_str() == "https://c.example.com/")
+        .unwrap();
+    assert_eq!(c.container, None);
+    assert_eq!(c.tags, vec!["top"]);
+}
+
+#[test]
+fn matches_outline_folders_by_path() {
+    let (stored, _) = sample_hierarchy();
+    let (parsed, _) = outline::from_markdown("- Work \\[old\\]\n  - Sub\n  - New\n").unwrap();
+
+    let (created, mapping) = commands::match_folders(parsed.clone(), &stored);
+    assert_eq!(mapping[&parsed[0].id], stored[0].id);
+    assert_eq!(mapping[&parsed[1].id], stored[1].id);
+    assert_eq!(created.len(), 1);
+    assert_eq!(created[0].label, "New");
+    assert_eq!(created[0].container, Some(stored[0].id));
+}
+
+#[test]
+fn renders_templates() {
+    let rendered = site::render(
+        "<h1>{{ title }}</h1>{{content}}{{unknown}}{{",
+        &[("title", "Hi"), ("content", "<p>x</p>")],
+    );
+    assert_eq!(rendered, "<h1>Hi</h1><p>x</p>{{unknown}}{{");
+}
+
+#[test]
+fn publishes_only_web_links() {
+    let dir = temporary_store("site").unwrap();
+    let site = site::Site {
+        title: "Bookmarks",
+        base_url: None,
+        templates: site::Templates::load(&dir.join("templates")).unwrap(),
+    };
+    let bookmarks = vec![
+        bookmark("https://a.example.com", &["web"], None),
+        bookmark("javascript:alert(1)", &["script"], None),
+    ];
+    site::publish(&dir, &site, &[], &bookmarks).unwrap();
+    for page in ["index.html", "search.json", "feed.atom"] {
+        let text = std::fs::read_to_string(dir.join(page)).unwrap();
+        assert!(text.contains("https://a.example.com/"), "{}", page);
+        assert!(!text.contains("javascript:"), "{}", page);
+    }
+    assert!(!dir.join("tags/script.html").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        commands::templates_dir(&Some("/etc/tinymark/work.toml".into()), false),
+        std::path::Path::new("/etc/tinymark/templates")
+    );
+}
+
+#[test]
+fn verifies_cli() {
+    Cli::into_app().debug_assert();
+}
+
+#[test]
+fn builds_feeds() {
+    let mut old = bookmark("https://old.example.com", &["rust"], None);
+    old.created_at = Utc::now() - Duration::days(1);
+    let new = bookmark("https://new.example.com/?a=1&b=2", &["rust"], Some("<b>"));
+    let untagged = bookmark("https://untagged.example.com", &[], None);
+
+    let recent = feed::recent(vec![old, new, untagged], Some("rust"), 10);
+    assert_eq!(recent.len(), 2);
+    assert_eq!(recent[0].link.as_str(), "https://new.example.com/?a=1&b=2");
+
+    let info = feed::FeedInfo {
+        title: "Rust & friends",
+        id: "urn:tinymark:tag:rust",
+        link: Some("https://example.com/"),
+        description: "Tagged #rust",
+    };
+    let atom = feed::atom(&info, &recent);
+    assert!(atom.contains("<title>Rust &amp; friends</title>"));
+    assert!(atom.contains("<id>https://new.example.com/?a=1&amp;b=2</id>"));
+    assert!(atom.contains("<summary>&lt;b&gt;</summary>"));
+
+    let rss = feed::rss(&info, &recent);
+    assert!(rss.contains("<link>https://example.com/</link>"));
+    assert!(rss.contains("<category>rust</category>"));
+    assert_eq!(rss.matches("<item>").count(), 2);
+}
+
+#[test]
+fn imports_pinboard() {
+    let (containers, bookmarks) =
+        services::from_pinboard(include_str!("fixtures/pinboard.json")).unwrap();
+    assert!(containers.is_empty());
+    assert_eq!(bookmarks.len(), 2);
+
+    assert_eq!(bookmarks[0].label, "Rust Programming Language");
+    assert_eq!(
+        bookmarks[0].description.as_deref(),
+        Some("A language empowering everyone.")
+    );
+    assert_eq!(bookmarks[0].tags, vec!["rust", "programming"]);
+    assert_eq!(
+        bookmarks[0].created_at,
+        "2021-03-04T05:06:07Z".parse::<DateTime<Utc>>().unwrap()
+    );
+
+    // an untitled post is labelled with its link
+    assert_eq!(bookmarks[1].label, "https://example.com/untitled");
+    assert_eq!(bookmarks[1].description, None);
+    assert!(bookmarks[1].tags.is_empty());
+}
+
+#[test]
+fn imports_raindrop() {
+    let (containers, bookmarks) =
+        services::from_raindrop(include_str!("fixtures/raindrop.csv")).unwrap();
+    assert_eq!(containers.len(), 2);
+    let dev = containers.iter().find(|c| c.label == "Dev").unwrap();
+    let databases = containers.iter().find(|c| c.label == "Databases").unwrap();
+    assert_eq!(dev.container, None);
+    assert_eq!(databases.container, Some(dev.id));
+
+    assert_eq!(bookmarks[0].container, Some(databases.id));
+    assert_eq!(
+        bookmarks[0].description.as_deref(),
+        Some("Embedded database")
+    );
+    assert_eq!(bookmarks[0].tags, vec!["rust", "db"]);
+    assert_eq!(
+        bookmarks[0].created_at,
+        "2022-05-06T07:08:09Z".parse::<DateTime<Utc>>().unwrap()
+    );
+
+    assert_eq!(bookmarks[1].label, "Quotes, \"and\" commas");
+    assert_eq!(bookmarks[1].container, Some(dev.id));
+    assert_eq!(bookmarks[1].description.as_deref(), Some("Only an excerpt"));
+
+    assert_eq!(bookmarks[2].container, None);
+    assert_eq!(bookmarks[2].tags, vec!["reading"]);
+}
+
+#[test]
+fn imports_pocket() {
+    let (containers, bookmarks) =
+        services::from_pocket(include_str!("fixtures/pocket.html")).unwrap();
+    let labels: Vec<&str> = containers.iter().map(|c| c.label.as_str()).collect();
+    assert_eq!(labels, vec!["Unread", "Read Archive"]);
+    assert_eq!(bookmarks.len(), 3);
+
+    assert_eq!(bookmarks[0].link.as_str(), "https://example.com/?a=1&b=2");
+    assert_eq!(bookmarks[0].label, "Ampersands & \"quotes\"");
+    assert_eq!(bookmarks[0].tags, vec!["news", "tech"]);
+    assert_eq!(bookmarks[0].container, Some(containers[0].id));
+    assert_eq!(
+        bookmarks[0].created_at,
+        Utc.timestamp_opt(1600000000, 0).unwrap()
+    );
+
+    assert_eq!(bookmarks[1].label, "https://example.com/untitled");
+    assert!(bookmarks[1].tags.is_empty());
+    assert_eq!(bookmarks[2].container, Some(containers[1].id));
+}
+
+#[test]
+fn imports_linkding() {
+    let text = include_str!("fixtures/linkding.json");
+    let (containers, bookmarks) = services::from_linkding(text).unwrap();
+    assert_eq!(containers.len(), 1);
+    assert_eq!(containers[0].label, "Archived");
+
+    assert_eq!(bookmarks[0].label, "linkding on GitHub");
+    assert_eq!(
+        bookmarks[0].description.as_deref(),
+        Some("Self-hosted bookmark manager\n\nSelf-hosted")
+    );
+    assert_eq!(bookmarks[0].tags, vec!["bookmarks", "selfhosted"]);
+    assert_eq!(bookmarks[0].container, None);
+
+    assert_eq!(bookmarks[1].label, "Old page");
+    assert_eq!(bookmarks[1].container, Some(containers[0].id));
+
+    // a bare array of results is accepted too
+    let value: serde_json::Value = serde_json::from_str(text).unwrap();
+    let (_, bare) = services::from_linkding(&value["results"].to_string()).unwrap();
+    assert_eq!(bare.len(), 2);
+    assert_eq!(bare[0], bookmarks[0]);
+}
+
+#[test]
+fn round_trips_buku() {
+    assert_eq!(buku::parse_tags(",rust,cli,,rust,"), vec!["rust", "cli"]);
+    assert_eq!(buku::format_tags(&[]), ",");
+    let tags = vec!["a,b".to_string(), "c".to_string()];
+    assert_eq!(buku::format_tags(&tags), ",a b,c,");
+
+    let path = std::env::temp_dir().join(format!("tinymark-buku-{}.db", Uuid::new_v4()));
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(include_str!("fixtures/buku.sql"))
+        .unwrap();
+
+    let mut bookmarks = buku::read(&path).unwrap();
+    assert_eq!(bookmarks.len(), 3);
+    assert_eq!(bookmarks[0].label, "buku");
+    assert_eq!(bookmarks[0].tags, vec!["bookmarks", "cli", "python"]);
+    assert_eq!(
+        bookmarks[0].description.as_deref(),
+        Some("Personal mini-web in text")
+    );
+    assert_eq!(bookmarks[1].label, "https://example.com/");
+    assert!(bookmarks[1].tags.is_empty());
+    assert_eq!(bookmarks[2].link.as_str(), "http://example.org/bare");
+
+    // writing back updates the rows in place and keeps their flags
+    bookmarks[1].tags.push("new".to_string());
+    bookmarks.push(bookmark("https://added.example.com", &["x"], None));
+    buku::write(&path, &bookmarks).unwrap();
+    let rows: Vec<(i64, String, String, i64)> = connection
+        .prepare("SELECT id, URL, tags, flags FROM bookmarks ORDER BY id")
+        .unwrap()
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    // untitled rows stay untitled
+    let title: String = connection
+        .query_row("SELECT metadata FROM bookmarks WHERE id = 2", [], |r| {
+            r.get(0)
+        })
+        .unwrap();
+    assert_eq!(title, "");
+
+    // importing again doesn't redate what's already stored
+    let store = temporary_store("buku-import");
+    let import = || {
+        commands::import(
+            path.clone(),
+            Some(FileFormat::Buku),
+            None,
+            ConflictStrategy::Overwrite,
+            &Normalize::default(),
+            false,
+            store.clone(),
+        )
+    };
+    import();
+    let imported = stored_bookmarks(&store);
+    assert_eq!(imported.len(), 4);
+    import();
+    assert_eq!(stored_bookmarks(&store), imported);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(rows.len(), 4);
+    assert_eq!(
+        rows[1],
+        (2, "https://example.com".to_string(), ",new,".to_string(), 1)
+    );
+    assert_eq!(rows[2].1, "example.org/bare");
+    assert_eq!(rows[3].1, "https://added.example.com/");
+}
+
+#[test]
+fn resolves_sync_conflicts() {
+    use sync::{Resolution, Version};
+    let then = Utc::now() - Duration::days(1);
+    let now = Utc::now();
+    let old = Version::Present(bookmark("https://example.com", &["old"], None), then);
+    let new = Version::Present(bookmark("https://example.com", &["new"], None), now);
+    let synced = SyncState {
+        local: then,
+        remote: then,
+    };
+
+    let resolve =
+        |local: &Version, remote: &Version, state| sync::resolve(Some(local), Some(remote), state);
+    assert_eq!(resolve(&old, &old, Some(&synced)), Resolution::Unchanged);
+    // only the side that changed since the last sync is copied
+    assert_eq!(
+        resolve(&new, &old, Some(&synced)),
+        Resolution::Push { conflict: false }
+    );
+    assert_eq!(
+        resolve(&old, &new, Some(&synced)),
+        Resolution::Pull { conflict: false }
+    );
+    // both changed, the newer wins and the other is kept
+    assert_eq!(
+        resolve(&new, &old, None),
+        Resolution::Push { conflict: true }
+    );
+    assert_eq!(
+        resolve(&old, &new, None),
+        Resolution::Pull { conflict: true }
+    );
+    // a losing deletion isn't kept
+    assert_eq!(
+        resolve(&Version::Deleted(then), &new, None),
+        Resolution::Pull { conflict: false }
+    );
+    assert_eq!(
+        sync::resolve(None, Some(&new), None),
+        Resolution::Pull { conflict: false }
+    );
+}
+
+#[test]
+fn round_trips_sync_dir() {
+    let dir = std::env::temp_dir().join(format!("tinymark-sync-{}", Uuid::new_v4()));
+    let kept = bookmark("https://kept.example.com", &["a"], Some("desc"));
+    let gone = bookmark("https://gone.example.com", &[], None);
+    let now = Utc::now();
+
+    let mut operation = operation(1, OperationKind::Change);
+    operation.changes.push(Change {
+        keyspace: Keyspace::Bookmarks,
+        key: gone.link.to_string(),
+        before: None,
+        after: None,
+    });
+    let local = sync::local_versions(vec![kept.clone()], &[operation.clone()]);
+    assert_eq!(
+        local[kept.link.as_str()],
+        sync::Version::Present(kept.clone(), kept.created_at)
+    );
+    assert_eq!(
+        local[gone.link.as_str()],
+        sync::Version::Deleted(operation.at)
+    );
+
+    sync::write(&dir, &kept.link, &sync::Version::Present(kept.clone(), now)).unwrap();
+    sync::write(&dir, &gone.link, &sync::Version::Present(gone.clone(), now)).unwrap();
+    let deleted_at = now + Duration::seconds(1);
+    sync::write(&dir, &gone.link, &sync::Version::Deleted(deleted_at)).unwrap();
+
+    let remote = sync::read_dir(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(remote.len(), 2);
+    assert_eq!(
+        remote[kept.link.as_str()],
+        sync::Version::Present(kept.clone(), now)
+    );
+    assert_eq!(
+        remote[gone.link.as_str()],
+        sync::Version::Deleted(deleted_at)
+    );
+}
+
+fn temporary_store(name: &str) -> Option<std::path::PathBuf> {
+    Some(std::env::temp_dir().join(format!("tinymark-{}-{}", name, Uuid::new_v4())))
+}
+
+fn stored_bookmarks(path: &Option<std::path::PathBuf>) -> Vec<Bookmark> {
+    let mut bookmarks = Vec::new();
+    database::for_each(false, path.clone(), Keyspace::Bookmarks, |b| {
+        bookmarks.push(b)
+    });
+    bookmarks
+}
+
+#[test]
+fn replicates_between_stores() {
+    let (a, b) = (temporary_store("a"), temporary_store("b"));
+    let (actor_a, actor_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let exchange = |since| {
+        let to_b = commands::delta_since(since, actor_a, false, a.clone());
+        let to_a = commands::delta_since(since, actor_b, false, b.clone());
+        commands::merge_delta(to_b, actor_b, false, b.clone());
+        commands::merge_delta(to_a, actor_a, false, a.clone());
+    };
+
+    let shared = bookmark("https://shared.example.com", &["rust"], None);
+    database::insert_entry(false, a.clone(), Keyspace::Bookmarks, &shared);
+    let gone = bookmark("https://gone.example.com", &[], None);
+    database::insert_entry(false, b.clone(), Keyspace::Bookmarks, &gone);
+    exchange(None);
+    assert_eq!(stored_bookmarks(&a), stored_bookmarks(&b));
+
+    // concurrent edits, B relabels last and removes the tag A had
+    let start = Utc::now();
+    let mut on_a = shared.clone();
+    on_a.label = "from a".to_string();
+    on_a.tags.push("cli".to_string());
+    database::insert_entry(false, a.clone(), Keyspace::Bookmarks, &on_a);
+    let mut on_b = shared.clone();
+    on_b.label = "from b".to_string();
+    on_b.tags = vec!["web".to_string()];
+    database::insert_entry(false, b.clone(), Keyspace::Bookmarks, &on_b);
+    database::remove_entries(std::slice::from_ref(&gone.link), false, a.clone());
+    exchange(Some(start));
+
+    let in_a = stored_bookmarks(&a);
+    assert_eq!(in_a, stored_bookmarks(&b));
+    assert_eq!(in_a.len(), 1);
+    assert_eq!(in_a[0].label, "from b");
+    assert_eq!(in_a[0].tags, vec!["cli", "web"]);
+
+    // deltas are idempotent
+    let again = commands::delta_since(None, actor_a, false, a.clone());
+    assert_eq!(
+        commands::merge_delta(again, actor_b, false, b.clone()),
+        (0, 0)
+    );
+
+    // stores used from the same configuration each keep their own actor
+    let cfg = Config {
+        actor: Some(actor_a),
+        ..Config::default()
+    };
+    let of_a = commands::store_actor(&cfg, &None, true, false, a.clone());
+    assert_eq!(of_a, actor_a);
+    let of_b = commands::store_actor(&cfg, &None, false, false, b.clone());
+    assert_ne!(of_b, actor_a);
+    assert_eq!(
+        commands::store_actor(&cfg, &None, false, false, b.clone()),
+        of_b
+    );
+    assert_eq!(
+        commands::store_actor(&Config::default(), &None, true, false, a.clone()),
+        actor_a
+    );
+
+    for path in [a, b].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn serves_the_rest_api() {
+    let store = temporary_store("serve");
+    let request =
+        |method: &str, url: &str, body: &str| server::handle(method, url, body, false, &store);
+
+    let created = request(
+        "POST",
+        "/bookmarks",
+        r#"{"link": "https://example.com/a", "label": "A", "tags": ["rust"]}"#,
+    );
+    assert_eq!(created.status, 201);
+    assert_eq!(created.body["data"]["label"], "A");
+    let again = request(
+        "POST",
+        "/bookmarks",
+        r#"{"link": "https://example.com/a", "label": "A"}"#,
+    );
+    assert_eq!(again.status, 409);
+    assert_eq!(again.body["status"], "fail");
+
+    let folder = request("POST", "/containers", r#"{"label": "Reading"}"#);
+    assert_eq!(folder.status, 201);
+    let id = folder.body["data"]["id"].as_str().unwrap().to_string();
+    let moved = request(
+        "PUT",
+        "/bookmarks/https%3A%2F%2Fexample.com%2Fa",
+        &format!(
+            r#"{{"link": "https://example.com/a", "label": "B", "tags": ["rust"], "container": "{}"}}"#,
+            id
+        ),
+    );
+    assert_eq!(moved.status, 200);
+    assert_eq!(
+        moved.body["data"]["created_at"],
+        created.body["data"]["created_at"]
+    );
+
+    // unencoded links work too
+    let fetched = request("GET", "/bookmarks/https://example.com/a", "");
+    assert_eq!(fetched.body["data"]["label"], "B");
+    assert_eq!(fetched.body["data"]["container"], id.as_str());
+    let listed = request("GET", &format!("/bookmarks?folder={}", id), "");
+    assert_eq!(listed.body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        request("GET", "/search?q=b", "").body["data"][0]["label"],
+        "B"
+    );
+    assert_eq!(
+        request("GET", "/search?q=zzz", "").body["data"],
+        serde_json::json!([])
+    );
+    assert_eq!(request("GET", "/search", "").status, 400);
+    assert_eq!(request("GET", "/tags", "").body["data"][0]["name"], "rust");
+
+    let missing = request(
+        "PUT",
+        "/bookmarks/https%3A%2F%2Fexample.com%2Fa",
+        r#"{"link": "https://example.com/a", "label": "B", "container": "00000000-0000-0000-0000-000000000000"}"#,
+    );
+    assert_eq!(missing.status, 422);
+    assert_eq!(request("DELETE", "/tags", "").status, 405);
+    assert_eq!(request("GET", "/nothing", "").status, 404);
+
+    let deleted = request("DELETE", "/bookmarks/https%3A%2F%2Fexample.com%2Fa", "");
+    assert_eq!(deleted.body["data"][0]["label"], "B");
+    assert_eq!(deleted.body["errors"], serde_json::json!([]));
+    assert_eq!(
+        request("GET", "/bookmarks/https%3A%2F%2Fexample.com%2Fa", "").status,
+        404
+    );
+    assert_eq!(
+        request("DELETE", &format!("/containers/{}", id), "").status,
+        200
+    );
+    assert_eq!(
+        request("GET", "/containers", "").body["data"],
+        serde_json::json!([])
+    );
+
+    if let Some(path) = store {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn serves_over_http() {
+    use std::io::{Read, Write};
+    let store = temporary_store("http");
+    let listener = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let address = listener.server_addr().to_ip().unwrap();
+    let served = store.clone();
+    let allowed = vec!["http://localhost:3000".to_string()];
+    std::thread::spawn(move || server::run(listener, &allowed, false, served));
+
+    let send = |method: &str, headers: &str, body: &str| {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} /bookmarks HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            address,
+            headers,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let body = r#"{"link": "https://example.com", "label": "Example"}"#;
+
+    // What a form or `fetch` on any other page could send without asking first
+    let response = send(
+        "POST",
+        "Origin: https://evil.example\r\nContent-Type: text/plain\r\n",
+        body,
+    );
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    let response = send("POST", "Content-Type: text/plain\r\n", body);
+    assert!(response.starts_with("HTTP/1.1 415"), "{}", response);
+    let response = send("DELETE", "Origin: https://evil.example\r\n", "");
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+    let own = format!("Origin: http://{}\r\n", address);
+    assert!(send("GET", &own, "").starts_with("HTTP/1.1 200"));
+    assert!(send("GET", "Origin: http://localhost:3000\r\n", "").starts_with("HTTP/1.1 200"));
+    let response = send(
+        "POST",
+        "Content-Type: application/json; charset=utf-8\r\n",
+        body,
+    );
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    assert!(response.contains("application/json"));
+    assert!(response.contains(r#""label":"Example","link":"https://example.com/","tags":[]}"#));
+
+    if let Some(path) = store {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn answers_native_messages() {
+    let store = temporary_store("native");
+    let messages = [
+        r#"{"action": "check", "link": "https://example.com", "id": 1}"#,
+        r#"{"action": "add", "link": "https://example.com", "label": "Example", "tags": ["web"]}"#,
+        r#"{"action": "check", "link": "https://example.com/"}"#,
+        r#"{"action": "search", "query": "exa"}"#,
+        r#"{"action": "add", "link": "https://example.com", "folder": "00000000-0000-0000-0000-000000000000"}"#,
+        r#"{"action": "fly"}"#,
+        "not json",
+    ];
+    let mut input = Vec::new();
+    for message in messages {
+        input.extend_from_slice(&(message.len() as u32).to_ne_bytes());
+        input.extend_from_slice(message.as_bytes());
+    }
+    let mut output = Vec::new();
+    native::run(&mut input.as_slice(), &mut output, &store).unwrap();
+
+    let mut output = output.as_slice();
+    let mut replies = Vec::new();
+    while let Some(reply) = native::read_message(&mut output).unwrap() {
+        replies.push(serde_json::from_slice::<serde_json::Value>(&reply).unwrap());
+    }
+    assert_eq!(replies.len(), messages.len());
+    assert_eq!(replies[0]["meta"]["bookmarked"], false);
+    assert_eq!(replies[0]["meta"]["id"], 1);
+    assert_eq!(replies[1]["data"]["label"], "Example");
+    assert_eq!(replies[2]["meta"]["bookmarked"], true);
+    assert_eq!(replies[2]["data"]["tags"][0], "web");
+    assert_eq!(replies[3]["data"][0]["link"], "https://example.com/");
+    for reply in &replies[4..] {
+        assert_eq!(reply["status"], "fail");
+    }
+    // a failed add doesn't change the bookmark
+    assert_eq!(stored_bookmarks(&store)[0].label, "Example");
+
+    // a message cut short is an error rather than the end
+    let mut truncated: &[u8] = &[10, 0, 0, 0, b'{'];
+    assert!(native::read_message(&mut truncated).is_err());
+
+    if let Some(path) = store {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn hooks_stored_values_into_completions() {
+    let bash = completions::script(CompletionShell::Bash);
+    assert!(bash.contains("tinymark__delete)"));
+    assert!(bash.contains("tinymark __complete urls"));
+    assert!(bash
+        .trim_end()
+        .ends_with("complete -F _tinymark_stored -o bashdefault -o default tinymark"));
+    assert!(!bash.contains("__complete)"));
+
+    let zsh = completions::script(CompletionShell::Zsh);
+    assert!(zsh.contains("_tinymark_static() {"));
+    assert!(zsh.contains("tinymark __complete tags"));
+    assert!(zsh.trim_end().ends_with("_tinymark \"$@\""));
+
+    let fish = completions::script(CompletionShell::Fish);
+    assert!(fish.contains("-l tag -x -a \"(tinymark __complete tags 2>/dev/null)\""));
+}
+
+#[test]
+fn documents_every_command() {
+    fn check(command: &clap::Command, path: &str) {
+        for sub in command.get_subcommands() {
+            if sub.is_hide_set() {
+                continue;
+            }
+            let path = format!("{} {}", path, sub.get_name());
+            let examples = sub.get_after_long_help().unwrap_or_default();
+            assert!(examples.contains(&path), "no examples for `{}`", path);
+            check(sub, &path);
+        }
+    }
+    check(&Cli::into_app(), "tinymark");
+
+    let pages = manual::pages().unwrap();
+    let names: Vec<&str> = pages.iter().map(|(name, _)| name.as_str()).collect();
+    assert!(names.contains(&"tinymark.1"));
+    assert!(names.contains(&"tinymark-trash-empty.1"));
+    assert!(!names
+        .iter()
+        .any(|n| n.contains("complete") && !n.contains("completions")));
+    for (name, page) in &pages {
+        let page = String::from_utf8_lossy(page);
+        assert!(page.contains(".SH EXAMPLES"), "no examples in {}", name);
+    }
+
+    let page = String::from_utf8(manual::page(&["trash".into(), "empty".into()]).unwrap()).unwrap();
+    assert!(page.contains(".TH tinymark-trash-empty 1"));
+    assert!(page.contains("tinymark trash empty \\-\\-older\\-than 30d"));
+    assert!(manual::page(&["nothing".into()]).is_err());
+}
+
+#[test]
+fn finds_the_store() {
+    use clap::Parser;
+    let args = Cli::try_parse_from(["tinymark", "list", "--db", "/tmp/store"]).unwrap();
+    assert_eq!(args.db, Some("/tmp/store".into()));
+
+    let home = || Ok("/home/someone".to_string());
+    assert_eq!(
+        database::data_dir_in(Some("/data".into()), home(), false),
+        std::path::Path::new("/data/tinymark")
+    );
+    assert_eq!(
+        database::data_dir_in(Some("relative".into()), home(), false),
+        std::path::Path::new("/home/someone/.local/share/tinymark")
+    );
+    assert_eq!(
+        database::data_dir_in(None, home(), false),
+        std::path::Path::new("/home/someone/.local/share/tinymark")
+    );
+}
+
+#[test]
+fn copies_between_profiles() {
+    let (from, root) = (
+        temporary_store("from"),
+        temporary_store("profiles").unwrap(),
+    );
+    let config_path = Some(root.join("tinymark.toml"));
+    let mut cfg = Config::default();
+    commands::create_profile(
+        "work",
+        &Some(root.join("work")),
+        true,
+        &mut cfg,
+        &config_path,
+        false,
+    );
+    let stored: Config = commands::load_config(&config_path, false);
+    assert_eq!(stored.default_profile.as_deref(), Some("work"));
+    assert_eq!(stored.profiles, cfg.profiles);
+    assert!(cfg.profiles["work"].actor.is_some());
+    assert_eq!(
+        commands::selected_profile(&None, &cfg, false).as_deref(),
+        Some("work")
+    );
+
+    let parent = folder("Code", None);
+    let child = folder("Rust", Some(parent.id));
+    let other = folder("Other", None);
+    database::insert_imported(
+        false,
+        from.clone(),
+        &[parent.clone(), child.clone(), other],
+        &[],
+    );
+    let mut rust = bookmark("https://rust-lang.org", &["lang"], None);
+    rust.container = Some(child.id);
+    database::insert_entry(false, from.clone(), Keyspace::Bookmarks, &rust);
+    let example = bookmark("https://example.com", &[], None);
+    database::insert_entry(false, from.clone(), Keyspace::Bookmarks, &example);
+
+    commands::copy_to_profile(
+        "work",
+        &Some("rust".to_string()),
+        ConflictStrategy::Overwrite,
+        &cfg,
+        false,
+        from.clone(),
+    );
+    let work = Some(cfg.profiles["work"].storage_location.clone());
+    assert_eq!(stored_bookmarks(&work), vec![rust]);
+    let mut folders: Vec<String> = database::get_containers(false, work.clone())
+        .into_iter()
+        .map(|c| c.label)
+        .collect();
+    folders.sort();
+    assert_eq!(folders, ["Code", "Rust"]);
+
+    // the source is left alone
+    assert_eq!(stored_bookmarks(&from).len(), 2);
+
+    for path in [from, Some(root)].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn changes_settings() {
+    let cfg = Config::default();
+    let cfg = config::set(&cfg, "sort", "newest").unwrap();
+    assert_eq!(cfg.sort, SortOrder::Newest);
+    let cfg = config::set(&cfg, "normalize.remove_tracking", "true").unwrap();
+    assert!(cfg.normalize.remove_tracking);
+    assert_eq!(
+        config::get(&cfg, "normalize.remove_tracking").unwrap(),
+        Some(toml::Value::Boolean(true))
+    );
+    assert_eq!(config::get(&cfg, "default_profile").unwrap(), None);
+
+    // values are checked before anything is changed
+    assert!(config::set(&cfg, "sort", "random").is_err());
+    assert!(config::set(&cfg, "json", "maybe").is_err());
+    assert!(config::set(&cfg, "jsn", "true").is_err());
+    assert!(config::set(&cfg, "default_profile", "work").is_err());
+
+    let cfg = config::set(&cfg, "profiles.work.storage_location", "/tmp/work").unwrap();
+    let cfg = config::set(&cfg, "default_profile", "work").unwrap();
+    assert!(config::unset(&cfg, "profiles.work").is_err());
+    let cfg = config::unset(&cfg, "sort").unwrap();
+    assert_eq!(cfg.sort, SortOrder::Link);
+
+    assert_eq!(config::parse(&config::to_string(&cfg).unwrap()), Ok(cfg));
+    assert!(config::parse("json = \"yes\"").is_err());
+    assert!(config::parse("default_profile = \"missing\"").is_err());
+    // nothing would be kept between runs
+    assert!(config::parse("backend = \"memory\"").is_err());
+    // settings that aren't there are the defaults
+    assert_eq!(config::parse("json = true").unwrap().sort, SortOrder::Link);
+}
+
+#[test]
+fn normalizes_links() {
+    let normalize = Normalize {
+        remove_tracking: true,
+        remove_fragment: true,
+        remove_trailing_slash: true,
+    };
+    let link = |s: &str| Url::parse(s).unwrap();
+    assert_eq!(
+        normalize.apply(&link(
+            "https://example.com/a/?utm_source=feed&id=3&fbclid=x#top"
+        )),
+        link("https://example.com/a?id=3")
+    );
+    assert_eq!(
+        normalize.apply(&link("https://example.com/?utm_medium=email")),
+        link("https://example.com/")
+    );
+    assert_eq!(
+        Normalize::default().apply(&link("https://example.com/a/#top")),
+        link("https://example.com/a/#top")
+    );
+}
+
+#[test]
+fn reports_errors_with_codes() {
+    assert_eq!(ErrorCode::Usage.exit_code(), exitcode::USAGE);
+    assert_eq!(ErrorCode::NotFound.exit_code(), exitcode::DATAERR);
+    assert_eq!(ErrorCode::Config.exit_code(), exitcode::CONFIG);
+
+    // the server and the native host fail the same way
+    let store = temporary_store("errors");
+    let response = server::handle(
+        "GET",
+        "/bookmarks/https%3A%2F%2Fexample.com",
+        "",
+        false,
+        &store,
+    );
+    assert_eq!(response.status, 404);
+    assert_eq!(response.body["errors"][0]["code"], "not_found");
+    let reply = native::handle(br#"{"action": "fly", "id": 1}"#, &store);
+    assert_eq!(reply["errors"][0]["code"], "usage");
+    assert_eq!(reply["meta"]["id"], 1);
+
+    if let Some(path) = store {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn wraps_output_in_an_envelope() {
+    let schema: serde_json::Value = serde_json::from_str(output::SCHEMA).unwrap();
+    let required = schema["required"].as_array().unwrap();
+
+    let success = output::success(
+        vec![bookmark("https://example.com", &[], None)],
+        json!({
+            "reason": "listed",
+        }),
+    );
+    let failure = output::failure(ErrorCode::NotFound, "entry https://example.com/ not found");
+    for envelope in [&success, &failure] {
+        let keys: Vec<&String> = envelope.as_object().unwrap().keys().collect();
+        assert_eq!(keys.len(), required.len());
+        assert!(required
+            .iter()
+            .all(|k| keys.contains(&&k.as_str().unwrap().to_string())));
+        assert_eq!(envelope["meta"]["version"], output::VERSION);
+    }
+    assert_eq!(success["status"], "success");
+    assert_eq!(success["data"][0]["link"], "https://example.com/");
+    assert_eq!(success["errors"], json!([]));
+    assert_eq!(success["meta"]["reason"], "listed");
+    assert_eq!(failure["status"], "fail");
+    assert_eq!(failure["data"], serde_json::Value::Null);
+    assert_eq!(failure["errors"][0]["code"], "not_found");
+
+    // every code the schema allows is one tinymark reports
+    let codes = schema["$defs"]["error"]["properties"]["code"]["enum"]
+        .as_array()
+        .unwrap();
+    let known = [
+        ErrorCode::Usage,
+        ErrorCode::NotFound,
+        ErrorCode::InvalidData,
+        ErrorCode::NoInput,
+        ErrorCode::Unavailable,
+        ErrorCode::Internal,
+        ErrorCode::CantCreate,
+        ErrorCode::Io,
+        ErrorCode::Busy,
+        ErrorCode::Config,
+    ];
+    assert_eq!(codes, &known.iter().map(|c| json!(c)).collect::<Vec<_>>());
+}
+
+#[test]
+fn starts_with_an_empty_store() {
+    let store = temporary_store("first-run");
+    assert!(database::get_all(false, store.clone(), Keyspace::Bookmarks).is_empty());
+    assert!(database::get_containers(false, store.clone()).is_empty());
+    assert!(database::get_trash(false, store.clone()).is_empty());
+    assert!(database::get_journal(false, store.clone()).is_empty());
+    let mut listed = Vec::new();
+    commands::list_to(&mut listed, SortOrder::Newest, true, store.clone());
+    let listed: serde_json::Value = serde_json::from_slice(&listed).unwrap();
+    assert_eq!(listed["status"], "success");
+    assert_eq!(listed["data"], serde_json::json!([]));
+    let mut listed = Vec::new();
+    commands::list_to(&mut listed, SortOrder::Newest, false, store.clone());
+    assert!(listed.is_empty());
+
+    // empty exports can be imported again
+    let dir = temporary_store("first-run-exports").unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in ["empty.json", "empty.jsonl", "empty.md", "empty.org"] {
+        let file = dir.join(name);
+        commands::export(file.clone(), None, None, false, store.clone());
+        if name == "empty.json" {
+            let document: ExportDocument =
+                serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+            assert!(document.bookmarks.is_empty());
+            assert!(document.containers.is_empty());
+        }
+        commands::import(
+            file,
+            None,
+            None,
+            ConflictStrategy::Overwrite,
+            &Normalize::default(),
+            false,
+            store.clone(),
+        );
+    }
+    assert!(stored_bookmarks(&store).is_empty());
+
+    for path in [store, Some(dir)].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn shares_a_locked_store() {
+    let store = temporary_store("locked");
+    let path = store.clone().unwrap();
+    database::write_entry(
+        false,
+        store.clone(),
+        Keyspace::Bookmarks,
+        &bookmark("https://example.com/", &[], None),
+    );
+
+    // a snapshot can be read and changed without touching the store
+    let snapshot = database::snapshot(false, store.clone());
+    assert_eq!(stored_bookmarks(&Some(snapshot.clone())).len(), 1);
+    database::write_entry(
+        false,
+        Some(snapshot.clone()),
+        Keyspace::Bookmarks,
+        &bookmark("https://example.org/", &[], None),
+    );
+    assert_eq!(stored_bookmarks(&store).len(), 1);
+
+    // another process holding the lock is waited for
+    let locked = temporary_store("held").unwrap();
+    let held = sled::open(&locked).unwrap();
+    assert!(matches!(
+        SledStorage::open(&locked, std::time::Duration::ZERO),
+        Err(StorageError::Locked)
+    ));
+    let holder = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        drop(held);
+    });
+    let opened = SledStorage::open(&locked, std::time::Duration::from_secs(10)).unwrap();
+    assert_eq!(opened.scan(Keyspace::Bookmarks, &[]).count(), 0);
+    holder.join().unwrap();
+
+    let parse = |args: &[&str]| Cli::try_parse_from(args).unwrap().command;
+    assert!(!parse(&["tinymark", "--read-only", "list"]).writes());
+    assert!(!parse(&["tinymark", "--read-only", "export", "out.json"]).writes());
+    assert!(parse(&["tinymark", "--read-only", "add", "https://a.com", "A"]).writes());
+    assert!(parse(&["tinymark", "trash", "restore", "1"]).writes());
+
+    for path in [path, locked, snapshot] {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+fn exercise_storage(storage: &dyn Storage) {
+    let ks = Keyspace::Sync;
+    assert_eq!(storage.get(ks, b"a/1").unwrap(), None);
+    assert_eq!(storage.put(ks, b"a/1", b"one").unwrap(), None);
+    assert_eq!(
+        storage.put(ks, b"a/1", b"uno").unwrap(),
+        Some(b"one".to_vec())
+    );
+    storage.put(ks, b"b/1", b"two").unwrap();
+    storage.put(ks, b"a/2", b"three").unwrap();
+    // other keyspaces don't show up
+    storage.put(Keyspace::Replica, b"a/3", b"four").unwrap();
+
+    let scanned: Vec<_> = storage.scan(ks, b"a/").map(Result::unwrap).collect();
+    assert_eq!(
+        scanned,
+        vec![
+            (b"a/1".to_vec(), b"uno".to_vec()),
+            (b"a/2".to_vec(), b"three".to_vec())
+        ]
+    );
+    assert_eq!(storage.scan(ks, &[]).count(), 3);
+    assert_eq!(storage.last(ks).unwrap().unwrap().0, b"b/1".to_vec());
+    assert_eq!(storage.delete(ks, b"b/1").unwrap(), Some(b"two".to_vec()));
+    assert_eq!(storage.delete(ks, b"b/1").unwrap(), None);
+    assert_eq!(storage.last(Keyspace::Journal).unwrap(), None);
+
+    storage
+        .batch(vec![
+            (ks, b"a/1".to_vec(), None),
+            (Keyspace::Trash, b"a/1".to_vec(), Some(b"uno".to_vec())),
+        ])
+        .unwrap();
+    assert_eq!(storage.get(ks, b"a/1").unwrap(), None);
+    assert_eq!(
+        storage.get(Keyspace::Trash, b"a/1").unwrap(),
+        Some(b"uno".to_vec())
+    );
+
+    // a transaction that gives up leaves nothing behind
+    let failed = storage.transaction(&mut |transaction| {
+        transaction.put(ks, b"c/1", b"five")?;
+        assert_eq!(transaction.get(ks, b"c/1")?, Some(b"five".to_vec()));
+        Err(StorageError::Abort("changed my mind".to_string()))
+    });
+    assert!(matches!(failed, Err(StorageError::Abort(_))));
+    assert_eq!(storage.get(ks, b"c/1").unwrap(), None);
+
+    storage
+        .transaction(&mut |transaction| {
+            let moved = transaction.delete(ks, b"a/2")?.unwrap();
+            transaction.put(Keyspace::Trash, b"a/2", &moved)?;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(storage.scan(ks, &[]).count(), 0);
+    assert_eq!(storage.scan(Keyspace::Trash, &[]).count(), 2);
+
+    // scans that take more than one read
+    let many = Keyspace::Replica;
+    let writes = (0..512)
+        .map(|i| (many, format!("p/{:04}", i).into_bytes(), Some(vec![])))
+        .chain([(many, b"q/1".to_vec(), Some(vec![]))])
+        .collect();
+    storage.batch(writes).unwrap();
+    let keys: Vec<_> = storage
+        .scan(many, b"p/")
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(keys.len(), 512);
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(storage.scan(many, &[]).count(), 514);
+    assert_eq!(storage.last(many).unwrap().unwrap().0, b"q/1".to_vec());
+}
+
+#[test]
+fn stores_in_every_backend() {
+    let dir = temporary_store("backends").unwrap();
+    let wait = std::time::Duration::ZERO;
+    exercise_storage(&MemoryStorage::default());
+    exercise_storage(&SledStorage::open(&dir.join("sled"), wait).unwrap());
+    exercise_storage(&SqliteStorage::open(&dir.join("store.sqlite"), wait).unwrap());
+
+    assert_eq!(database::backend_of(&dir.join("sled")), Backend::Sled);
+    assert_eq!(
+        database::backend_of(&dir.join("store.sqlite")),
+        Backend::Sqlite
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn converts_between_backends() {
+    let store = temporary_store("convert");
+    let path = store.clone().unwrap();
+    database::write_entry(
+        false,
+        store.clone(),
+        Keyspace::Bookmarks,
+        &bookmark("https://example.com/", &["web"], None),
+    );
+    database::insert_container(false, store.clone(), &folder("Reading", None));
+
+    commands::convert_store(Backend::Sqlite, &None, false, store.clone());
+    let backup = path.with_extension("bak");
+    assert!(path.is_file());
+    assert!(backup.is_dir());
+    assert_eq!(stored_bookmarks(&store).len(), 1);
+    assert_eq!(database::get_containers(false, store.clone()).len(), 1);
+    // the journal comes along, so the new store can undo what the old one did
+    assert_eq!(database::get_journal(false, store.clone()).len(), 2);
+    database::undo(false, store.clone());
+    assert!(database::get_containers(false, store.clone()).is_empty());
+
+    let out = temporary_store("converted");
+    commands::convert_store(Backend::Sled, &out, false, store.clone());
+    assert!(out.as_ref().unwrap().is_dir());
+    assert_eq!(stored_bookmarks(&out).len(), 1);
+    assert_eq!(database::get_journal(false, out.clone()).len(), 3);
+
+    for path in [Some(path), Some(backup), out].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// A store where another process writes to the journal right after it's read,
+/// like one sharing a SQLite store can
+struct RacingStore {
+    inner: MemoryStorage,
+    raced: std::sync::atomic::AtomicBool,
+}
+
+impl Storage for RacingStore {
+    fn get(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.get(keyspace, key)
+    }
+
+    fn put(
+        &self,
+        keyspace: Keyspace,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.put(keyspace, key, value)
+    }
+
+    fn delete(&self, keyspace: Keyspace, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        self.inner.delete(keyspace, key)
+    }
+
+    fn scan<'a>(
+        &'a self,
+        keyspace: Keyspace,
+        prefix: &[u8],
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), StorageError>> + 'a> {
+        self.inner.scan(keyspace, prefix)
+    }
+
+    fn last(&self, keyspace: Keyspace) -> Result<Option<(Vec<u8>, Vec<u8>)>, StorageError> {
+        let last = self.inner.last(keyspace)?;
+        if !self.raced.swap(true, std::sync::atomic::Ordering::SeqCst) {
+            let next = last
+                .as_ref()
+                .map_or(1, |_| unreachable!("the journal starts empty"));
+            let other = bincode::serialize(&operation(next, OperationKind::Change)).unwrap();
+            self.inner
+                .put(Keyspace::Journal, &u64::to_be_bytes(next), &other)?;
+        }
+        Ok(last)
+    }
+
+    fn batch(&self, writes: Vec<crate::storage::Write>) -> Result<(), StorageError> {
+        self.inner.batch(writes)
+    }
+
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&mut dyn crate::storage::Transaction) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        self.inner.transaction(f)
+    }
+}
+
+#[test]
+fn journals_concurrent_writers() {
+    let store: std::sync::Arc<dyn Storage> = std::sync::Arc::new(RacingStore {
+        inner: MemoryStorage::default(),
+        raced: std::sync::atomic::AtomicBool::new(false),
+    });
+    let operation = database::try_commit(
+        &store,
+        "add https://example.com/",
+        OperationKind::Change,
+        vec![(
+            Keyspace::Bookmarks,
+            "https://example.com/".to_string(),
+            Some(vec![1]),
+        )],
+    )
+    .unwrap();
+
+    // The other change keeps its id, and this one gets the next
+    assert_eq!(operation.id, 2);
+    let journal: Vec<_> = store.scan(Keyspace::Journal, &[]).collect();
+    assert_eq!(journal.len(), 2);
+}
+
+#[test]
+fn serves_through_broken_entries() {
+    let dir = temporary_store("broken").unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("store.sqlite");
+    {
+        let storage = SqliteStorage::open(&file, std::time::Duration::ZERO).unwrap();
+        storage
+            .put(Keyspace::Bookmarks, b"https://example.com/", b"not bincode")
+            .unwrap();
+    }
+    let store = Some(file);
+
+    // Exiting here would take the test runner down with it
+    for url in [
+        "/bookmarks",
+        "/bookmarks/https%3A%2F%2Fexample.com%2F",
+        "/tags",
+    ] {
+        let response = server::handle("GET", url, "", false, &store);
+        assert_eq!(response.status, 500, "{}", url);
+        assert_eq!(response.body["errors"][0]["code"], "invalid_data");
+    }
+    let response = server::handle("GET", "/containers", "", false, &store);
+    assert_eq!(response.status, 200);
+
+    let reply = native::handle(br#"{"action": "search", "query": "x"}"#, &store);
+    assert_eq!(reply["errors"][0]["code"], "invalid_data");
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn undoes_a_command_at_once() {
+    let store = temporary_store("one-step");
+    for link in [
+        "https://a.example.com/",
+        "https://b.example.com/",
+        "https://c.example.com/",
+    ] {
+        database::write_entry(
+            false,
+            store.clone(),
+            Keyspace::Bookmarks,
+            &bookmark(link, &[], None),
+        );
+    }
+    let targets = [
+        Url::parse("https://a.example.com/").unwrap(),
+        Url::parse("https://b.example.com/").unwrap(),
+    ];
+    commands::delete_bookmarks(&targets, &None, false, true, false, store.clone());
+    assert_eq!(stored_bookmarks(&store).len(), 1);
+    assert_eq!(database::get_trash(false, store.clone()).len(), 2);
+    database::undo(false, store.clone());
+    assert_eq!(stored_bookmarks(&store).len(), 3);
+    assert!(database::get_trash(false, store.clone()).is_empty());
+
+    // more lines than are looked up at once, with a link repeated across them
+    let dir = temporary_store("one-step-files").unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("many.jsonl");
+    let mut lines = String::new();
+    for i in 0..2500 {
+        let mut b = bookmark(&format!("https://example.com/{}", i % 2000), &[], None);
+        b.label = format!("line {}", i);
+        lines.push_str(&serde_json::to_string(&b).unwrap());
+        lines.push('\n');
+    }
+    std::fs::write(&file, lines).unwrap();
+    let journal = database::get_journal(false, store.clone()).len();
+    commands::import(
+        file,
+        None,
+        None,
+        ConflictStrategy::Overwrite,
+        &Normalize::default(),
+        false,
+        store.clone(),
+    );
+    assert_eq!(
+        database::get_journal(false, store.clone()).len(),
+        journal + 1
+    );
+    let stored = stored_bookmarks(&store);
+    assert_eq!(stored.len(), 2003);
+    assert!(stored.iter().any(|b| b.label == "line 2499"));
+    database::undo(false, store.clone());
+    assert_eq!(stored_bookmarks(&store).len(), 3);
+
+    // folders and bookmarks of an outline go together too
+    let file = dir.join("outline.md");
+    std::fs::write(&file, "- Work\n  - [Example](https://example.org/)\n").unwrap();
+    commands::import(
+        file,
+        None,
+        None,
+        ConflictStrategy::Overwrite,
+        &Normalize::default(),
+        false,
+        store.clone(),
+    );
+    assert_eq!(database::get_containers(false, store.clone()).len(), 1);
+    database::undo(false, store.clone());
+    assert!(database::get_containers(false, store.clone()).is_empty());
+    assert_eq!(stored_bookmarks(&store).len(), 3);
+
+    for path in [store, Some(dir)].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+/// A store with a folder and bookmarks that use every field
+fn filled_store(name: &str) -> Option<std::path::PathBuf> {
+    let store = temporary_store(name);
+    let reading = folder("Reading", None);
+    database::insert_container(false, store.clone(), &reading);
+    let mut a = bookmark(
+        "https://a.example.com/",
+        &["rust", "cli"],
+        Some("two\nlines"),
+    );
+    a.container = Some(reading.id);
+    let b = bookmark("https://b.example.com/", &[], None);
+    for entry in [a, b] {
+        database::write_entry(false, store.clone(), Keyspace::Bookmarks, &entry);
+    }
+    store
+}
+
+#[test]
+fn round_trips_json_lines() {
+    let from = filled_store("jsonl-from");
+    let to = temporary_store("jsonl-to");
+    let dir = temporary_store("jsonl-files").unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("bookmarks.jsonl");
+
+    commands::export(file.clone(), None, None, false, from.clone());
+    let text = std::fs::read_to_string(&file).unwrap();
+    assert_eq!(text.lines().count(), 2);
+    commands::import(
+        file,
+        None,
+        None,
+        ConflictStrategy::Overwrite,
+        &Normalize::default(),
+        false,
+        to.clone(),
+    );
+    assert_eq!(stored_bookmarks(&to), stored_bookmarks(&from));
+    // the whole import is one change
+    assert_eq!(database::get_journal(false, to.clone()).len(), 1);
+
+    for path in [from, to, Some(dir)].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn exports_to_stdout_and_imports_from_stdin() {
+    let from = filled_store("stdio-from");
+    let to = temporary_store("stdio-to");
+    let stdio = std::path::Path::new("-");
+
+    // stdout only gets the bookmarks, without a message after them
+    let mut stdout = Vec::new();
+    commands::export_to(&mut stdout, stdio, FileFormat::Jsonl, false, from.clone());
+    let text = String::from_utf8(stdout).unwrap();
+    for line in text.lines() {
+        serde_json::from_str::<Bookmark>(line).unwrap();
+    }
+    commands::import_from(
+        text.as_bytes(),
+        stdio,
+        FileFormat::Jsonl,
+        ConflictStrategy::Overwrite,
+        &Normalize::default(),
+        false,
+        to.clone(),
+    );
+    assert_eq!(stored_bookmarks(&to), stored_bookmarks(&from));
+
+    let mut stdout = Vec::new();
+    commands::export_to(&mut stdout, stdio, FileFormat::Json, true, from.clone());
+    let document: ExportDocument = serde_json::from_slice(&stdout).unwrap();
+    assert_eq!(document.bookmarks.len(), 2);
+    assert_eq!(document.containers.len(), 1);
+
+    for path in [from, to].into_iter().flatten() {
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[test]
+fn counts_each_imported_link_once() {
+    use commands::Outcome;
+    let store = temporary_store("import-counts");
+    let stored = bookmark("https://stored.example.com", &["a"], None);
+    database::insert_entry(false, store.clone(), Keyspace::Bookmarks, &stored);
+    let lines: Vec<String> = [
+        bookmark("https://new.example.com", &["a"], None),
+        bookmark("https://new.example.com", &["b"], None),
+        bookmark("https://stored.example.com", &["b"], None),
+        bookmark("https://stored.example.com", &["a"], None),
+        bookmark("https://other.example.com", &[], None),
+    ]
+    .iter()
+    .map(|b| serde_json::to_string(b).unwrap())
+    .collect();
+    let summary = commands::import_from(
+        lines.join("\n").as_bytes(),
+        std::path::Path::new("-"),
+        FileFormat::Jsonl,
+        ConflictStrategy::Merge,
+        &Normalize::default(),
+        false,
+        store.clone(),
+    );
+    assert_eq!(summary.outcomes.len(), 3);
+    assert_eq!(summary.count(Outcome::Added), 2);
+    assert_eq!(summary.count(Outcome::Updated), 1);
+    assert_eq!(summary.count(Outcome::Skipped), 0);
+    assert_eq!(summary.conflicted.len(), 2);
+    assert_eq!(stored_bookmarks(&store).len(), 3);
+    let _ = std::fs::remove_dir_all(store.unwrap());
+}
+
+#[test]
+fn trashes_folders_with_their_contents() {
+    let store = temporary_store("trash-folder");
+    let work = folder("Work", None);
+    let sub = folder("Sub", Some(work.id));
+    let other = folder("Other", None);
+    for f in [&work, &sub, &other] {
+        database::insert_container(false, store.clone(), f);
+    }
+    let mut inside = bookmark("https://a.example.com/", &[], None);
+    inside.container = Some(sub.id);
+    let mut outside = bookmark("https://b.example.com/", &[], None);
+    outside.container = Some(other.id);
+    for b in [&inside, &outside] {
+        database::write_entry(false, store.clone(), Keyspace::Bookmarks, b);
+    }
+
+    commands::delete_folder(&work.id, false, store.clone());
+    let folders = database::get_containers(false, store.clone());
+    assert_eq!(folders.len(), 1);
+    // nothing is left pointing at a folder that's gone
+    let bookmarks = stored_bookmarks(&store);
+    assert_eq!(bookmarks, vec![outside.clone()]);
+    assert_eq!(database::get_trash(false, store.clone()).len(), 3);
+
+    database::restore_trash(&work.id.to_string(), false, store.clone());
+    assert_eq!(database::get_containers(false, store.clone()).len(), 3);
+    assert_eq!(stored_bookmarks(&store).len(), 2);
+    assert!(database::get_trash(false, store.clone()).is_empty());
+
+    // the server does the same, in one step to undo
+    let response = server::handle(
+        "DELETE",
+        &format!("/containers/{}", work.id),
+        "",
+        false,
+        &store,
+    );
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["data"].as_array().unwrap().len(), 3);
+    assert_eq!(stored_bookmarks(&store), vec![outside]);
+    database::undo(false, store.clone());
+    assert_eq!(stored_bookmarks(&store).len(), 2);
+
+    if let Some(path) = store {
+        let _ = std::fs::remove_dir_all(path);
     }
 }